@@ -1,9 +1,14 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::path::PathBuf;
 use std::thread;
 use std::time::Duration;
 
+use ark_bls12_381::Bls12_381;
+use ark_groth16::ProvingKey;
 use bytes::BufMut;
 use bytes::BytesMut;
+use clap::Parser;
 use cometbft_proto::abci::v1::request::Value;
 use cometbft_proto::abci::v1::FlushRequest;
 use cometbft_proto::abci::v1::InfoRequest;
@@ -11,9 +16,35 @@ use cometbft_proto::abci::v1::Request;
 use prost::Message;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use util::keys;
+use util::merkletree::IncrementalMerkleTree;
+use util::merkletree::TREE_DEPTH;
+use util::tx::Circuit;
 use util::types::CoinCommitment;
 use util::types::Key;
 
+/// A protocash client, which pays over a node's ABCI connection.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Loads the proving keys of the payment circuits from this directory
+    #[arg(long, default_value = "keys")]
+    keys: PathBuf,
+
+    /// The depth of the commitment tree, as set in the genesis of the chain. The proving keys are
+    /// generated for this depth
+    #[arg(long, default_value_t = TREE_DEPTH)]
+    depth: usize,
+
+    /// The rate of the Poseidon sponge of the tree, as set in the genesis of the chain
+    #[arg(long, default_value_t = 4)]
+    rate: u32,
+
+    /// The Poseidon parameters of the tree are optimized for constraints rather than weights, as
+    /// set in the genesis of the chain
+    #[arg(long)]
+    optimized_for_constraints: bool,
+}
+
 async fn write_request(stream: &mut TcpStream, req: Request) -> Result<(), Box<dyn Error>> {
     let mut buf = BytesMut::new();
     let mut dst = BytesMut::new();
@@ -42,7 +73,11 @@ struct Client {
     /// These are *all* the transactions on the network. The client needs to know this - and in
     /// fact, keep an up-to-date picture of this - in order to make the proof of payment to the
     /// validator nodes.
-    all_coins: Vec<CoinCommitment>,
+    all_coins: IncrementalMerkleTree,
+
+    /// The proving key of each circuit, generated with `protocash-node keys generate`. They have
+    /// to come from the same setup as the verifying keys of the nodes.
+    proving_keys: HashMap<Circuit, ProvingKey<Bls12_381>>,
 }
 
 impl Client {
@@ -71,6 +106,16 @@ impl Client {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let proving_keys = keys::load_proving_keys(
+        &args.keys,
+        args.depth,
+        args.rate,
+        !args.optimized_for_constraints,
+    )?;
+    println!("loaded the proving keys of {} circuits", proving_keys.len());
+
     let mut stream = TcpStream::connect("127.0.0.1:26658").await?;
 
     let req = Request {
@@ -1,125 +1,717 @@
-use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::future::Future;
 use std::pin::Pin;
 
 use bytes::Bytes;
+use cometbft::abci::types;
+use cometbft::abci::v1::request;
 use cometbft::abci::v1::request::Request;
 use cometbft::abci::v1::response;
+use cometbft::abci::v1::response::ApplySnapshotChunkResult;
 use cometbft::abci::v1::response::ExtendVote;
 use cometbft::abci::v1::response::FinalizeBlock;
 use cometbft::abci::v1::response::PrepareProposal;
 use cometbft::abci::v1::response::ProcessProposal;
 use cometbft::abci::v1::response::Response;
 use cometbft::abci::v1::response::VerifyVoteExtension;
+use cometbft::abci::Code;
+use cometbft::abci::Event;
+use cometbft::abci::EventAttributeIndexExt;
+use cometbft::merkle::proof::ProofOp;
+use cometbft::merkle::proof::ProofOps;
 use cometbft::validator::Update;
 use cometbft::PublicKey;
+use ed25519_consensus::SigningKey;
 use rand::Rng;
+use sha2::Digest;
+use sha2::Sha256;
 use tower::Service;
 use tower_abci::BoxError;
-use util::types::CoinCommitment;
-use util::types::CoinID;
+use util::attestation::Attestation;
+use util::attestation::Attestations;
+use util::attestation::Roots;
+use util::tx;
+use util::tx::Transaction;
+use util::tx::Tx;
+use util::tx::TxError;
+use util::tx::ValidatorUpdate;
 
-#[derive(Default)]
-pub struct State {
-    /// a list of coins. You might think that this should be a MerkleTree, but in fact ArkWorks'
-    /// MerkleTree size is fixed at runtime, so we actually just store the coin commitments in a
-    /// list, and build the tree for each new commitment. This sucks but idk how else to do it.
-    txs: Vec<CoinCommitment>,
-
-    /// We check in this set to see if a coin is already spent
-    spents: BTreeSet<CoinID>,
-
-    height: u32,
-    size: u32,
-}
-
-pub enum TxError {
-    AlreadySpent,
-}
-
-impl State {
-    fn hash(&self) -> Vec<u8> {
-        todo!()
-
-        // let hasher = Sha256::new();
-        //
-        // let bytes: Vec<u8> = self
-        //     .store
-        //     .iter()
-        //     .flat_map(|(k, v)| k.bytes().chain(v.bytes()))
-        //     .collect();
-        //
-        // let hasher = hasher
-        //     .chain_update(bytes.as_slice()) // add the store
-        //     .chain_update(self.height.to_ne_bytes()) // add the height
-        //     .chain_update(self.size.to_ne_bytes()); // add the size
-        //
-        // hasher.finalize().to_vec() // TODO: should be [u8; 32] for SHA256
-    }
-
-    pub fn pay(&self) {
-        // TODO: verify the proof here
-    }
-}
+use crate::config::Config;
+use crate::events;
+use crate::genesis::Genesis;
+use crate::genesis::GenesisError;
+use crate::mempool;
+use crate::mempool::Mempool;
+use crate::mempool::TxHash;
+use crate::proposal;
+use crate::query;
+use crate::snapshot;
+use crate::snapshot::ApplyError;
+use crate::snapshot::Restore;
+use crate::snapshot::Restored;
+use crate::snapshot::Snapshot;
+use crate::snapshot::Snapshots;
+use crate::state;
+use crate::state::State;
+use crate::state::TreeConfig;
+use crate::state::VerifyingKeys;
+use crate::store;
+use crate::store::Store;
+use crate::store::StoreError;
 
 // according to cometbft, this is the first 20 bytes of `SHA256(public_key)`
 pub type Addr = [u8; 20];
 
+/// The version of the rules of the chain, which cometbft records in every block header.
+pub const APP_VERSION: u64 = 1;
+
 #[derive(Default)]
 pub struct Application {
     /// The state of our application
     state: State,
 
-    /// The number of blocks to retain after a commit.
-    retain_blocks: i32,
+    /// The roots of the state as of the last commit, which our votes attest to. They only change
+    /// once a block, so they aren't computed again for every vote. None until the chain starts.
+    roots: Option<Roots>,
+
+    /// Where the state is kept across restarts. Without one, the state only lives in memory.
+    store: Option<Store>,
+
+    /// The snapshots of the state that we serve to nodes catching up with state sync.
+    snapshots: Snapshots,
+
+    /// The snapshot being restored, if we are catching up with state sync ourselves.
+    restoring: Option<Restore>,
+
+    /// The coins spent by the transactions in the mempool.
+    mempool: Mempool,
+
+    /// The transactions of the blocks we accepted in ProcessProposal since the last commit, whose
+    /// proofs don't need to be verified again when the block is finalized, or when we propose a
+    /// block in a later round.
+    verified: HashSet<TxHash>,
+
+    /// The verifying key of each circuit that payments can be proven with.
+    verifying_keys: VerifyingKeys,
+
+    /// The tree that the `verifying_keys` are for, before the chain starts. A snapshot restored
+    /// without its genesis has to be for the same tree.
+    keys_tree: TreeConfig,
+
+    /// The genesis that the chain started from, which our snapshots carry. None until the chain
+    /// starts, or if it was imported without one.
+    genesis: Option<Vec<u8>>,
+
+    /// The number of blocks that cometbft keeps after a commit, and that our own history, such as
+    /// snapshots, goes back. Zero keeps every block.
+    retain_blocks: u64,
 
     /// The list of staged transactions.
     staged_txs: Vec<Bytes>,
 
-    /// We keep track of a list of updates for our validators.
+    /// The validator updates of the block being finalized, handed to cometbft at the end of it.
     validator_upds: Vec<Update>,
 
-    /// A set of validators, mapping addresses to public keys.
-    validators: HashMap<Addr, PublicKey>,
+    /// The current validators, mapping addresses to public keys. The state keeps them by public
+    /// key, but cometbft refers to them by address.
+    validators: HashMap<Addr, [u8; 32]>,
+
+    /// The key that we sign our votes with, if we are a validator. We attest to the roots of the
+    /// state with it.
+    validator_key: Option<SigningKey>,
 
-    /// If true, the app will generate block events in BeginBlock. Used to test the event indexer
-    /// Should be false by default to avoid generating too much data.
+    /// If true, the app emits a block event in FinalizeBlock, with the new root of the commitment
+    /// tree. Should be false by default to avoid generating too much data.
     gen_block_events: bool,
 }
 
 // These are the functions that our KVStore struct implements.
 impl Application {
+    pub fn new(verifying_keys: VerifyingKeys) -> Self {
+        Self {
+            verifying_keys,
+            ..Default::default()
+        }
+    }
+
+    /// Open the application with its state stored in the data directory of `config`, as it was at
+    /// the last commit. Once the chain is initialized, the verifying keys are the ones of its
+    /// genesis, for the depth of its tree. Until then, they come from `verifying_keys`, which isn't
+    /// called otherwise. With a `validator_key`, our votes attest to the roots of the state.
+    pub fn open(
+        config: &Config,
+        verifying_keys: impl FnOnce() -> VerifyingKeys,
+        validator_key: Option<SigningKey>,
+    ) -> Result<Self, StoreError> {
+        let (store, state) = Store::open(&config.data_dir, store::DEFAULT_CHECKPOINT_INTERVAL)?;
+
+        let retain_blocks = config.retain_blocks;
+        let mut app = Self::new(VerifyingKeys::new());
+        app.mempool = Mempool::new(config.min_fee);
+        app.retain_blocks = retain_blocks;
+        app.gen_block_events = config.block_events;
+        app.validator_key = validator_key;
+        if retain_blocks != 0 && retain_blocks < snapshot::DEFAULT_INTERVAL {
+            tracing::warn!(
+                "retaining {retain_blocks} blocks, fewer than between two snapshots: most of the \
+                 time, no snapshot will be served"
+            );
+        }
+
+        app.genesis = store.genesis()?;
+        app.verifying_keys = match &app.genesis {
+            Some(genesis) => Genesis::parse(genesis)
+                .and_then(|genesis| genesis.verifying_keys())
+                .map_err(|_| StoreError::Genesis)?,
+            None => {
+                app.keys_tree = config.keys.tree();
+                verifying_keys()
+            }
+        };
+        if let Some(state) = state {
+            tracing::info!("recovered the state at height {}", state.height());
+
+            app.state = state;
+            app.roots = Some(app.state.roots());
+            app.sync_validators();
+        }
+        app.store = Some(store);
+
+        Ok(app)
+    }
+
     // Info returns information about the state of the application. This is generally used
     // everytime a CometBFT instance begins and let's the application know what CometBFT
     // versions it's interacting with. Based from this information, CometBFT will ensure it is in
     // sync with the application by potentially replaying the blocks it has. If the Application
     // returns a 0 appBlockHeight, CometBFT will call InitChain to initialize the application
     // with consensus related data
+    //
+    // The state, validators included, is recovered from the store as of the last commit, so
+    // CometBFT replays the blocks after it.
     fn info(&self) -> response::Info {
-        // CometBFT expects the application to persist validators. On startup, we need to load them
-        // if they exist.
-        // TODO
-
         response::Info {
             data: String::from("498c-protocash"),
-            version: String::from("0.1.0"),
-            app_version: 1,
-            last_block_height: self.state.height.into(),
-            last_block_app_hash: self.state.hash().try_into().unwrap(),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            app_version: APP_VERSION,
+            last_block_height: self.state.height().into(),
+            last_block_app_hash: self.state.hash().to_vec().try_into().unwrap(),
         }
     }
 
-    fn query(&self, query: Bytes) -> response::Query {
-        todo!()
+    /// Start the chain from the genesis in `app_state_bytes`. An invalid genesis is an error, which
+    /// stops the node: no block can be built on top of it.
+    fn init_chain(&mut self, req: request::InitChain) -> Result<response::InitChain, BoxError> {
+        let genesis = Genesis::parse(&req.app_state_bytes)?;
+        let (mut state, verifying_keys) = genesis.build(req.initial_height.value())?;
+
+        // without validators in our genesis, we go with the ones in cometbft's
+        let validators = if genesis.validators.is_empty() {
+            for (i, update) in req.validators.iter().enumerate() {
+                let key = update
+                    .pub_key
+                    .ed25519()
+                    .and_then(|key| key.as_bytes().try_into().ok())
+                    .ok_or(GenesisError::Validator(i))?;
+
+                state.set_validator(key, update.power.value());
+            }
+
+            Vec::new()
+        } else {
+            state
+                .validators()
+                .iter()
+                .map(|(key, power)| validator_update(key, *power))
+                .collect()
+        };
+
+        self.state = state;
+        self.roots = Some(self.state.roots());
+        self.verifying_keys = verifying_keys;
+        self.genesis = Some(req.app_state_bytes.to_vec());
+        self.sync_validators();
+
+        self.state.take_changes();
+        if let Some(store) = self.store.as_mut() {
+            store.init(&self.state, &req.app_state_bytes)?;
+        }
+
+        tracing::info!(
+            "started the chain with {} coins and {} validators",
+            self.state.size(),
+            self.state.validators().len()
+        );
+
+        Ok(response::InitChain {
+            consensus_params: None,
+            validators,
+            app_hash: self.state.hash().to_vec().try_into().unwrap(),
+        })
     }
 
-    fn deliver_tx(&mut self, tx: Bytes) -> response::DeliverTx {
-        todo!()
+    /// Answer a query about the state. See [`query::answer`] for the paths.
+    fn query(&self, req: request::Query) -> response::Query {
+        match query::answer(&self.state, &req.path, req.height.value(), req.prove) {
+            Ok(answer) => {
+                let ops: Vec<ProofOp> = answer
+                    .proof
+                    .into_iter()
+                    .map(|op| ProofOp {
+                        field_type: op.kind.to_string(),
+                        key: op.key,
+                        data: op.data,
+                    })
+                    .collect();
+
+                response::Query {
+                    key: answer.key.into(),
+                    value: answer.value.into(),
+                    proof: (!ops.is_empty()).then_some(ProofOps { ops }),
+                    height: self.state.height().into(),
+                    ..Default::default()
+                }
+            }
+            Err(e) => response::Query {
+                code: Code::from(1),
+                log: e.to_string(),
+                height: self.state.height().into(),
+                ..Default::default()
+            },
+        }
     }
 
-    fn commit(&mut self) -> response::Commit {
-        todo!()
+    /// Decide whether a transaction gets into the mempool. Transactions are rechecked after every
+    /// commit, when their root may have expired or their coins may have been spent; the proof
+    /// doesn't need to be verified again then.
+    fn check_tx(&mut self, req: request::CheckTx) -> response::CheckTx {
+        let hash = mempool::tx_hash(&req.tx);
+
+        let result = state::decode_tx(&req.tx).and_then(|tx| {
+            // only the proposer of a block publishes attestations
+            if matches!(tx, Tx::Attestations(_)) {
+                return Err(TxError::Unauthorized);
+            }
+
+            self.state.check(&tx)?;
+            self.mempool.check(&hash, &tx)?;
+
+            if req.kind == request::CheckTxKind::New {
+                state::verify(&self.verifying_keys, &tx)?;
+            }
+
+            Ok(tx)
+        });
+
+        match result {
+            Ok(tx) => {
+                self.mempool.insert(hash, &tx);
+
+                Default::default()
+            }
+            Err(e) => {
+                self.mempool.remove(&hash);
+
+                response::CheckTx {
+                    code: Code::from(e.code()),
+                    codespace: tx::CODESPACE.to_string(),
+                    log: e.to_string(),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    /// Build our block when we are the proposer, out of the transactions in cometbft's mempool.
+    /// The attestations in the votes for the previous block go first, if they fit.
+    fn prepare_proposal(&self, proposal: request::PrepareProposal) -> PrepareProposal {
+        let mut max_tx_bytes = proposal.max_tx_bytes.try_into().unwrap_or(0);
+        let mut txs = Vec::new();
+
+        let attestations = proposal.local_last_commit.as_ref().and_then(|commit| {
+            let votes = commit.votes.iter().filter_map(|vote| {
+                let key = self.validators.get(&vote.validator.address)?;
+
+                Some((*key, vote.vote_extension.as_ref()))
+            });
+
+            proposal::attestations(&self.state, votes)
+        });
+        if let Some(attestations) = attestations {
+            let tx = Tx::Attestations(attestations).encode();
+            let len = proposal::encoded_len(&tx);
+            if len <= max_tx_bytes {
+                max_tx_bytes -= len;
+                txs.push(Bytes::from(tx));
+            }
+        }
+
+        let block = proposal::prepare(
+            &self.state,
+            &self.verifying_keys,
+            &proposal.txs,
+            &self.verified,
+            max_tx_bytes,
+        );
+        txs.extend(block.into_iter().map(|i| proposal.txs[i].clone()));
+
+        PrepareProposal { txs }
+    }
+
+    /// Vote against a proposed block unless every transaction in it is a valid payment.
+    fn process_proposal(&mut self, proposal: request::ProcessProposal) -> ProcessProposal {
+        match proposal::process(&self.state, &self.verifying_keys, &proposal.txs) {
+            Ok(()) => {
+                self.verified
+                    .extend(proposal.txs.iter().map(|tx| mempool::tx_hash(tx)));
+
+                ProcessProposal::Accept
+            }
+            Err(e) => {
+                tracing::warn!("rejecting the block at height {}: {e}", proposal.height);
+
+                ProcessProposal::Reject
+            }
+        }
+    }
+
+    /// Attest to the roots of the state after the last block, if we are a validator. The proposer
+    /// of the next block publishes the attestations, see [`proposal::attestations`].
+    fn extend_vote(&self, vote: request::ExtendVote) -> ExtendVote {
+        let vote_extension = match (&self.validator_key, self.roots) {
+            (Some(key), Some(roots)) if vote.height.value() == u64::from(roots.height) + 1 => {
+                Attestation::sign(key, roots).encode().into()
+            }
+            _ => Bytes::new(),
+        };
+
+        ExtendVote { vote_extension }
+    }
+
+    /// Check the attestation in the vote of another validator. A validator doesn't have to attest,
+    /// but its vote is rejected if it attests to other roots than ours, or with a bad signature.
+    fn verify_vote_extension(&self, vote: request::VerifyVoteExtension) -> VerifyVoteExtension {
+        if vote.vote_extension.is_empty() {
+            return VerifyVoteExtension::Accept;
+        }
+
+        let Some(attestation) = Attestation::decode(&vote.vote_extension) else {
+            return VerifyVoteExtension::Reject;
+        };
+        if Some(attestation.roots) != self.roots {
+            tracing::warn!(
+                "validator {} attests to other roots at height {}",
+                vote.validator_address,
+                vote.height
+            );
+
+            return VerifyVoteExtension::Reject;
+        }
+
+        // the validator set of cometbft lags behind ours, so we may not know the validator yet,
+        // or anymore: its attestation is then left out of the ones we publish
+        let addr: Option<Addr> = vote.validator_address.as_bytes().try_into().ok();
+        match addr.and_then(|addr| self.validators.get(&addr)) {
+            Some(key) if !attestation.verify(key) => VerifyVoteExtension::Reject,
+            _ => VerifyVoteExtension::Accept,
+        }
+    }
+
+    /// Execute the transactions of a decided block, in order. A transaction which fails doesn't
+    /// fail the block, it is just left out of the state.
+    fn finalize_block(&mut self, block: request::FinalizeBlock) -> FinalizeBlock {
+        self.verify_block(&block.txs);
+        let tx_results = block.txs.iter().map(|tx| self.deliver_tx(tx)).collect();
+
+        self.state.end_block();
+        debug_assert_eq!(u64::from(self.state.height()), block.height.value());
+
+        let validator_updates = std::mem::take(&mut self.validator_upds);
+        if !validator_updates.is_empty() {
+            self.sync_validators();
+        }
+
+        let block_events = if self.gen_block_events {
+            vec![event(events::block(&self.state))]
+        } else {
+            Vec::new()
+        };
+
+        FinalizeBlock {
+            events: block_events,
+            tx_results,
+            validator_updates,
+            consensus_param_updates: None,
+            app_hash: self.state.hash().to_vec().try_into().unwrap(),
+        }
+    }
+
+    /// Verify the proofs of the payments that we didn't verify in ProcessProposal, all at once,
+    /// such as when we replay blocks or didn't see the proposal in time.
+    fn verify_block(&mut self, txs: &[Bytes]) {
+        let unverified: Vec<(TxHash, Transaction)> = txs
+            .iter()
+            .filter_map(|tx| {
+                let hash = mempool::tx_hash(tx);
+                if self.verified.contains(&hash) {
+                    return None;
+                }
+
+                match state::decode_tx(tx) {
+                    Ok(Tx::Payment(tx)) => Some((hash, tx)),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let payments: Vec<&Transaction> = unverified.iter().map(|(_, tx)| tx).collect();
+        let results = state::verify_payments(&self.verifying_keys, &payments);
+
+        // the invalid ones are verified again, and rejected, when they are delivered
+        for ((hash, _), result) in unverified.iter().zip(results) {
+            if result.is_ok() {
+                self.verified.insert(*hash);
+            }
+        }
+    }
+
+    fn deliver_tx(&mut self, tx: &[u8]) -> types::ExecTxResult {
+        // cometbft drops the transactions of the block from its mempool
+        let hash = mempool::tx_hash(tx);
+        self.mempool.remove(&hash);
+
+        let result = state::decode_tx(tx).and_then(|tx| match tx {
+            Tx::Payment(tx) => self.deliver_payment(&hash, &tx),
+            Tx::ValidatorUpdate(update) => self.deliver_update(&update),
+            Tx::Attestations(attestations) => self.deliver_attestations(&attestations),
+        });
+
+        result.unwrap_or_else(tx_error)
+    }
+
+    fn deliver_payment(
+        &mut self,
+        hash: &TxHash,
+        tx: &Transaction,
+    ) -> Result<types::ExecTxResult, TxError> {
+        let index = if self.verified.contains(hash) {
+            self.state.check_payment(tx)?;
+            self.state.apply(tx)
+        } else {
+            self.state.pay(&self.verifying_keys, tx)?
+        };
+
+        Ok(types::ExecTxResult {
+            data: Bytes::copy_from_slice(&(index as u64).to_be_bytes()),
+            events: events::payment(tx, index).into_iter().map(event).collect(),
+            ..Default::default()
+        })
+    }
+
+    /// Change the validator set in the state, and queue the change for cometbft, which applies it
+    /// a couple of blocks later.
+    fn deliver_update(&mut self, update: &ValidatorUpdate) -> Result<types::ExecTxResult, TxError> {
+        self.state.check_update(update)?;
+        self.state.apply_update(update);
+
+        // cometbft refuses two updates of the same validator in a block, the last one wins
+        let upd = validator_update(&update.pub_key, update.power);
+        self.validator_upds
+            .retain(|other| other.pub_key != upd.pub_key);
+        self.validator_upds.push(upd);
+
+        Ok(types::ExecTxResult {
+            events: vec![event(events::validator_update(update))],
+            ..Default::default()
+        })
+    }
+
+    /// Publish the attestations of the roots before the previous block, which don't change the
+    /// state.
+    fn deliver_attestations(
+        &self,
+        attestations: &Attestations,
+    ) -> Result<types::ExecTxResult, TxError> {
+        self.state.check_attestations(attestations)?;
+
+        Ok(types::ExecTxResult {
+            events: events::attestations(attestations)
+                .into_iter()
+                .map(event)
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    /// Rebuild the addresses of the validators from the state.
+    fn sync_validators(&mut self) {
+        self.validators = self
+            .state
+            .validators()
+            .keys()
+            .map(|key| {
+                let addr = Sha256::digest(key)[..20]
+                    .try_into()
+                    .expect("a SHA256 digest has 32 bytes");
+
+                (addr, *key)
+            })
+            .collect();
+    }
+
+    /// The state was already updated by FinalizeBlock, all that is left is to keep it. Failing to
+    /// store it is an error, which stops the node rather than acknowledge a block it would forget.
+    fn commit(&mut self) -> Result<response::Commit, StoreError> {
+        let changes = self.state.take_changes();
+        if let Some(store) = self.store.as_mut() {
+            store.commit(&self.state, changes)?;
+        }
+        self.roots = Some(self.state.roots());
+
+        self.snapshots
+            .maybe_take(&self.state, self.genesis.as_deref());
+        self.mempool.commit();
+        self.verified.clear();
+
+        let retain_height = retain_height(self.state.height().into(), self.retain_blocks);
+        self.snapshots.prune(retain_height);
+
+        Ok(response::Commit {
+            retain_height: retain_height.try_into().unwrap(),
+            ..Default::default()
+        })
+    }
+
+    fn list_snapshots(&self) -> response::ListSnapshots {
+        let snapshots = self
+            .snapshots
+            .list()
+            .into_iter()
+            .map(|s| types::Snapshot {
+                height: s.height.try_into().unwrap(),
+                format: s.format,
+                chunks: s.chunks,
+                hash: Bytes::copy_from_slice(&s.hash),
+                metadata: s.metadata.into(),
+            })
+            .collect();
+
+        response::ListSnapshots { snapshots }
+    }
+
+    fn offer_snapshot(&mut self, offer: request::OfferSnapshot) -> response::OfferSnapshot {
+        let Ok(hash) = offer.snapshot.hash.as_ref().try_into() else {
+            return response::OfferSnapshot::Reject;
+        };
+        let Ok(app_hash) = offer.app_hash.as_bytes().try_into() else {
+            return response::OfferSnapshot::Reject;
+        };
+
+        let snapshot = Snapshot {
+            height: offer.snapshot.height.value(),
+            format: offer.snapshot.format,
+            chunks: offer.snapshot.chunks,
+            hash,
+            metadata: offer.snapshot.metadata.to_vec(),
+        };
+
+        match Restore::offer(snapshot, app_hash) {
+            Ok(restore) => {
+                self.restoring = Some(restore);
+
+                response::OfferSnapshot::Accept
+            }
+            Err(snapshot::OfferError::Format) => response::OfferSnapshot::RejectFormat,
+            Err(snapshot::OfferError::Invalid) => response::OfferSnapshot::Reject,
+        }
+    }
+
+    fn load_snapshot_chunk(&self, req: request::LoadSnapshotChunk) -> response::LoadSnapshotChunk {
+        let chunk = self
+            .snapshots
+            .chunk(req.height.value(), req.format, req.chunk)
+            .map(Bytes::copy_from_slice)
+            .unwrap_or_default();
+
+        response::LoadSnapshotChunk { chunk }
+    }
+
+    fn apply_snapshot_chunk(
+        &mut self,
+        req: request::ApplySnapshotChunk,
+    ) -> response::ApplySnapshotChunk {
+        let Some(restore) = self.restoring.as_mut() else {
+            return response::ApplySnapshotChunk {
+                result: ApplySnapshotChunkResult::Abort,
+                ..Default::default()
+            };
+        };
+
+        match restore.apply(req.index, req.chunk.to_vec()) {
+            Ok(restored) => {
+                if let Some(Restored { state, genesis }) = restored {
+                    tracing::info!("restored the state at height {}", state.height());
+
+                    // without the genesis, our own keys have to be for the tree of the chain
+                    if genesis.is_none() && state.config() != self.keys_tree {
+                        tracing::error!(
+                            "the snapshot has no genesis, and our verifying keys are for another \
+                             tree: {:?}",
+                            state.config()
+                        );
+                        self.restoring = None;
+
+                        return response::ApplySnapshotChunk {
+                            result: ApplySnapshotChunkResult::RejectSnapshot,
+                            ..Default::default()
+                        };
+                    }
+
+                    let stored = match (self.store.as_mut(), &genesis) {
+                        (Some(store), Some((bytes, _))) => store.init(&state, bytes),
+                        (Some(store), None) => store.checkpoint(&state),
+                        (None, _) => Ok(()),
+                    };
+                    if let Err(e) = stored {
+                        tracing::error!("failed to store the restored state: {e}");
+                        self.restoring = None;
+
+                        return response::ApplySnapshotChunk {
+                            result: ApplySnapshotChunkResult::Abort,
+                            ..Default::default()
+                        };
+                    }
+
+                    if let Some((bytes, verifying_keys)) = genesis {
+                        self.genesis = Some(bytes);
+                        self.verifying_keys = verifying_keys;
+                    }
+                    self.state = state;
+                    self.roots = Some(self.state.roots());
+                    self.restoring = None;
+                    self.sync_validators();
+                }
+
+                response::ApplySnapshotChunk {
+                    result: ApplySnapshotChunkResult::Accept,
+                    ..Default::default()
+                }
+            }
+            Err(ApplyError::BadChunk) => response::ApplySnapshotChunk {
+                result: ApplySnapshotChunkResult::Retry,
+                refetch_chunks: vec![req.index],
+                reject_senders: vec![req.sender],
+            },
+            Err(ApplyError::BadSnapshot) => {
+                self.restoring = None;
+
+                response::ApplySnapshotChunk {
+                    result: ApplySnapshotChunkResult::RejectSnapshot,
+                    ..Default::default()
+                }
+            }
+        }
     }
 }
 
@@ -144,36 +736,93 @@ impl Service<Request> for Application {
         println!("got {:?}", req);
 
         let res = match req {
-            Request::Info(_) => Response::Info(Default::default()),
-            Request::Query(_) => Response::Query(Default::default()),
-            Request::Commit => Response::Commit(Default::default()),
+            Request::Info(_) => Response::Info(self.info()),
+            Request::Query(req) => Response::Query(self.query(req)),
+            Request::Commit => match self.commit() {
+                Ok(res) => Response::Commit(res),
+                Err(e) => {
+                    tracing::error!("failed to store the block: {e}");
+
+                    return Box::pin(async move { Err(e.into()) });
+                }
+            },
             Request::Echo(_) => Response::Echo(Default::default()),
             Request::Flush => Response::Flush,
-            Request::InitChain(_) => Response::InitChain(Default::default()),
-            Request::CheckTx(_) => Response::CheckTx(Default::default()),
-            Request::ListSnapshots => Response::ListSnapshots(Default::default()),
-            Request::OfferSnapshot(_) => Response::ListSnapshots(Default::default()),
-            Request::LoadSnapshotChunk(_) => Response::LoadSnapshotChunk(Default::default()),
-            Request::ApplySnapshotChunk(_) => Response::ApplySnapshotChunk(Default::default()),
+            Request::InitChain(req) => match self.init_chain(req) {
+                Ok(res) => Response::InitChain(res),
+                Err(e) => {
+                    tracing::error!("failed to start the chain: {e}");
+
+                    return Box::pin(async move { Err(e) });
+                }
+            },
+            Request::CheckTx(req) => Response::CheckTx(self.check_tx(req)),
+            Request::ListSnapshots => Response::ListSnapshots(self.list_snapshots()),
+            Request::OfferSnapshot(offer) => Response::OfferSnapshot(self.offer_snapshot(offer)),
+            Request::LoadSnapshotChunk(req) => {
+                Response::LoadSnapshotChunk(self.load_snapshot_chunk(req))
+            }
+            Request::ApplySnapshotChunk(req) => {
+                Response::ApplySnapshotChunk(self.apply_snapshot_chunk(req))
+            }
             Request::PrepareProposal(proposal) => {
-                Response::PrepareProposal(PrepareProposal { txs: proposal.txs })
-            }
-            Request::ProcessProposal(_) => Response::ProcessProposal(ProcessProposal::Accept),
-            Request::ExtendVote(_) => Response::ExtendVote(ExtendVote {
-                vote_extension: Bytes::new(),
-            }),
-            Request::VerifyVoteExtension(_) => {
-                Response::VerifyVoteExtension(VerifyVoteExtension::Accept)
-            }
-            Request::FinalizeBlock(_) => Response::FinalizeBlock(FinalizeBlock {
-                events: vec![],
-                tx_results: vec![],
-                validator_updates: vec![],
-                consensus_param_updates: None,
-                app_hash: Default::default(),
-            }),
+                Response::PrepareProposal(self.prepare_proposal(proposal))
+            }
+            Request::ProcessProposal(proposal) => {
+                Response::ProcessProposal(self.process_proposal(proposal))
+            }
+            Request::ExtendVote(vote) => Response::ExtendVote(self.extend_vote(vote)),
+            Request::VerifyVoteExtension(vote) => {
+                Response::VerifyVoteExtension(self.verify_vote_extension(vote))
+            }
+            Request::FinalizeBlock(block) => Response::FinalizeBlock(self.finalize_block(block)),
         };
 
         Box::pin(async move { Ok(res) })
     }
 }
+
+/// The lowest height to keep after committing the block at `height`, so that the last
+/// `retain_blocks` blocks are kept. Zero keeps every block.
+fn retain_height(height: u64, retain_blocks: u64) -> u64 {
+    match retain_blocks {
+        0 => 0,
+        n => (height + 1).saturating_sub(n),
+    }
+}
+
+/// Hand an event to cometbft.
+fn event(event: events::Event) -> Event {
+    let attributes: Vec<_> = event
+        .attributes
+        .into_iter()
+        .map(|a| {
+            if a.index {
+                (a.key, a.value).index()
+            } else {
+                (a.key, a.value).no_index()
+            }
+        })
+        .collect();
+
+    Event::new(event.kind, attributes)
+}
+
+/// The update telling cometbft about the power of a validator. A power of zero removes it.
+fn validator_update(key: &[u8; 32], power: u64) -> Update {
+    Update {
+        pub_key: PublicKey::from_raw_ed25519(key).expect("validator keys are checked"),
+        power: power.try_into().expect("validator powers are checked"),
+    }
+}
+
+/// The result of a transaction which was rejected. Clients decode the code back with
+/// [`TxError::from_code`].
+fn tx_error(e: TxError) -> types::ExecTxResult {
+    types::ExecTxResult {
+        code: Code::from(e.code()),
+        codespace: tx::CODESPACE.to_string(),
+        log: e.to_string(),
+        ..Default::default()
+    }
+}
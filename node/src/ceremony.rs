@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use clap::Subcommand;
+use util::ceremony::Ceremony;
+use util::keys;
+use util::keys::Header;
+use util::keys::KeyKind;
+use util::merkletree::Params;
+use util::merkletree::TREE_DEPTH;
+use util::tx::Circuit;
+
+/// The steps of a setup ceremony. Participants pass the ceremony file along, each one running
+/// `contribute` on the file they were given. The coordinator then closes the powers of tau, the
+/// participants contribute again, and the coordinator exports the keys.
+#[derive(Subcommand, Debug)]
+pub enum CeremonyCommand {
+    /// Start a ceremony for one of the payment circuits
+    Init {
+        /// The circuit, `payment` or `joinsplit`
+        #[arg(long, value_parser = parse_circuit)]
+        circuit: Circuit,
+
+        /// The depth of the commitment tree
+        #[arg(long, default_value_t = TREE_DEPTH)]
+        depth: usize,
+
+        /// The rate of the Poseidon sponge of the tree
+        #[arg(long, default_value_t = 4)]
+        rate: u32,
+
+        /// Use the Poseidon parameters optimized for constraints rather than weights
+        #[arg(long)]
+        optimized_for_constraints: bool,
+
+        /// Writes the ceremony to this file
+        #[arg(long)]
+        out: PathBuf,
+    },
+
+    /// Add a contribution with fresh randomness, to the powers of tau until they are closed, and
+    /// to the keys after that
+    Contribute {
+        /// Reads the ceremony from this file
+        #[arg(long)]
+        input: PathBuf,
+
+        /// Writes the ceremony, with the new contribution, to this file
+        #[arg(long)]
+        out: PathBuf,
+    },
+
+    /// End the contributions to the powers of tau, and derive the keys of the circuit from them
+    Close {
+        /// Reads the ceremony from this file
+        #[arg(long)]
+        input: PathBuf,
+
+        /// Writes the closed ceremony to this file
+        #[arg(long)]
+        out: PathBuf,
+    },
+
+    /// Check every contribution made so far
+    Verify {
+        /// Reads the ceremony from this file
+        #[arg(long)]
+        input: PathBuf,
+    },
+
+    /// Check the ceremony and write its proving and verifying keys
+    Export {
+        /// Reads the ceremony from this file
+        #[arg(long)]
+        input: PathBuf,
+
+        /// Writes the keys to this directory
+        #[arg(long, default_value = "keys")]
+        out: PathBuf,
+    },
+}
+
+fn parse_circuit(name: &str) -> Result<Circuit, String> {
+    Circuit::ALL
+        .into_iter()
+        .find(|c| c.name() == name)
+        .ok_or_else(|| format!("unknown circuit {name}"))
+}
+
+/// The Poseidon parameters of the tree that the ceremony is for.
+fn params(header: &Header) -> Result<Params, Box<dyn Error>> {
+    Ok(header
+        .params()
+        .ok_or("no Poseidon parameters for this rate")?)
+}
+
+fn load(path: &PathBuf) -> Result<(Header, Ceremony), Box<dyn Error>> {
+    let (header, ceremony) = keys::load_any(path)?;
+    if header.kind != KeyKind::Ceremony {
+        return Err(format!("{} is not a ceremony", path.display()).into());
+    }
+
+    Ok((header, ceremony))
+}
+
+pub fn run(command: CeremonyCommand) -> Result<(), Box<dyn Error>> {
+    let mut rng = rand::rngs::OsRng;
+
+    match command {
+        CeremonyCommand::Init {
+            circuit,
+            depth,
+            rate,
+            optimized_for_constraints,
+            out,
+        } => {
+            let header = Header {
+                kind: KeyKind::Ceremony,
+                circuit,
+                depth: depth as u32,
+                rate,
+                optimized_for_weights: !optimized_for_constraints,
+            };
+            let ceremony = Ceremony::new(&params(&header)?, circuit, depth)?;
+            keys::save(&out, &header, &ceremony)?;
+
+            tracing::info!(
+                "started a ceremony for {} in {}",
+                circuit.name(),
+                out.display()
+            );
+        }
+        CeremonyCommand::Contribute { input, out } => {
+            let (header, mut ceremony) = load(&input)?;
+
+            let hash = ceremony.contribute(&mut rng);
+            keys::save(&out, &header, &ceremony)?;
+
+            if ceremony.is_closed() {
+                tracing::info!(
+                    "added contribution {} with hash {}",
+                    ceremony.contributions().len(),
+                    hex::encode(hash)
+                );
+            } else {
+                tracing::info!(
+                    "added contribution {} to the powers of tau with hash {}",
+                    ceremony.powers_contributions().len(),
+                    hex::encode(hash)
+                );
+            }
+        }
+        CeremonyCommand::Close { input, out } => {
+            let (header, mut ceremony) = load(&input)?;
+            if ceremony.powers_contributions().is_empty() {
+                return Err("the powers of tau have no contributions".into());
+            }
+
+            ceremony.close(&params(&header)?, header.circuit, header.depth as usize)?;
+            keys::save(&out, &header, &ceremony)?;
+
+            tracing::info!("closed the powers of tau in {}", out.display());
+        }
+        CeremonyCommand::Verify { input } => {
+            let (header, ceremony) = load(&input)?;
+            ceremony.verify(
+                &params(&header)?,
+                header.circuit,
+                header.depth as usize,
+                &mut rng,
+            )?;
+
+            for (i, contribution) in ceremony.powers_contributions().iter().enumerate() {
+                tracing::info!(
+                    "contribution {} to the powers of tau has hash {}",
+                    i + 1,
+                    hex::encode(contribution.hash())
+                );
+            }
+            for (i, contribution) in ceremony.contributions().iter().enumerate() {
+                tracing::info!(
+                    "contribution {} has hash {}",
+                    i + 1,
+                    hex::encode(contribution.hash())
+                );
+            }
+        }
+        CeremonyCommand::Export { input, out } => {
+            let (header, ceremony) = load(&input)?;
+            ceremony.verify(
+                &params(&header)?,
+                header.circuit,
+                header.depth as usize,
+                &mut rng,
+            )?;
+
+            if ceremony.powers_contributions().is_empty() || ceremony.contributions().is_empty() {
+                return Err("both phases of the ceremony need contributions".into());
+            }
+
+            let (pk, vk) = ceremony.keys()?;
+            fs::create_dir_all(&out)?;
+
+            let header = Header {
+                kind: KeyKind::Proving,
+                ..header
+            };
+            keys::save(out.join(header.file_name()), &header, &pk)?;
+            let header = Header {
+                kind: KeyKind::Verifying,
+                ..header
+            };
+            keys::save(out.join(header.file_name()), &header, &vk)?;
+
+            tracing::info!(
+                "wrote the keys of {} to {}",
+                header.circuit.name(),
+                out.display()
+            );
+        }
+    }
+
+    Ok(())
+}
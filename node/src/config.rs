@@ -0,0 +1,341 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::state::TreeConfig;
+
+/// The prefix of the environment variables which override the config.
+const ENV_PREFIX: &str = "PROTOCASH_";
+
+/// The configuration of a node, read from a TOML file. Every value has a default, so the file
+/// only needs the ones that differ, and can be missing altogether.
+///
+/// Every value can be overridden by an environment variable named after its key, and its section
+/// if it has one, such as `PROTOCASH_DATA_DIR` or `PROTOCASH_ABCI_PORT`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where the state of the chain is kept.
+    pub data_dir: PathBuf,
+
+    /// The most verbose level of the logs: `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: String,
+
+    /// The number of blocks that cometbft keeps, and the snapshots taken within them. Zero keeps
+    /// every block.
+    pub retain_blocks: u64,
+
+    /// Emit an event at the end of every block, with the new root of the commitment tree.
+    pub block_events: bool,
+
+    /// Keep the payments paying a lower fee out of the mempool.
+    pub min_fee: u64,
+
+    pub abci: AbciConfig,
+    pub limits: LimitsConfig,
+    pub keys: KeysConfig,
+}
+
+/// Where cometbft connects to the node.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AbciConfig {
+    /// The host that the TCP server binds to.
+    pub host: String,
+
+    pub port: u16,
+
+    /// Bind a Unix domain socket at this path rather than a TCP server.
+    pub uds: Option<PathBuf>,
+}
+
+/// How many requests the node takes in before it sheds the load, so that cometbft's mempool and
+/// queries can't starve consensus.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// The number of CheckTx requests waiting to be handled.
+    pub mempool_buffer: usize,
+
+    /// The number of Info and Query requests waiting to be handled.
+    pub info_buffer: usize,
+
+    /// The number of Info and Query requests handled per second.
+    pub info_rate_limit: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// The directory of the verifying keys of the payment circuits. Once the chain is initialized,
+    /// the node uses the keys of its genesis instead.
+    pub verifying_keys: PathBuf,
+
+    /// The depth of the commitment tree that the `verifying_keys` are for, the same as `genesis
+    /// --depth`.
+    pub depth: u32,
+
+    /// The rate of the Poseidon sponge that the `verifying_keys` are for.
+    pub rate: u32,
+
+    /// Whether the `verifying_keys` are for Poseidon parameters optimized for weights rather than
+    /// constraints.
+    pub optimized_for_weights: bool,
+
+    /// The `priv_validator_key.json` of cometbft. With one, the node attests to the roots of the
+    /// state in its votes.
+    pub validator_key: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+
+    /// The file isn't a valid config.
+    Toml(toml::de::Error),
+
+    /// The environment variable doesn't parse to the type of its value.
+    Env(&'static str),
+
+    /// The log level isn't one of the levels.
+    LogLevel(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{e}"),
+            ConfigError::Toml(e) => write!(f, "{e}"),
+            ConfigError::Env(name) => {
+                write!(f, "the environment variable {ENV_PREFIX}{name} is invalid")
+            }
+            ConfigError::LogLevel(level) => write!(f, "unknown log level {level}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("data"),
+            log_level: String::from("info"),
+            retain_blocks: 0,
+            block_events: false,
+            min_fee: 0,
+            abci: AbciConfig::default(),
+            limits: LimitsConfig::default(),
+            keys: KeysConfig::default(),
+        }
+    }
+}
+
+impl Default for AbciConfig {
+    fn default() -> Self {
+        Self {
+            host: String::from("127.0.0.1"),
+            port: 26658,
+            uds: None,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            mempool_buffer: 10,
+            info_buffer: 100,
+            info_rate_limit: 50,
+        }
+    }
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        let tree = TreeConfig::default();
+
+        Self {
+            verifying_keys: PathBuf::from("keys"),
+            depth: tree.depth,
+            rate: tree.rate,
+            optimized_for_weights: tree.optimized_for_weights,
+            validator_key: None,
+        }
+    }
+}
+
+impl KeysConfig {
+    /// The tree that the `verifying_keys` are for.
+    pub fn tree(&self) -> TreeConfig {
+        TreeConfig {
+            depth: self.depth,
+            rate: self.rate,
+            optimized_for_weights: self.optimized_for_weights,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config at `path`, or the default one if there is no such file, and override it
+    /// with the environment.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let mut config = match fs::read_to_string(path) {
+            Ok(s) => Self::parse(&s)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(ConfigError::Io(e)),
+        };
+
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.log_level()?;
+
+        Ok(config)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, ConfigError> {
+        toml::from_str(s).map_err(ConfigError::Toml)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("the config serializes to TOML")
+    }
+
+    /// Override the values of the config with the environment variables that `var` finds. An
+    /// empty variable unsets an optional value.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let vars = |name: &str| var(&format!("{ENV_PREFIX}{name}"));
+
+        env(&vars, "DATA_DIR", &mut self.data_dir)?;
+        env(&vars, "LOG_LEVEL", &mut self.log_level)?;
+        env(&vars, "RETAIN_BLOCKS", &mut self.retain_blocks)?;
+        env(&vars, "BLOCK_EVENTS", &mut self.block_events)?;
+        env(&vars, "MIN_FEE", &mut self.min_fee)?;
+
+        env(&vars, "ABCI_HOST", &mut self.abci.host)?;
+        env(&vars, "ABCI_PORT", &mut self.abci.port)?;
+        env_path(&vars, "ABCI_UDS", &mut self.abci.uds);
+
+        env(
+            &vars,
+            "LIMITS_MEMPOOL_BUFFER",
+            &mut self.limits.mempool_buffer,
+        )?;
+        env(&vars, "LIMITS_INFO_BUFFER", &mut self.limits.info_buffer)?;
+        env(
+            &vars,
+            "LIMITS_INFO_RATE_LIMIT",
+            &mut self.limits.info_rate_limit,
+        )?;
+
+        env(&vars, "KEYS_VERIFYING_KEYS", &mut self.keys.verifying_keys)?;
+        env(&vars, "KEYS_DEPTH", &mut self.keys.depth)?;
+        env(&vars, "KEYS_RATE", &mut self.keys.rate)?;
+        env(
+            &vars,
+            "KEYS_OPTIMIZED_FOR_WEIGHTS",
+            &mut self.keys.optimized_for_weights,
+        )?;
+        env_path(&vars, "KEYS_VALIDATOR_KEY", &mut self.keys.validator_key);
+
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Result<tracing::Level, ConfigError> {
+        self.log_level
+            .parse()
+            .map_err(|_| ConfigError::LogLevel(self.log_level.clone()))
+    }
+}
+
+/// Set `value` from the environment variable `PROTOCASH_<name>`, if there is one.
+fn env<T: FromStr>(
+    vars: &impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    value: &mut T,
+) -> Result<(), ConfigError> {
+    if let Some(s) = vars(name) {
+        *value = s.parse().map_err(|_| ConfigError::Env(name))?;
+    }
+
+    Ok(())
+}
+
+/// Set the optional path `value` from the environment variable `PROTOCASH_<name>`, if there is
+/// one.
+fn env_path(
+    vars: &impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    value: &mut Option<PathBuf>,
+) {
+    if let Some(s) = vars(name) {
+        *value = (!s.is_empty()).then(|| PathBuf::from(s));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::Config;
+    use super::ConfigError;
+
+    #[test]
+    /// Check that a config only needs the values that differ from the defaults, and that the
+    /// environment overrides them
+    fn overrides() -> Result<(), ConfigError> {
+        let mut config = Config::parse(
+            r#"
+            data_dir = "/var/lib/protocash"
+
+            [abci]
+            port = 3000
+
+            [keys]
+            validator_key = "priv_validator_key.json"
+            depth = 20
+            "#,
+        )?;
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/protocash"));
+        assert_eq!(config.abci.port, 3000);
+        assert_eq!(config.abci.host, "127.0.0.1");
+        assert_eq!(config.limits, Config::default().limits);
+        assert_eq!(config.keys.depth, 20);
+        assert_eq!(config.keys.rate, Config::default().keys.rate);
+        assert_eq!(Config::parse(&config.to_toml())?, config);
+
+        let vars = HashMap::from([
+            ("PROTOCASH_ABCI_PORT", "4000"),
+            ("PROTOCASH_LIMITS_INFO_RATE_LIMIT", "10"),
+            ("PROTOCASH_KEYS_VALIDATOR_KEY", ""),
+            ("PROTOCASH_LOG_LEVEL", "debug"),
+            ("PROTOCASH_KEYS_DEPTH", "24"),
+        ]);
+        config.apply_env(|name| vars.get(name).map(|v| v.to_string()))?;
+        assert_eq!(config.abci.port, 4000);
+        assert_eq!(config.limits.info_rate_limit, 10);
+        assert_eq!(config.keys.validator_key, None);
+        assert_eq!(config.keys.tree().depth, 24);
+        assert_eq!(config.log_level()?, tracing::Level::DEBUG);
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/protocash"));
+
+        let vars = HashMap::from([("PROTOCASH_ABCI_PORT", "many")]);
+        assert!(matches!(
+            config.apply_env(|name| vars.get(name).map(|v| v.to_string())),
+            Err(ConfigError::Env("ABCI_PORT"))
+        ));
+
+        assert!(matches!(
+            Config::parse("port = 3000"),
+            Err(ConfigError::Toml(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use util::attestation::Attestations;
+use util::query::field_bytes;
+use util::tx::Transaction;
+use util::tx::ValidatorUpdate;
+use util::types::CoinID;
+
+use crate::state::State;
+
+/// An event, as handed to cometbft. Wallets find their coins by querying cometbft's indexer on the
+/// indexed attributes, such as `commitment.index >= 1000`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Event {
+    pub kind: &'static str,
+    pub attributes: Vec<Attribute>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Attribute {
+    pub key: &'static str,
+    pub value: String,
+
+    /// Whether cometbft indexes the attribute, so that it can be searched.
+    pub index: bool,
+}
+
+impl Event {
+    fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            attributes: Vec::new(),
+        }
+    }
+
+    fn indexed(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.attributes.push(Attribute {
+            key,
+            value: value.to_string(),
+            index: true,
+        });
+
+        self
+    }
+
+    fn stored(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.attributes.push(Attribute {
+            key,
+            value: value.to_string(),
+            index: false,
+        });
+
+        self
+    }
+
+    /// The value of the attribute `key`, if there is one.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|a| a.key == key)
+            .map(|a| a.value.as_str())
+    }
+}
+
+/// Field elements are shown in events as the hex of their canonical encoding.
+fn field_hex(x: &CoinID) -> String {
+    hex::encode(field_bytes(x))
+}
+
+/// The events of a payment whose first new coin went in the tree at `index`: a `payment` event
+/// with its fee, a `nullifier` event for each coin spent, and a `commitment` event for each coin
+/// created, with its index in the tree and its encrypted note if it has one.
+pub fn payment(tx: &Transaction, index: usize) -> Vec<Event> {
+    let mut events = vec![Event::new("payment")
+        .indexed("fee", tx.fee)
+        .stored("memo", hex::encode(&tx.memo))];
+
+    events.extend(
+        tx.serial_numbers
+            .iter()
+            .map(|sn| Event::new("nullifier").indexed("serial_number", field_hex(sn))),
+    );
+
+    events.extend(tx.outputs.iter().enumerate().map(|(i, commitment)| {
+        let event = Event::new("commitment")
+            .indexed("commitment", field_hex(commitment))
+            .indexed("index", index + i);
+
+        match tx.notes.get(i) {
+            Some(note) => event.stored("note", hex::encode(note)),
+            None => event,
+        }
+    }));
+
+    events
+}
+
+/// The event of an applied validator update.
+pub fn validator_update(update: &ValidatorUpdate) -> Event {
+    Event::new("validator_update")
+        .indexed("pub_key", hex::encode(update.pub_key))
+        .stored("power", update.power)
+        .stored("nonce", update.nonce)
+}
+
+/// The events of the attestations published in a block: an `attested_roots` event with the roots
+/// and the height of the block they are after, and an `attestation` event for each validator which
+/// signed them. A wallet trusts the roots once validators with more than two thirds of the power
+/// signed them, see [`Attestations::is_quorum`].
+pub fn attestations(attestations: &Attestations) -> Vec<Event> {
+    let roots = &attestations.roots;
+
+    let mut events = vec![Event::new("attested_roots")
+        .indexed("height", roots.height)
+        .stored("root", field_hex(&roots.root))
+        .stored("nullifier_root", hex::encode(roots.nullifier_root))];
+
+    events.extend(attestations.signatures.iter().map(|(key, signature)| {
+        Event::new("attestation")
+            .indexed("height", roots.height)
+            .indexed("pub_key", hex::encode(key))
+            .stored("signature", hex::encode(signature))
+    }));
+
+    events
+}
+
+/// The event of a block which just ended: the new root of the commitment tree, and how many coins
+/// the block created and spent. A wallet that saw every block up to here knows the whole tree.
+pub fn block(state: &State) -> Event {
+    let changes = state.changes();
+
+    Event::new("block")
+        .indexed("height", state.height())
+        .indexed("root", field_hex(&state.root()))
+        .stored("size", state.tree().len())
+        .stored("commitments", changes.commitments.len())
+        .stored("nullifiers", changes.spents.len())
+}
+
+#[cfg(test)]
+mod test {
+    use util::tx::Transaction;
+    use util::types::CoinID;
+
+    use super::block;
+    use super::field_hex;
+    use super::payment;
+    use crate::state::State;
+
+    #[test]
+    /// Check that a payment shows its nullifiers, and its commitments with their index and note
+    fn payment_events() {
+        let tx = Transaction {
+            root: Default::default(),
+            serial_numbers: vec![CoinID::from(1), CoinID::from(2)],
+            outputs: vec![CoinID::from(3), CoinID::from(4)],
+            fee: 5,
+            memo: Vec::new(),
+            notes: vec![vec![0xab, 0xcd]],
+            proof: Default::default(),
+        };
+
+        let events = payment(&tx, 10);
+        let kinds: Vec<&str> = events.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                "payment",
+                "nullifier",
+                "nullifier",
+                "commitment",
+                "commitment"
+            ]
+        );
+        assert_eq!(events[0].get("fee"), Some("5"));
+
+        let sn = field_hex(&CoinID::from(2));
+        assert_eq!(events[2].get("serial_number"), Some(sn.as_str()));
+
+        assert_eq!(events[3].get("index"), Some("10"));
+        assert_eq!(events[3].get("note"), Some("abcd"));
+        assert_eq!(events[4].get("index"), Some("11"));
+        assert_eq!(events[4].get("note"), None);
+
+        let commitment = field_hex(&CoinID::from(4));
+        assert_eq!(events[4].get("commitment"), Some(commitment.as_str()));
+        assert!(events[4].attributes.iter().all(|a| a.index));
+    }
+
+    #[test]
+    /// Check that the block event carries the new root, and what the block changed
+    fn block_event() {
+        let mut state = State::default();
+        state.append(CoinID::from(1));
+        state.append(CoinID::from(2));
+        state.end_block();
+
+        let event = block(&state);
+        let root = field_hex(&state.root());
+        assert_eq!(event.get("root"), Some(root.as_str()));
+        assert_eq!(event.get("height"), Some("1"));
+        assert_eq!(event.get("commitments"), Some("2"));
+        assert_eq!(event.get("nullifiers"), Some("0"));
+    }
+}
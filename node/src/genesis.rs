@@ -0,0 +1,492 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use ark_bls12_381::Bls12_381;
+use ark_groth16::prepare_verifying_key;
+use ark_groth16::VerifyingKey;
+use ark_serialize::CanonicalDeserialize;
+use ed25519_consensus::VerificationKey;
+use serde::Deserialize;
+use serde::Serialize;
+use util::keys;
+use util::keys::Header;
+use util::keys::KeyError;
+use util::keys::KeyKind;
+use util::tx::Circuit;
+use util::types::CoinCommitment;
+
+use crate::state::State;
+use crate::state::TreeConfig;
+use crate::state::VerifyingKeys;
+
+/// The version of the genesis format.
+pub const VERSION: u32 = 1;
+
+/// The state of the chain at genesis, as found in the `app_state` of cometbft's `genesis.json`.
+/// Binary values are in hex: field elements in their canonical compressed encoding, and
+/// verifying keys as the key files written by `keys generate`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Genesis {
+    pub version: u32,
+
+    /// The depth of the commitment tree.
+    pub depth: u32,
+
+    /// The Poseidon parameters of the commitment tree.
+    pub poseidon: Poseidon,
+
+    /// The number of recent roots that proofs are accepted against.
+    pub root_window: u64,
+
+    /// The commitments of the coins that exist at genesis, in the order they go in the tree.
+    #[serde(default)]
+    pub premine: Vec<String>,
+
+    /// The validators at genesis. If there are none, the ones in cometbft's genesis are used.
+    #[serde(default)]
+    pub validators: Vec<Validator>,
+
+    /// The ed25519 public key which signs validator updates. Without one, the validator set can't
+    /// change after genesis.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validator_admin: Option<String>,
+
+    /// The verifying key of each circuit that payments can be proven with, by circuit name.
+    pub verifying_keys: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Poseidon {
+    pub rate: u32,
+    pub optimized_for_weights: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Validator {
+    /// The ed25519 public key of the validator.
+    pub pub_key: String,
+    pub power: u64,
+}
+
+/// Parse a validator given as `<ed25519 public key in hex>:<power>`.
+impl FromStr for Validator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pub_key, power) = s.split_once(':').ok_or("expected <public key>:<power>")?;
+        let power = power.parse().map_err(|_| "invalid power")?;
+
+        Ok(Self {
+            pub_key: pub_key.to_string(),
+            power,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum GenesisError {
+    Io(io::Error),
+
+    /// The genesis isn't valid JSON, or doesn't have the right fields.
+    Json(serde_json::Error),
+
+    UnsupportedVersion(u32),
+
+    /// There are no Poseidon parameters for this rate, or the depth is out of range.
+    Tree(TreeConfig),
+
+    /// The root window is empty.
+    RootWindow,
+
+    /// The premine commitment at this index isn't a field element, or doesn't fit in the tree.
+    Premine(usize),
+
+    /// The validator at this index has an invalid key or no power, is listed twice, or brings the
+    /// total power of the set above [`MAX_POWER`](crate::state::MAX_POWER).
+    Validator(usize),
+
+    /// The key of the validator admin isn't an ed25519 public key.
+    ValidatorAdmin,
+
+    /// The verifying key of this circuit is missing, or can't be read, or is for another tree than
+    /// the one of the genesis.
+    VerifyingKey(String, Option<KeyError>),
+
+    /// The chain can't start at this height.
+    InitialHeight(u64),
+}
+
+impl fmt::Display for GenesisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenesisError::Io(e) => write!(f, "{e}"),
+            GenesisError::Json(e) => write!(f, "invalid genesis: {e}"),
+            GenesisError::UnsupportedVersion(v) => write!(f, "unsupported genesis version {v}"),
+            GenesisError::Tree(config) => write!(f, "unsupported commitment tree {config:?}"),
+            GenesisError::RootWindow => write!(f, "the root window can't be empty"),
+            GenesisError::Premine(i) => write!(f, "invalid premine commitment at index {i}"),
+            GenesisError::Validator(i) => write!(f, "invalid validator at index {i}"),
+            GenesisError::ValidatorAdmin => write!(f, "invalid validator admin key"),
+            GenesisError::VerifyingKey(circuit, None) => {
+                write!(f, "invalid verifying key for circuit `{circuit}`")
+            }
+            GenesisError::VerifyingKey(circuit, Some(e)) => {
+                write!(f, "invalid verifying key for circuit `{circuit}`: {e}")
+            }
+            GenesisError::InitialHeight(h) => write!(f, "invalid initial height {h}"),
+        }
+    }
+}
+
+impl std::error::Error for GenesisError {}
+
+impl From<io::Error> for GenesisError {
+    fn from(e: io::Error) -> Self {
+        GenesisError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for GenesisError {
+    fn from(e: serde_json::Error) -> Self {
+        GenesisError::Json(e)
+    }
+}
+
+impl Genesis {
+    /// A genesis with no coins, and the verifying keys of every circuit found in `keys_dir`.
+    pub fn new(
+        keys_dir: impl AsRef<Path>,
+        config: TreeConfig,
+        root_window: u64,
+    ) -> Result<Self, GenesisError> {
+        let mut verifying_keys = BTreeMap::new();
+        for circuit in Circuit::ALL {
+            let header = Header {
+                kind: KeyKind::Verifying,
+                circuit,
+                depth: config.depth,
+                rate: config.rate,
+                optimized_for_weights: config.optimized_for_weights,
+            };
+
+            let bytes = fs::read(keys_dir.as_ref().join(header.file_name()))?;
+            verifying_keys.insert(circuit.name().to_string(), hex::encode(bytes));
+        }
+
+        Ok(Self {
+            version: VERSION,
+            depth: config.depth,
+            poseidon: Poseidon {
+                rate: config.rate,
+                optimized_for_weights: config.optimized_for_weights,
+            },
+            root_window,
+            premine: Vec::new(),
+            validators: Vec::new(),
+            validator_admin: None,
+            verifying_keys,
+        })
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, GenesisError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a genesis always serializes")
+    }
+
+    pub fn config(&self) -> TreeConfig {
+        TreeConfig {
+            depth: self.depth,
+            rate: self.poseidon.rate,
+            optimized_for_weights: self.poseidon.optimized_for_weights,
+        }
+    }
+
+    /// Check everything in the genesis, and build the state of the chain right before
+    /// `initial_height`, along with the verifying keys.
+    pub fn build(&self, initial_height: u64) -> Result<(State, VerifyingKeys), GenesisError> {
+        if self.version != VERSION {
+            return Err(GenesisError::UnsupportedVersion(self.version));
+        }
+
+        let config = self.config();
+        if !config.is_valid() {
+            return Err(GenesisError::Tree(config));
+        }
+
+        if self.root_window == 0 {
+            return Err(GenesisError::RootWindow);
+        }
+
+        let height = initial_height
+            .checked_sub(1)
+            .and_then(|h| u32::try_from(h).ok())
+            .ok_or(GenesisError::InitialHeight(initial_height))?;
+
+        let mut state = State::with_config(config, self.root_window as usize);
+
+        for (i, commitment) in self.premine.iter().enumerate() {
+            let commitment: CoinCommitment =
+                decode_hex(commitment).ok_or(GenesisError::Premine(i))?;
+            if state.tree().len() >> config.depth != 0 {
+                return Err(GenesisError::Premine(i));
+            }
+
+            state.append(commitment);
+        }
+
+        for (i, validator) in self.validators.iter().enumerate() {
+            let key = hex::decode(&validator.pub_key)
+                .ok()
+                .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                .filter(|key| VerificationKey::try_from(*key).is_ok())
+                .filter(|key| !state.validators().contains_key(key))
+                .ok_or(GenesisError::Validator(i))?;
+            if validator.power == 0 || state.total_power_with(&key, validator.power).is_none() {
+                return Err(GenesisError::Validator(i));
+            }
+
+            state.set_validator(key, validator.power);
+        }
+
+        if let Some(admin) = &self.validator_admin {
+            let key = hex::decode(admin)
+                .ok()
+                .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                .filter(|key| VerificationKey::try_from(*key).is_ok())
+                .ok_or(GenesisError::ValidatorAdmin)?;
+
+            state.set_validator_admin(Some(key));
+        }
+
+        state.init(height);
+
+        Ok((state, self.verifying_keys()?))
+    }
+
+    /// The verifying keys of the genesis, by circuit.
+    pub fn verifying_keys(&self) -> Result<VerifyingKeys, GenesisError> {
+        if self.verifying_keys.is_empty() {
+            return Err(GenesisError::VerifyingKey(
+                Circuit::Payment.name().into(),
+                None,
+            ));
+        }
+
+        self.verifying_keys
+            .iter()
+            .map(|(name, key)| {
+                let error = |e| GenesisError::VerifyingKey(name.clone(), e);
+
+                let circuit = Circuit::ALL
+                    .into_iter()
+                    .find(|c| c.name() == name)
+                    .ok_or(error(None))?;
+                let bytes = hex::decode(key).map_err(|_| error(None))?;
+
+                let header = Header {
+                    kind: KeyKind::Verifying,
+                    circuit,
+                    depth: self.depth,
+                    rate: self.poseidon.rate,
+                    optimized_for_weights: self.poseidon.optimized_for_weights,
+                };
+                let vk: VerifyingKey<Bls12_381> =
+                    keys::read(bytes.as_slice(), &header).map_err(|e| error(Some(e)))?;
+
+                Ok((circuit, prepare_verifying_key(&vk)))
+            })
+            .collect()
+    }
+}
+
+fn decode_hex<T: CanonicalDeserialize>(hex: &str) -> Option<T> {
+    let bytes = hex::decode(hex).ok()?;
+
+    T::deserialize_compressed(bytes.as_slice()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use ed25519_consensus::SigningKey;
+    use util::keys;
+    use util::keys::Header;
+    use util::keys::KeyKind;
+    use util::query;
+    use util::tx::Circuit;
+    use util::types::CoinID;
+
+    use super::Genesis;
+    use super::GenesisError;
+    use super::Validator;
+    use crate::state::State;
+    use crate::state::TreeConfig;
+
+    /// The public key of the validator at genesis.
+    fn validator_key() -> [u8; 32] {
+        SigningKey::from([7; 32]).verification_key().to_bytes()
+    }
+
+    /// A genesis for a small tree, with the verifying key of the payment circuit.
+    fn sample_genesis() -> Result<Genesis, Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let config = TreeConfig {
+            depth: 4,
+            ..Default::default()
+        };
+
+        let params = config.params().ok_or("no params")?;
+        let (_, vk) = keys::setup(&params, Circuit::Payment, 4, &mut rng)?;
+        let header = Header {
+            kind: KeyKind::Verifying,
+            circuit: Circuit::Payment,
+            depth: 4,
+            rate: config.rate,
+            optimized_for_weights: config.optimized_for_weights,
+        };
+        let mut bytes = Vec::new();
+        keys::write(&mut bytes, &header, &vk)?;
+
+        Ok(Genesis {
+            version: super::VERSION,
+            depth: config.depth,
+            poseidon: super::Poseidon {
+                rate: config.rate,
+                optimized_for_weights: config.optimized_for_weights,
+            },
+            root_window: 10,
+            premine: (1..=3)
+                .map(|i| hex::encode(query::field_bytes(&CoinID::from(i))))
+                .collect(),
+            validators: vec![Validator {
+                pub_key: hex::encode(validator_key()),
+                power: 10,
+            }],
+            validator_admin: Some(hex::encode(
+                SigningKey::new(&mut rng).verification_key().to_bytes(),
+            )),
+            verifying_keys: [(Circuit::Payment.name().into(), hex::encode(bytes))].into(),
+        })
+    }
+
+    #[test]
+    /// Check that the state built from a genesis holds the premine and the validators
+    fn build() -> Result<(), Box<dyn Error>> {
+        let genesis = sample_genesis()?;
+        let parsed = Genesis::parse(genesis.to_json().as_bytes())?;
+        assert_eq!(parsed, genesis);
+
+        let (state, vks) = parsed.build(1)?;
+        assert!(vks.contains_key(&Circuit::Payment));
+
+        let mut expected = State::with_config(genesis.config(), 10);
+        for i in 1..=3 {
+            expected.append(CoinID::from(i));
+        }
+        expected.set_validator(validator_key(), 10);
+        assert!(state.validator_admin().is_some());
+        expected.set_validator_admin(state.validator_admin());
+        expected.init(0);
+        assert_eq!(state.hash(), expected.hash());
+
+        // proofs against the empty tree aren't accepted
+        let empty = State::with_config(genesis.config(), 10);
+        assert!(state.check_root(&empty.root()).is_err());
+        assert!(state.check_root(&state.root()).is_ok());
+
+        let (state, _) = parsed.build(100)?;
+        assert_eq!(state.height(), 99);
+
+        let validator: Validator = format!("{}:10", genesis.validators[0].pub_key).parse()?;
+        assert_eq!(validator, genesis.validators[0]);
+        assert!("07:x".parse::<Validator>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that invalid genesis files are rejected
+    fn invalid() -> Result<(), Box<dyn Error>> {
+        let genesis = sample_genesis()?;
+        let error = |genesis: Genesis| genesis.build(1).err().map(|e| e.to_string());
+
+        assert!(Genesis::parse(b"{}").is_err());
+        assert!(matches!(
+            genesis.build(0),
+            Err(GenesisError::InitialHeight(0))
+        ));
+
+        let mut bad = genesis.clone();
+        bad.version += 1;
+        assert_eq!(error(bad), Some("unsupported genesis version 2".into()));
+
+        let mut bad = genesis.clone();
+        bad.poseidon.rate = 1000;
+        assert!(matches!(bad.build(1), Err(GenesisError::Tree(_))));
+
+        let mut bad = genesis.clone();
+        bad.root_window = 0;
+        assert!(matches!(bad.build(1), Err(GenesisError::RootWindow)));
+
+        // a tree of depth 4 holds 16 coins
+        let mut bad = genesis.clone();
+        bad.premine = vec![bad.premine[0].clone(); 17];
+        assert!(matches!(bad.build(1), Err(GenesisError::Premine(16))));
+
+        let mut bad = genesis.clone();
+        bad.premine[1] = "zz".into();
+        assert!(matches!(bad.build(1), Err(GenesisError::Premine(1))));
+
+        let mut bad = genesis.clone();
+        bad.validators.push(bad.validators[0].clone());
+        assert!(matches!(bad.build(1), Err(GenesisError::Validator(1))));
+
+        // a second validator with all the power there is
+        let mut bad = genesis.clone();
+        bad.validators.push(Validator {
+            pub_key: hex::encode(SigningKey::from([8; 32]).verification_key().to_bytes()),
+            power: crate::state::MAX_POWER,
+        });
+        assert!(matches!(bad.build(1), Err(GenesisError::Validator(1))));
+
+        let mut bad = genesis.clone();
+        bad.validator_admin = Some(hex::encode([7; 31]));
+        assert!(matches!(bad.build(1), Err(GenesisError::ValidatorAdmin)));
+
+        // the key doesn't match the depth of the tree
+        let mut bad = genesis.clone();
+        bad.depth = 5;
+        assert!(matches!(
+            bad.build(1),
+            Err(GenesisError::VerifyingKey(_, Some(_)))
+        ));
+
+        // or its Poseidon parameters
+        let mut bad = genesis.clone();
+        bad.poseidon.optimized_for_weights = false;
+        assert!(matches!(
+            bad.build(1),
+            Err(GenesisError::VerifyingKey(_, Some(_)))
+        ));
+
+        let mut bad = genesis;
+        bad.verifying_keys.clear();
+        assert!(matches!(
+            bad.build(1),
+            Err(GenesisError::VerifyingKey(_, None))
+        ));
+
+        Ok(())
+    }
+}
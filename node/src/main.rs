@@ -1,66 +1,353 @@
+use std::fs;
+use std::path::PathBuf;
+
 use clap::arg;
 use clap::Parser;
+use clap::Subcommand;
 use tower::ServiceBuilder;
 use tower_abci::split;
 use tower_abci::Server;
+use util::keys;
+use util::merkletree::TREE_DEPTH;
+use util::tx;
 
 mod app;
+mod ceremony;
+mod config;
+mod events;
+mod genesis;
+mod mempool;
+mod proposal;
+mod query;
+mod snapshot;
+mod state;
+mod store;
+mod validator_key;
 
 use app::Application;
+use ceremony::CeremonyCommand;
+use config::Config;
+use genesis::Genesis;
+use genesis::Validator;
+use state::TreeConfig;
+use state::VerifyingKeys;
+use state::DEFAULT_ROOT_WINDOW;
+use store::Store;
 
-/// A simple KVStore example on cometbft.
+/// A protocash node, which runs the chain behind cometbft over ABCI.
 #[derive(Parser, Debug)]
+#[command(name = "protocash-node", version)]
 struct Args {
-    /// Binds the TCP server to this host
-    #[arg(long, default_value = "127.0.0.1")]
-    host: String,
+    /// Reads the config from this TOML file. Every value in it can be overridden by an
+    /// environment variable, such as `PROTOCASH_DATA_DIR` or `PROTOCASH_ABCI_PORT`
+    #[arg(
+        long,
+        global = true,
+        default_value = "protocash.toml",
+        env = "PROTOCASH_CONFIG"
+    )]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write the default config, and create the data directory
+    Init {
+        /// Overwrites the config if there already is one
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Run the node, for cometbft to connect to
+    Start,
+
+    /// Write the state of the chain, and its genesis, to a single file that another node can
+    /// import. The node must be stopped.
+    Export {
+        /// Writes the export to this file
+        #[arg(long)]
+        out: PathBuf,
+    },
+
+    /// Start an empty data directory from an export rather than from InitChain. The node goes on
+    /// from the height of the export, so its cometbft must be at the same height.
+    Import {
+        /// Reads the export from this file
+        #[arg(long)]
+        input: PathBuf,
+    },
+
+    /// Manage the keys of the node
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+
+    /// Print the versions of the node, of the chain and of its encodings
+    Version,
+
+    /// Run a multi-party setup ceremony, so that no single party can forge proofs
+    Ceremony {
+        #[command(subcommand)]
+        command: CeremonyCommand,
+    },
+
+    /// Write the `app_state` of a new chain, to go in cometbft's `genesis.json`. The verifying
+    /// keys are read from the `verifying_keys` of the config.
+    Genesis {
+        /// The depth of the commitment tree
+        #[arg(long, default_value_t = TREE_DEPTH as u32)]
+        depth: u32,
+
+        /// The rate of the Poseidon sponge
+        #[arg(long, default_value_t = 4)]
+        rate: u32,
+
+        /// Use the Poseidon parameters optimized for constraints rather than weights
+        #[arg(long)]
+        optimized_for_constraints: bool,
+
+        /// The number of recent roots that proofs are accepted against
+        #[arg(long, default_value_t = DEFAULT_ROOT_WINDOW as u64)]
+        root_window: u64,
+
+        /// The commitment of a coin which exists at genesis, in hex. Can be repeated
+        #[arg(long)]
+        premine: Vec<String>,
+
+        /// A validator at genesis, as `<ed25519 public key in hex>:<power>`. Can be repeated
+        #[arg(long = "validator")]
+        validators: Vec<Validator>,
+
+        /// The ed25519 public key, in hex, which signs validator updates. Without one, the
+        /// validator set can't change after genesis
+        #[arg(long)]
+        validator_admin: Option<String>,
+
+        /// Writes the genesis to this file rather than to stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
 
-    #[arg(long, default_value = "26658")]
-    port: u16,
+#[derive(Subcommand, Debug)]
+enum KeysCommand {
+    /// Generate the proving and verifying keys of the payment circuits. The proving keys go to the
+    /// clients, and the verifying keys to the nodes.
+    Generate {
+        /// Writes the keys to this directory
+        #[arg(long, default_value = "keys")]
+        out: PathBuf,
 
-    /// Binds the UDS server to this path
-    #[arg(long)]
-    uds: Option<String>,
+        /// The depth of the commitment tree, the same as `genesis --depth`. Clients load the keys
+        /// with the same `--depth`
+        #[arg(long, default_value_t = TREE_DEPTH)]
+        depth: usize,
+
+        /// The rate of the Poseidon sponge, the same as `genesis --rate`
+        #[arg(long, default_value_t = 4)]
+        rate: u32,
+
+        /// Use the Poseidon parameters optimized for constraints rather than weights, as with
+        /// `genesis --optimized-for-constraints`
+        #[arg(long)]
+        optimized_for_constraints: bool,
+    },
+
+    /// Print the ed25519 public key, in hex, of the `validator_key` of the config
+    Show,
 }
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+
+    // a broken config can't stop us from replacing it, or from telling our version
+    let config = match args.command {
+        Command::Init { .. } | Command::Version => Config::default(),
+        _ => Config::load(&args.config).expect("failed to load the config"),
+    };
+
     tracing_subscriber::fmt::Subscriber::builder()
-        .with_max_level(tracing::Level::TRACE)
+        .with_max_level(config.log_level().expect("invalid log level"))
         .init();
 
-    let args = Args::parse();
-    let service = Application::default();
+    match args.command {
+        Command::Init { force } => {
+            if args.config.exists() && !force {
+                tracing::error!(
+                    "{} already exists, pass --force to overwrite it",
+                    args.config.display()
+                );
+                return;
+            }
+
+            fs::write(&args.config, config.to_toml()).expect("failed to write the config");
+            fs::create_dir_all(&config.data_dir).expect("failed to create the data directory");
+            tracing::info!("wrote {}", args.config.display());
+        }
+        Command::Start => start(config).await,
+        Command::Export { out } => {
+            let (store, state) = Store::open(&config.data_dir, store::DEFAULT_CHECKPOINT_INTERVAL)
+                .expect("failed to open the data directory");
+            let Some(state) = state else {
+                tracing::error!("there is no chain to export");
+                return;
+            };
+
+            store
+                .export(&state, &out)
+                .expect("failed to write the export");
+            tracing::info!("exported the state at height {}", state.height());
+        }
+        Command::Import { input } => {
+            let (mut store, state) =
+                Store::open(&config.data_dir, store::DEFAULT_CHECKPOINT_INTERVAL)
+                    .expect("failed to open the data directory");
+            if state.is_some() {
+                tracing::error!("{} already holds a chain", config.data_dir.display());
+                return;
+            }
+
+            let state = store.import(&input).expect("failed to import");
+            tracing::info!("imported the state at height {}", state.height());
+        }
+        Command::Keys {
+            command:
+                KeysCommand::Generate {
+                    out,
+                    depth,
+                    rate,
+                    optimized_for_constraints,
+                },
+        } => {
+            let paths = keys::generate(
+                out,
+                depth,
+                rate,
+                !optimized_for_constraints,
+                &mut rand::rngs::OsRng,
+            )
+            .expect("failed to generate the keys");
+            for path in paths {
+                tracing::info!("wrote {}", path.display());
+            }
+        }
+        Command::Keys {
+            command: KeysCommand::Show,
+        } => {
+            let Some(path) = config.keys.validator_key else {
+                tracing::error!("there is no validator key in the config");
+                return;
+            };
+
+            let key = validator_key::load(path).expect("failed to load the validator key");
+            println!("{}", hex::encode(key.verification_key().to_bytes()));
+        }
+        Command::Version => {
+            println!("protocash-node {}", env!("CARGO_PKG_VERSION"));
+            println!("app version: {}", app::APP_VERSION);
+            println!("transaction version: {}", tx::VERSION);
+            println!("genesis version: {}", genesis::VERSION);
+            println!("snapshot format: {}", snapshot::FORMAT);
+        }
+        Command::Ceremony { command } => {
+            ceremony::run(command).expect("the ceremony failed");
+        }
+        Command::Genesis {
+            depth,
+            rate,
+            optimized_for_constraints,
+            root_window,
+            premine,
+            validators,
+            validator_admin,
+            out,
+        } => {
+            let tree = TreeConfig {
+                depth,
+                rate,
+                optimized_for_weights: !optimized_for_constraints,
+            };
+
+            let mut genesis = Genesis::new(&config.keys.verifying_keys, tree, root_window)
+                .expect("failed to read the verifying keys");
+            genesis.premine = premine;
+            genesis.validators = validators;
+            genesis.validator_admin = validator_admin;
+
+            if let Err(e) = genesis.build(1) {
+                tracing::error!("invalid genesis: {e}");
+                return;
+            }
+
+            match out {
+                Some(path) => fs::write(path, genesis.to_json()).expect("failed to write"),
+                None => println!("{}", genesis.to_json()),
+            }
+        }
+    }
+}
+
+/// Serve the application to cometbft until it disconnects.
+async fn start(config: Config) {
+    // the chain keeps the verifying keys of its genesis, so these are only needed before it
+    // starts, and InitChain or a snapshot brings the right ones anyway
+    let verifying_keys = || {
+        let tree = config.keys.tree();
+
+        keys::load_verifying_keys(
+            &config.keys.verifying_keys,
+            tree.depth as usize,
+            tree.rate,
+            tree.optimized_for_weights,
+        )
+        .unwrap_or_else(|e| {
+            tracing::warn!("no verifying keys until the chain starts: {e}");
+            VerifyingKeys::new()
+        })
+    };
+    let validator_key = config
+        .keys
+        .validator_key
+        .as_ref()
+        .map(|path| validator_key::load(path).expect("failed to load the validator key"));
+    let service = Application::open(&config, verifying_keys, validator_key)
+        .expect("failed to open the data directory");
 
     let (consensus, mempool, snapshot, info) = split::service(service, 1);
 
     // Hand those components to the ABCI server, but customize request behavior
     // for each category -- for instance, apply load-shedding only to mempool
     // and info requests, but not to consensus requests.
+    let limits = &config.limits;
     let server_builder = Server::builder()
         .consensus(consensus)
         .snapshot(snapshot)
         .mempool(
             ServiceBuilder::new()
                 .load_shed()
-                .buffer(10)
+                .buffer(limits.mempool_buffer)
                 .service(mempool),
         )
         .info(
             ServiceBuilder::new()
                 .load_shed()
-                .buffer(100)
-                .rate_limit(50, std::time::Duration::from_secs(1))
+                .buffer(limits.info_buffer)
+                .rate_limit(limits.info_rate_limit, std::time::Duration::from_secs(1))
                 .service(info),
         );
 
     let server = server_builder.finish().unwrap();
 
-    if let Some(uds_path) = args.uds {
+    if let Some(uds_path) = config.abci.uds {
         server.listen_unix(uds_path).await.unwrap();
     } else {
         server
-            .listen_tcp(format!("{}:{}", args.host, args.port))
+            .listen_tcp(format!("{}:{}", config.abci.host, config.abci.port))
             .await
             .unwrap();
     }
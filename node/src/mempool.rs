@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use sha2::Digest;
+use sha2::Sha256;
+use util::tx::Tx;
+use util::tx::TxError;
+use util::types::CoinID;
+use util::types::Value;
+
+/// The hash of a transaction, as computed by cometbft.
+pub type TxHash = [u8; 32];
+
+pub fn tx_hash(tx: &[u8]) -> TxHash {
+    Sha256::digest(tx).into()
+}
+
+/// A pending transaction, as far as we are concerned.
+struct Pending {
+    serial_numbers: Vec<CoinID>,
+
+    /// The number of commits since the transaction was last checked.
+    age: u32,
+}
+
+/// The serial numbers revealed by the transactions in the mempool. cometbft keeps the
+/// transactions themselves, but only asks us about them one at a time, so this is how we refuse a
+/// transaction which spends a coin that a pending transaction already spends.
+#[derive(Default)]
+pub struct Mempool {
+    pending: HashMap<TxHash, Pending>,
+
+    /// The pending transaction spending each coin.
+    spenders: HashMap<CoinID, TxHash>,
+
+    /// The lowest fee of the payments we let in.
+    min_fee: Value,
+}
+
+impl Mempool {
+    pub fn new(min_fee: Value) -> Self {
+        Self {
+            min_fee,
+            ..Default::default()
+        }
+    }
+
+    /// Check that `tx` pays at least the minimum fee, if it is a payment, and that it doesn't
+    /// spend a coin that another pending transaction spends.
+    pub fn check(&self, hash: &TxHash, tx: &Tx) -> Result<(), TxError> {
+        if matches!(tx, Tx::Payment(tx) if tx.fee < self.min_fee) {
+            return Err(TxError::InsufficientFee);
+        }
+
+        let conflict = tx
+            .serial_numbers()
+            .iter()
+            .any(|sn| self.spenders.get(sn).is_some_and(|spender| spender != hash));
+
+        if conflict {
+            Err(TxError::MempoolConflict)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Track a transaction which was let into the mempool, or refresh one which passed a recheck.
+    pub fn insert(&mut self, hash: TxHash, tx: &Tx) {
+        for sn in tx.serial_numbers() {
+            self.spenders.insert(*sn, hash);
+        }
+
+        self.pending.insert(
+            hash,
+            Pending {
+                serial_numbers: tx.serial_numbers().to_vec(),
+                age: 0,
+            },
+        );
+    }
+
+    /// Stop tracking a transaction, because it was included in a block or evicted.
+    pub fn remove(&mut self, hash: &TxHash) {
+        let Some(pending) = self.pending.remove(hash) else {
+            return;
+        };
+
+        for sn in pending.serial_numbers {
+            if self.spenders.get(&sn) == Some(hash) {
+                self.spenders.remove(&sn);
+            }
+        }
+    }
+
+    /// Called on every commit. cometbft rechecks every transaction left in its mempool after a
+    /// commit, so a transaction that wasn't checked since the previous commit isn't in there
+    /// anymore, and its coins are released.
+    pub fn commit(&mut self) {
+        let stale: Vec<TxHash> = self
+            .pending
+            .iter_mut()
+            .filter_map(|(hash, pending)| {
+                pending.age += 1;
+
+                (pending.age > 1).then_some(*hash)
+            })
+            .collect();
+
+        for hash in stale {
+            self.remove(&hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ark_groth16::Proof;
+    use util::tx::Transaction;
+    use util::tx::Tx;
+    use util::tx::TxError;
+    use util::types::CoinID;
+
+    use super::Mempool;
+
+    fn tx(serial_numbers: &[u64]) -> Tx {
+        Tx::Payment(Transaction {
+            root: Default::default(),
+            serial_numbers: serial_numbers.iter().map(|&sn| CoinID::from(sn)).collect(),
+            outputs: vec![Default::default(); serial_numbers.len()],
+            fee: 0,
+            memo: Vec::new(),
+            notes: Vec::new(),
+            proof: Proof::default(),
+        })
+    }
+
+    #[test]
+    /// Check that a transaction spending a pending coin is refused until the coin is released
+    fn conflicts() {
+        let mut mempool = Mempool::default();
+
+        let (a, b) = (tx(&[1, 2]), tx(&[2, 3]));
+        mempool.insert([1; 32], &a);
+
+        assert_eq!(mempool.check(&[2; 32], &b), Err(TxError::MempoolConflict));
+        assert_eq!(mempool.check(&[2; 32], &tx(&[3])), Ok(()));
+
+        // rechecking a transaction doesn't conflict with itself
+        assert_eq!(mempool.check(&[1; 32], &a), Ok(()));
+
+        mempool.remove(&[1; 32]);
+        assert_eq!(mempool.check(&[2; 32], &b), Ok(()));
+    }
+
+    #[test]
+    /// Check that payments below the minimum fee are refused
+    fn fees() {
+        let mempool = Mempool::new(5);
+
+        let Tx::Payment(mut payment) = tx(&[1]) else {
+            unreachable!()
+        };
+        payment.fee = 4;
+        let low = Tx::Payment(payment.clone());
+        payment.fee = 5;
+        let enough = Tx::Payment(payment);
+
+        assert_eq!(mempool.check(&[1; 32], &low), Err(TxError::InsufficientFee));
+        assert_eq!(mempool.check(&[1; 32], &enough), Ok(()));
+    }
+
+    #[test]
+    /// Check that transactions which aren't rechecked after a commit are forgotten
+    fn stale() {
+        let mut mempool = Mempool::default();
+
+        mempool.insert([1; 32], &tx(&[1]));
+        mempool.insert([2; 32], &tx(&[2]));
+        mempool.commit();
+
+        // only the first one is rechecked
+        mempool.insert([1; 32], &tx(&[1]));
+        mempool.commit();
+
+        assert_eq!(mempool.pending.len(), 1);
+        assert_eq!(
+            mempool.check(&[3; 32], &tx(&[1])),
+            Err(TxError::MempoolConflict)
+        );
+        assert_eq!(mempool.check(&[3; 32], &tx(&[2])), Ok(()));
+    }
+}
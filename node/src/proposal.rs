@@ -0,0 +1,548 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+use util::attestation::Attestation;
+use util::attestation::Attestations;
+use util::tx::Transaction;
+use util::tx::Tx;
+use util::tx::TxError;
+use util::tx::ValidatorUpdate;
+use util::types::CoinID;
+
+use crate::mempool;
+use crate::mempool::TxHash;
+use crate::state;
+use crate::state::State;
+use crate::state::VerifyingKeys;
+
+/// The number of bytes a transaction takes up against `max_tx_bytes`. cometbft counts the
+/// transactions as they are encoded in the block: a field tag, the length as a varint, and the
+/// transaction itself.
+pub fn encoded_len(tx: &[u8]) -> usize {
+    let mut varint = 1;
+    let mut len = tx.len() >> 7;
+    while len > 0 {
+        varint += 1;
+        len >>= 7;
+    }
+
+    1 + varint + tx.len()
+}
+
+/// Build a block out of the transactions that cometbft hands to the proposer, straight from its
+/// mempool. Returns the indices of the transactions to include, in the order they go in the
+/// block.
+///
+/// Transactions which don't decode, don't verify, spend a coin that a transaction already in the
+/// block spends or create more coins than the tree has room left for are left out. Validator
+/// updates go first, by nonce, then payments with the highest fees. Transactions with the same
+/// priority keep their mempool order, so the block only depends on `txs`. A transaction which
+/// doesn't fit in what is left of `max_tx_bytes` is skipped, but a smaller one after it may still
+/// fit. Attestations are left out too: the proposer publishes its own, see [`attestations`].
+///
+/// The proofs of the payments that aren't in `verified` are verified all at once, before any is
+/// picked. See [`state::verify_payments`].
+pub fn prepare<T: AsRef<[u8]>>(
+    state: &State,
+    vks: &VerifyingKeys,
+    txs: &[T],
+    verified: &HashSet<TxHash>,
+    max_tx_bytes: usize,
+) -> Vec<usize> {
+    let candidates: Vec<(usize, Tx)> = txs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, tx)| Some((i, state::decode_tx(tx.as_ref()).ok()?)))
+        .collect();
+
+    let unverified: Vec<(usize, &Transaction)> = candidates
+        .iter()
+        .filter_map(|(i, tx)| match tx {
+            Tx::Payment(tx) if !verified.contains(&mempool::tx_hash(txs[*i].as_ref())) => {
+                Some((*i, tx))
+            }
+            _ => None,
+        })
+        .collect();
+    let payments: Vec<&Transaction> = unverified.iter().map(|(_, tx)| *tx).collect();
+    let invalid: HashSet<usize> = unverified
+        .iter()
+        .zip(state::verify_payments(vks, &payments))
+        .filter_map(|((i, _), result)| result.is_err().then_some(*i))
+        .collect();
+
+    let mut candidates: Vec<(usize, Tx)> = candidates
+        .into_iter()
+        .filter(|(i, _)| !invalid.contains(i))
+        .collect();
+
+    // a stable sort, so ties are broken by the mempool order
+    candidates.sort_by_key(|(_, tx)| match tx {
+        Tx::ValidatorUpdate(update) => (false, Reverse(0), update.nonce),
+        Tx::Payment(tx) => (true, Reverse(tx.fee), 0),
+        Tx::Attestations(_) => (true, Reverse(0), 0),
+    });
+
+    let mut spent: HashSet<CoinID> = HashSet::new();
+    let mut nonce = 0;
+    let mut left = max_tx_bytes;
+    let mut room = state.tree().room();
+    let mut block = Vec::new();
+
+    for (i, tx) in candidates {
+        let len = encoded_len(txs[i].as_ref());
+        if len > left
+            || outputs(&tx) > room
+            || tx.serial_numbers().iter().any(|sn| spent.contains(sn))
+            || matches!(tx, Tx::Attestations(_))
+        {
+            continue;
+        }
+
+        if state.check(&tx).is_err() {
+            continue;
+        }
+
+        if let Tx::ValidatorUpdate(update) = &tx {
+            if next_nonce(update, &mut nonce).is_err() {
+                continue;
+            }
+        }
+
+        spent.extend(tx.serial_numbers());
+        left -= len;
+        room -= outputs(&tx);
+        block.push(i);
+    }
+
+    block
+}
+
+/// Check every transaction of a block proposed by someone else. A single invalid payment, or two
+/// payments spending the same coin, and the whole block is rejected: the proposer should have
+/// left them out. So are payments creating more coins than the tree has room left for.
+/// Attestations may only come first, where the proposer puts them.
+///
+/// The proofs are only verified once everything else checks out, all at once. See
+/// [`state::verify_payments`].
+pub fn process<T: AsRef<[u8]>>(
+    state: &State,
+    vks: &VerifyingKeys,
+    txs: &[T],
+) -> Result<(), TxError> {
+    let mut spent: HashSet<CoinID> = HashSet::new();
+    let mut nonce = 0;
+    let mut room = state.tree().room();
+    let mut payments = Vec::new();
+
+    for (i, tx) in txs.iter().enumerate() {
+        let tx = state::decode_tx(tx.as_ref())?;
+        if i > 0 && matches!(tx, Tx::Attestations(_)) {
+            return Err(TxError::Malformed);
+        }
+
+        state.check(&tx)?;
+        for sn in tx.serial_numbers() {
+            if !spent.insert(*sn) {
+                return Err(TxError::AlreadySpent);
+            }
+        }
+
+        room = room.checked_sub(outputs(&tx)).ok_or(TxError::TreeFull)?;
+
+        match tx {
+            Tx::Payment(tx) => payments.push(tx),
+            Tx::ValidatorUpdate(update) => next_nonce(&update, &mut nonce)?,
+            Tx::Attestations(_) => {}
+        }
+    }
+
+    let payments: Vec<&Transaction> = payments.iter().collect();
+    match state::verify_payments(vks, &payments)
+        .into_iter()
+        .find_map(Result::err)
+    {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Collect the attestations in the votes for the previous block, for the proposer to publish
+/// first thing in its block. `votes` are the public key of each validator which voted, and its
+/// vote extension.
+///
+/// The attestations that aren't about the roots before the previous block, or aren't signed by a
+/// validator, are left out. Should the validators disagree, only the roots with the most power
+/// behind them are kept. There is nothing to publish if no validator attested.
+pub fn attestations<'a>(
+    state: &State,
+    votes: impl IntoIterator<Item = ([u8; 32], &'a [u8])>,
+) -> Option<Attestations> {
+    let height = state.height().checked_sub(1)?;
+    let validators = state.validators();
+
+    let mut candidates: Vec<Attestations> = Vec::new();
+    for (key, extension) in votes {
+        let Some(attestation) = Attestation::decode(extension) else {
+            continue;
+        };
+
+        if attestation.roots.height != height
+            || !validators.contains_key(&key)
+            || !attestation.verify(&key)
+        {
+            continue;
+        }
+
+        let signature = (key, attestation.signature);
+        match candidates.iter_mut().find(|c| c.roots == attestation.roots) {
+            Some(candidate) => candidate.signatures.push(signature),
+            None => candidates.push(Attestations {
+                roots: attestation.roots,
+                signatures: vec![signature],
+            }),
+        }
+    }
+
+    for candidate in &mut candidates {
+        candidate.signatures.sort_by_key(|(key, _)| *key);
+        candidate.signatures.dedup_by_key(|(key, _)| *key);
+    }
+
+    candidates.into_iter().max_by_key(|candidate| {
+        candidate
+            .signatures
+            .iter()
+            .map(|(key, _)| validators[key])
+            .sum::<u64>()
+    })
+}
+
+/// The number of coins that a transaction adds to the tree.
+fn outputs(tx: &Tx) -> usize {
+    match tx {
+        Tx::Payment(tx) => tx.outputs.len(),
+        Tx::ValidatorUpdate(_) | Tx::Attestations(_) => 0,
+    }
+}
+
+/// The state only knows about the nonces of the previous blocks, so the validator updates of a
+/// block must also use increasing nonces among themselves. `nonce` is the lowest nonce that the
+/// next update of the block may use.
+fn next_nonce(update: &ValidatorUpdate, nonce: &mut u64) -> Result<(), TxError> {
+    if update.nonce < *nonce {
+        return Err(TxError::Unauthorized);
+    }
+
+    *nonce = update.nonce + 1;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::error::Error;
+
+    use ark_bls12_381::Bls12_381;
+    use ark_bls12_381::Fr as BlsFr;
+    use ark_groth16::prepare_verifying_key;
+    use ark_groth16::Groth16;
+    use ark_snark::SNARK;
+    use ark_std::UniformRand;
+    use ed25519_consensus::SigningKey;
+    use util::attestation::Attestation;
+    use util::joinsplit::Output;
+    use util::payment::PaymentProof;
+    use util::poseidon::commitment;
+    use util::tx;
+    use util::tx::Circuit;
+    use util::tx::Transaction;
+    use util::tx::Tx;
+    use util::tx::TxError;
+    use util::tx::ValidatorUpdate;
+    use util::types::Coin;
+    use util::types::CoinID;
+    use util::types::Key;
+    use util::user::User;
+
+    use super::attestations;
+    use super::encoded_len;
+    use super::prepare;
+    use super::process;
+    use crate::mempool;
+    use crate::state::State;
+    use crate::state::TreeConfig;
+    use crate::state::VerifyingKeys;
+
+    #[test]
+    /// Check the length of transactions against the protobuf encoding
+    fn lengths() {
+        assert_eq!(encoded_len(&[]), 2);
+        assert_eq!(encoded_len(&[0; 127]), 129);
+        assert_eq!(encoded_len(&[0; 128]), 131);
+    }
+
+    #[test]
+    /// Check that the proposer keeps the valid, non conflicting transactions that fit, by fee
+    fn proposal() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let mut state = State::default();
+        let params = state.tree().params().clone();
+
+        let user = User::new(&params, &mut rng)?;
+        let mut coins = Vec::new();
+        for _ in 0..3 {
+            let mut coin = Coin::rand(&mut rng);
+            coin.pk = user.pk;
+            coin.value = 100;
+
+            let index = state.append(commitment::new_commitment(&params, &coin)?);
+            coins.push((index, coin));
+        }
+        state.end_block();
+
+        // pay `coins[i]` away, leaving `fee` to the validators
+        let payment = |i: usize, fee: u64, rng: &mut rand::rngs::ThreadRng| {
+            let (index, coin) = coins[i].clone();
+            let output = Output::new(Key::rand(rng), coin.value - fee, rng);
+
+            PaymentProof::new(
+                state.tree(),
+                index,
+                coin,
+                user.clone(),
+                output,
+                BlsFr::default(),
+            )
+        };
+
+        let (pk, vk) =
+            Groth16::<Bls12_381>::circuit_specific_setup(payment(0, 0, &mut rng)?, &mut rng)?;
+        let vks = VerifyingKeys::from([(Circuit::Payment, prepare_verifying_key(&vk))]);
+
+        let mut txs = Vec::new();
+        for (i, fee) in [(0, 1), (1, 5), (0, 3), (2, 5)] {
+            let mut payment = payment(i, fee, &mut rng)?;
+            payment.tx_digest = tx::digest(payment.fee, b"", &[]);
+
+            let tx = Transaction {
+                root: payment.root,
+                serial_numbers: vec![payment.serial_number],
+                outputs: vec![payment.output],
+                fee: payment.fee,
+                memo: Vec::new(),
+                notes: Vec::new(),
+                proof: Groth16::<Bls12_381>::prove(&pk, payment, &mut rng)?,
+            };
+
+            txs.push(Tx::Payment(tx).encode());
+        }
+
+        // garbage, and a proof which doesn't match its fee
+        txs.push(vec![1, 2, 3]);
+        let Tx::Payment(mut forged) = crate::state::decode_tx(&txs[0])? else {
+            unreachable!()
+        };
+        forged.fee += 1;
+        txs.push(Tx::Payment(forged).encode());
+
+        let all = usize::MAX;
+        let none = HashSet::new();
+        // coin 0 is spent by txs 0 and 2, so the one with the highest fee wins
+        assert_eq!(prepare(&state, &vks, &txs, &none, all), [1, 3, 2]);
+        assert_eq!(prepare(&state, &vks, &txs, &none, all), [1, 3, 2]);
+
+        // proofs which were already verified aren't verified again
+        let verified = HashSet::from([mempool::tx_hash(&txs[5])]);
+        assert_eq!(
+            prepare(&state, &vks, &txs[5..], &none, all),
+            [] as [usize; 0]
+        );
+        assert_eq!(prepare(&state, &vks, &txs[5..], &verified, all), [0]);
+
+        // a block is only valid if every transaction is
+        let block = |indices: &[usize]| -> Vec<&[u8]> {
+            indices.iter().map(|&i| txs[i].as_slice()).collect()
+        };
+        assert_eq!(process(&state, &vks, &block(&[1, 3, 2])), Ok(()));
+        assert_eq!(
+            process(&state, &vks, &block(&[1, 0, 2])),
+            Err(TxError::AlreadySpent)
+        );
+        assert_eq!(
+            process(&state, &vks, &block(&[1, 4])),
+            Err(TxError::Malformed)
+        );
+        assert_eq!(
+            process(&state, &vks, &block(&[5, 1])),
+            Err(TxError::InvalidProof)
+        );
+
+        let len = encoded_len(&txs[0]);
+        assert_eq!(prepare(&state, &vks, &txs, &none, 2 * len + 1), [1, 3]);
+        assert_eq!(
+            prepare(&state, &vks, &txs, &none, len - 1),
+            [] as [usize; 0]
+        );
+
+        // coins spent in a previous block are left out
+        let Tx::Payment(tx) = crate::state::decode_tx(&txs[1])? else {
+            unreachable!()
+        };
+        state.apply(&tx);
+        assert_eq!(prepare(&state, &vks, &txs, &none, all), [3, 2]);
+        assert_eq!(
+            process(&state, &vks, &block(&[1])),
+            Err(TxError::AlreadySpent)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that validator updates go first, in the order of their nonces
+    fn validator_updates() {
+        let mut rng = rand::thread_rng();
+        let admin = SigningKey::new(&mut rng);
+
+        let mut state = State::default();
+        state.set_validator_admin(Some(admin.verification_key().to_bytes()));
+        state.set_validator([1; 32], 10);
+
+        let update = |nonce| {
+            let mut update = ValidatorUpdate {
+                pub_key: SigningKey::new(rand::thread_rng())
+                    .verification_key()
+                    .to_bytes(),
+                power: 5,
+                nonce,
+                signature: [0; 64],
+            };
+            update.signature = admin.sign(&update.message()).to_bytes();
+
+            Tx::ValidatorUpdate(update).encode()
+        };
+
+        let vks = VerifyingKeys::new();
+        let txs = [update(4), update(1), update(1), update(7)];
+        assert_eq!(
+            prepare(&state, &vks, &txs, &HashSet::new(), usize::MAX),
+            [1, 0, 3]
+        );
+
+        let block = |indices: &[usize]| -> Vec<&[u8]> {
+            indices.iter().map(|&i| txs[i].as_slice()).collect()
+        };
+        assert_eq!(process(&state, &vks, &block(&[1, 0, 3])), Ok(()));
+        assert_eq!(
+            process(&state, &vks, &block(&[0, 1])),
+            Err(TxError::Unauthorized)
+        );
+        assert_eq!(
+            process(&state, &vks, &block(&[1, 2])),
+            Err(TxError::Unauthorized)
+        );
+    }
+
+    #[test]
+    /// Check that a block can't create more coins than the tree has room for, even though each
+    /// payment fits on its own
+    fn full_tree() {
+        let config = TreeConfig {
+            depth: 2,
+            ..Default::default()
+        };
+        let state = State::with_config(config, 1);
+
+        let payment = |sn: u64| {
+            Tx::Payment(Transaction {
+                root: state.root(),
+                serial_numbers: vec![CoinID::from(sn)],
+                outputs: vec![CoinID::from(sn); 3],
+                fee: 0,
+                memo: Vec::new(),
+                notes: Vec::new(),
+                proof: Default::default(),
+            })
+            .encode()
+        };
+
+        assert_eq!(
+            process(&state, &VerifyingKeys::new(), &[payment(1), payment(2)]),
+            Err(TxError::TreeFull)
+        );
+    }
+
+    #[test]
+    /// Check that the proposer publishes the attestations of the validators, and only those
+    fn published_attestations() {
+        let keys: Vec<SigningKey> = (1..=3).map(|i| SigningKey::from([i; 32])).collect();
+        let pub_keys: Vec<[u8; 32]> = keys
+            .iter()
+            .map(|key| key.verification_key().to_bytes())
+            .collect();
+
+        let mut state = State::default();
+        for key in &pub_keys {
+            state.set_validator(*key, 10);
+        }
+        state.end_block();
+        let roots = state.roots();
+        state.end_block();
+
+        // the validators 1 and 0 attest, 2 attests to other roots, and 3 isn't a validator
+        let outsider = SigningKey::from([4; 32]);
+        let mut other = roots;
+        other.nullifier_root[0] ^= 1;
+        let extensions = [
+            Attestation::sign(&keys[1], roots).encode(),
+            Attestation::sign(&keys[0], roots).encode(),
+            Attestation::sign(&keys[2], other).encode(),
+            Attestation::sign(&outsider, roots).encode(),
+            vec![1, 2, 3],
+            Vec::new(),
+        ];
+        let votes = [
+            pub_keys[1],
+            pub_keys[0],
+            pub_keys[2],
+            outsider.verification_key().to_bytes(),
+            pub_keys[2],
+            pub_keys[0],
+        ];
+        let all_votes = || votes.into_iter().zip(extensions.iter().map(Vec::as_slice));
+
+        let published = attestations(&state, all_votes()).expect("two validators attested");
+        assert_eq!(published.roots, roots);
+        let signers: Vec<[u8; 32]> = published.signatures.iter().map(|(key, _)| *key).collect();
+        let mut expected = vec![pub_keys[0], pub_keys[1]];
+        expected.sort();
+        assert_eq!(signers, expected);
+
+        let vks = VerifyingKeys::new();
+        let tx = Tx::Attestations(published.clone()).encode();
+        assert_eq!(process(&state, &vks, &[&tx]), Ok(()));
+        assert_eq!(process(&state, &vks, &[&tx, &tx]), Err(TxError::Malformed));
+
+        // the proposer doesn't take attestations from the mempool
+        assert_eq!(
+            prepare(&state, &vks, &[&tx], &HashSet::new(), usize::MAX),
+            [] as [usize; 0]
+        );
+
+        let mut reversed = published.clone();
+        reversed.signatures.reverse();
+        let tx = Tx::Attestations(reversed).encode();
+        assert_eq!(process(&state, &vks, &[&tx]), Err(TxError::Malformed));
+
+        let mut forged = published;
+        forged.roots = other;
+        let tx = Tx::Attestations(forged).encode();
+        assert_eq!(process(&state, &vks, &[&tx]), Err(TxError::Unauthorized));
+
+        // a block later, these attestations are stale
+        state.end_block();
+        assert_eq!(attestations(&state, all_votes()), None);
+    }
+}
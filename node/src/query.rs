@@ -0,0 +1,339 @@
+use std::fmt;
+
+use ark_serialize::CanonicalDeserialize;
+use util::merkletree::Root;
+use util::query;
+use util::query::NullifierProof;
+use util::query::Stats;
+use util::types::CoinCommitment;
+use util::types::CoinID;
+
+use crate::state::State;
+
+/// The most commitments returned by a single `/commitments` query.
+pub const MAX_LIMIT: usize = 1000;
+
+/// A step of the proof of an answer, as handed to cometbft. The steps go from the answer up to the
+/// app hash, and their data is encoded with [`query::encode`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProofOp {
+    pub kind: &'static str,
+    pub key: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// The answer to a query.
+#[derive(Debug, Default)]
+pub struct Answer {
+    /// What the answer is about, such as the index of a commitment.
+    pub key: Vec<u8>,
+
+    /// The answer itself, encoded with [`query::encode`].
+    pub value: Vec<u8>,
+
+    /// The proof of the answer, if one was asked for.
+    pub proof: Vec<ProofOp>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueryError {
+    /// There is nothing at this path.
+    UnknownPath,
+
+    /// An argument of the query couldn't be parsed.
+    BadArgument(&'static str),
+
+    /// There is no commitment at this index.
+    NotFound,
+
+    /// Only the last committed state can be queried.
+    Height,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::UnknownPath => write!(f, "unknown query path"),
+            QueryError::BadArgument(arg) => write!(f, "invalid argument `{arg}`"),
+            QueryError::NotFound => write!(f, "no commitment at this index"),
+            QueryError::Height => write!(f, "only the latest height can be queried"),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Answer the query at `path` against the state. Paths may carry arguments in a query string,
+/// such as `/commitments?from=10&limit=20`.
+///
+/// With `prove`, the answer comes with a proof that it is in the state committed to by the app
+/// hash: a path in the commitment tree or the nullifier tree if the answer is in one, and then
+/// the [preimage](query::AppHashPreimage) of the app hash.
+pub fn answer(state: &State, path: &str, height: u64, prove: bool) -> Result<Answer, QueryError> {
+    if height != 0 && height != u64::from(state.height()) {
+        return Err(QueryError::Height);
+    }
+
+    let (path, args) = path.split_once('?').unwrap_or((path, ""));
+    let arg = |name: &'static str| {
+        args.split('&')
+            .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+            .map(|value| {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| QueryError::BadArgument(name))
+            })
+            .transpose()
+    };
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let mut answer = match segments.as_slice() {
+        ["root"] => Answer {
+            value: query::encode(&state.root()),
+            ..Default::default()
+        },
+        ["roots", "recent"] => Answer {
+            value: query::encode(&state.recent_roots().copied().collect::<Vec<Root>>()),
+            ..Default::default()
+        },
+        ["commitment", index] => {
+            let index = parse_index(index)?;
+            let commitment = commitment(state, index)?;
+
+            Answer {
+                key: (index as u64).to_be_bytes().to_vec(),
+                value: query::encode(&commitment),
+                proof: commitment_proof(state, index, prove).into_iter().collect(),
+            }
+        }
+        ["commitments"] => {
+            let from = arg("from")?.unwrap_or(0);
+            let limit = arg("limit")?.unwrap_or(MAX_LIMIT).min(MAX_LIMIT);
+
+            let leaves = state.tree().leaves();
+            let range = from.min(leaves.len())..from.saturating_add(limit).min(leaves.len());
+
+            Answer {
+                key: (from as u64).to_be_bytes().to_vec(),
+                value: query::encode(&leaves[range.clone()].to_vec()),
+                proof: range
+                    .flat_map(|i| commitment_proof(state, i, prove))
+                    .collect(),
+            }
+        }
+        ["path", index] => {
+            let index = parse_index(index)?;
+            let path = state
+                .tree()
+                .generate_proof(index)
+                .map_err(|_| QueryError::NotFound)?;
+
+            Answer {
+                key: (index as u64).to_be_bytes().to_vec(),
+                value: query::encode(&path),
+                ..Default::default()
+            }
+        }
+        ["nullifier", sn] => {
+            let key = hex::decode(sn).map_err(|_| QueryError::BadArgument("sn"))?;
+            let sn = CoinID::deserialize_compressed(key.as_slice())
+                .map_err(|_| QueryError::BadArgument("sn"))?;
+
+            // only a proof needs the whole set, in order
+            let proof = if prove {
+                vec![ProofOp {
+                    kind: query::NULLIFIER_OP,
+                    key: key.clone(),
+                    data: query::encode(&NullifierProof::new(&state.spents(), &sn)),
+                }]
+            } else {
+                Vec::new()
+            };
+
+            Answer {
+                key,
+                value: query::encode(&state.is_spent(&sn)),
+                proof,
+            }
+        }
+        ["stats"] => Answer {
+            value: query::encode(&Stats {
+                height: state.height(),
+                size: state.size(),
+                commitments: state.tree().len() as u64,
+                nullifiers: state.nullifier_count() as u64,
+                root_window: state.root_window() as u64,
+            }),
+            ..Default::default()
+        },
+        _ => return Err(QueryError::UnknownPath),
+    };
+
+    if prove {
+        answer.proof.push(ProofOp {
+            kind: query::APP_OP,
+            key: Vec::new(),
+            data: query::encode(&state.preimage()),
+        });
+    }
+
+    Ok(answer)
+}
+
+fn parse_index(index: &str) -> Result<usize, QueryError> {
+    index.parse().map_err(|_| QueryError::BadArgument("index"))
+}
+
+fn commitment(state: &State, index: usize) -> Result<CoinCommitment, QueryError> {
+    state
+        .tree()
+        .leaves()
+        .get(index)
+        .copied()
+        .ok_or(QueryError::NotFound)
+}
+
+/// The path of the commitment at `index` to the root of the tree, if a proof was asked for.
+fn commitment_proof(state: &State, index: usize, prove: bool) -> Option<ProofOp> {
+    if !prove {
+        return None;
+    }
+
+    let path = state.tree().generate_proof(index).ok()?;
+
+    Some(ProofOp {
+        kind: query::COMMITMENT_OP,
+        key: (index as u64).to_be_bytes().to_vec(),
+        data: query::encode(&path),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use util::merkletree::Root;
+    use util::merkletree::TreePath;
+    use util::query;
+    use util::query::AppHashPreimage;
+    use util::query::NullifierProof;
+    use util::query::Stats;
+    use util::tx::Transaction;
+    use util::types::CoinCommitment;
+    use util::types::CoinID;
+
+    use super::answer;
+    use super::QueryError;
+    use crate::state::State;
+
+    fn sample_state() -> State {
+        let mut state = State::default();
+        for i in 1..=10 {
+            state.append(CoinID::from(i));
+            state.end_block();
+        }
+
+        let tx = Transaction {
+            root: state.root(),
+            serial_numbers: vec![CoinID::from(42)],
+            outputs: vec![CoinID::from(11)],
+            fee: 0,
+            memo: Vec::new(),
+            notes: Vec::new(),
+            proof: Default::default(),
+        };
+        state.apply(&tx);
+        state.end_block();
+
+        state
+    }
+
+    /// Check the last op of a proof against the app hash, and return the preimage.
+    fn open(state: &State, ops: &[super::ProofOp]) -> Result<AppHashPreimage, Box<dyn Error>> {
+        let op = ops.last().ok_or("no proof")?;
+        assert_eq!(op.kind, query::APP_OP);
+
+        let preimage: AppHashPreimage = query::decode(&op.data)?;
+        assert_eq!(preimage.hash(), state.hash());
+
+        Ok(preimage)
+    }
+
+    #[test]
+    /// Check that every answer decodes, and is proven against the app hash
+    fn answers() -> Result<(), Box<dyn Error>> {
+        let state = sample_state();
+        let params = state.tree().params().clone();
+
+        let reply = answer(&state, "/root", 0, true)?;
+        let root: Root = query::decode(&reply.value)?;
+        assert_eq!(open(&state, &reply.proof)?.root, root);
+
+        let reply = answer(&state, "/roots/recent", 0, true)?;
+        let roots: Vec<Root> = query::decode(&reply.value)?;
+        assert_eq!(roots.last(), Some(&root));
+        assert_eq!(
+            open(&state, &reply.proof)?.roots_hash,
+            query::roots_hash(roots.iter())
+        );
+
+        let reply = answer(&state, "/commitment/3", 0, true)?;
+        let commitment: CoinCommitment = query::decode(&reply.value)?;
+        assert_eq!(commitment, CoinID::from(4));
+        let path: TreePath = query::decode(&reply.proof[0].data)?;
+        assert!(path.verify(&params, &params, &root, [commitment])?);
+        open(&state, &reply.proof)?;
+
+        let reply = answer(&state, "/commitments?from=8&limit=5", 0, true)?;
+        let commitments: Vec<CoinCommitment> = query::decode(&reply.value)?;
+        assert_eq!(commitments, [9, 10, 11].map(CoinID::from));
+        assert_eq!(reply.proof.len(), commitments.len() + 1);
+
+        let reply = answer(&state, "/path/10", 0, false)?;
+        let path: TreePath = query::decode(&reply.value)?;
+        assert!(path.verify(&params, &params, &root, [CoinID::from(11)])?);
+        assert!(reply.proof.is_empty());
+
+        for (sn, spent) in [(42, true), (41, false)] {
+            let sn = CoinID::from(sn);
+            let path = format!("/nullifier/{}", hex::encode(query::field_bytes(&sn)));
+
+            let reply = answer(&state, &path, 0, true)?;
+            assert_eq!(query::decode::<bool>(&reply.value)?, spent);
+
+            let proof: NullifierProof = query::decode(&reply.proof[0].data)?;
+            let nullifier_root = open(&state, &reply.proof)?.nullifier_root;
+            assert_eq!(proof.verify(&sn, &nullifier_root), Some(spent));
+        }
+
+        let reply = answer(&state, "/stats", 0, false)?;
+        let stats: Stats = query::decode(&reply.value)?;
+        assert_eq!(
+            (stats.height, stats.commitments, stats.nullifiers),
+            (11, 11, 1)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that bad queries are refused
+    fn errors() {
+        let state = sample_state();
+
+        let error = |path, height| answer(&state, path, height, false).err();
+        assert_eq!(error("/nothing", 0), Some(QueryError::UnknownPath));
+        assert_eq!(error("/commitment/11", 0), Some(QueryError::NotFound));
+        assert_eq!(error("/path/x", 0), Some(QueryError::BadArgument("index")));
+        assert_eq!(
+            error("/commitments?limit=-1", 0),
+            Some(QueryError::BadArgument("limit"))
+        );
+        assert_eq!(
+            error("/nullifier/0g", 0),
+            Some(QueryError::BadArgument("sn"))
+        );
+        assert_eq!(error("/root", 3), Some(QueryError::Height));
+        assert!(error("/root", 11).is_none());
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+
+use ark_serialize::CanonicalDeserialize;
+use ark_serialize::CanonicalSerialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::genesis::Genesis;
+use crate::state::State;
+use crate::state::VerifyingKeys;
+
+/// The format of our snapshots. Bump it whenever the encoding of [`State`] or the layout of the
+/// chunks changes, so that nodes don't try to restore snapshots they can't read.
+pub const FORMAT: u32 = 4;
+
+/// The size of a chunk, well under the 16 MB that cometbft accepts.
+pub const CHUNK_SIZE: usize = 4 << 20;
+
+/// Take a snapshot every this many blocks.
+pub const DEFAULT_INTERVAL: u64 = 1000;
+
+/// The number of snapshots kept around for other nodes.
+pub const DEFAULT_KEEP_RECENT: usize = 2;
+
+/// A snapshot of the state, as described to cometbft.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub height: u64,
+    pub format: u32,
+    pub chunks: u32,
+
+    /// The SHA-256 hash of the whole serialized state and genesis.
+    pub hash: [u8; 32],
+
+    /// The SHA-256 hashes of the chunks, one after the other. This is how a chunk is checked as
+    /// soon as it arrives, before the whole state is there.
+    pub metadata: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Snapshot `state`, and the genesis that the chain started from, in chunks of `chunk_size`
+    /// bytes.
+    pub fn take(state: &State, genesis: Option<&[u8]>, chunk_size: usize) -> (Self, Vec<Vec<u8>>) {
+        let payload = Payload {
+            genesis: genesis.map(<[u8]>::to_vec),
+            state: state.encode(),
+        };
+
+        let mut bytes = Vec::with_capacity(payload.compressed_size());
+        payload
+            .serialize_compressed(&mut bytes)
+            .expect("serializing to a Vec can't fail");
+        let chunks: Vec<Vec<u8>> = bytes.chunks(chunk_size).map(<[u8]>::to_vec).collect();
+
+        let snapshot = Self {
+            height: state.height().into(),
+            format: FORMAT,
+            chunks: chunks.len() as u32,
+            hash: Sha256::digest(&bytes).into(),
+            metadata: chunks.iter().flat_map(Sha256::digest).collect(),
+        };
+
+        (snapshot, chunks)
+    }
+
+    fn chunk_hash(&self, index: usize) -> &[u8] {
+        &self.metadata[32 * index..32 * (index + 1)]
+    }
+}
+
+/// What a snapshot holds. The genesis comes along so that the restored node verifies payments with
+/// the keys of the chain, rather than with whichever ones it was configured with.
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+struct Payload {
+    genesis: Option<Vec<u8>>,
+    state: Vec<u8>,
+}
+
+/// The snapshots that this node serves to others.
+pub struct Snapshots {
+    /// Take a snapshot every this many blocks. Zero disables snapshots.
+    interval: u64,
+
+    /// The number of snapshots to keep.
+    keep_recent: usize,
+
+    snapshots: BTreeMap<u64, (Snapshot, Vec<Vec<u8>>)>,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERVAL, DEFAULT_KEEP_RECENT)
+    }
+}
+
+impl Snapshots {
+    pub fn new(interval: u64, keep_recent: usize) -> Self {
+        Self {
+            interval,
+            keep_recent,
+            snapshots: BTreeMap::new(),
+        }
+    }
+
+    /// Snapshot the state, along with `genesis`, if it is at a snapshot height, and forget the
+    /// oldest snapshots.
+    pub fn maybe_take(&mut self, state: &State, genesis: Option<&[u8]>) {
+        let height = u64::from(state.height());
+        if self.interval == 0 || height == 0 || height % self.interval != 0 {
+            return;
+        }
+
+        self.snapshots
+            .insert(height, Snapshot::take(state, genesis, CHUNK_SIZE));
+
+        while self.snapshots.len() > self.keep_recent {
+            self.snapshots.pop_first();
+        }
+    }
+
+    /// Forget the snapshots below `retain_height`. Nodes restoring them would need blocks that are
+    /// gone.
+    pub fn prune(&mut self, retain_height: u64) {
+        self.snapshots = self.snapshots.split_off(&retain_height);
+    }
+
+    /// Every snapshot we have, oldest first.
+    pub fn list(&self) -> Vec<Snapshot> {
+        self.snapshots.values().map(|(s, _)| s.clone()).collect()
+    }
+
+    pub fn chunk(&self, height: u64, format: u32, index: u32) -> Option<&[u8]> {
+        let (snapshot, chunks) = self.snapshots.get(&height)?;
+        if snapshot.format != format {
+            return None;
+        }
+
+        chunks.get(index as usize).map(Vec::as_slice)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OfferError {
+    /// We can't read snapshots of this format.
+    Format,
+
+    /// The snapshot doesn't describe its chunks properly.
+    Invalid,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApplyError {
+    /// The chunk doesn't match its hash, so whoever sent it should be avoided and the chunk
+    /// fetched again.
+    BadChunk,
+
+    /// Every chunk matched its hash, but the state they make up isn't the one that the chain
+    /// agreed on, its genesis is invalid or for another tree, or the chunk isn't part of the
+    /// snapshot at all. The whole snapshot has to be rejected.
+    BadSnapshot,
+}
+
+/// The state rebuilt from a snapshot.
+pub struct Restored {
+    pub state: State,
+
+    /// The genesis that the chain started from, as the snapshot encodes it, and its verifying
+    /// keys. None if the node that took the snapshot didn't have it, as after an import without
+    /// one.
+    pub genesis: Option<(Vec<u8>, VerifyingKeys)>,
+}
+
+/// A snapshot being restored, chunk by chunk.
+pub struct Restore {
+    snapshot: Snapshot,
+
+    /// The app hash of the chain at the height of the snapshot. cometbft gets it from the light
+    /// client, so it can be trusted.
+    app_hash: [u8; 32],
+
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl Restore {
+    /// Start restoring `snapshot`, which should end up with the given app hash.
+    pub fn offer(snapshot: Snapshot, app_hash: [u8; 32]) -> Result<Self, OfferError> {
+        if snapshot.format != FORMAT {
+            return Err(OfferError::Format);
+        }
+
+        if snapshot.chunks == 0 || snapshot.metadata.len() != 32 * snapshot.chunks as usize {
+            return Err(OfferError::Invalid);
+        }
+
+        let chunks = vec![None; snapshot.chunks as usize];
+
+        Ok(Self {
+            snapshot,
+            app_hash,
+            chunks,
+        })
+    }
+
+    /// Apply the chunk at `index`. Once the last chunk is in, the state is rebuilt and checked
+    /// against the app hash, and returned with the genesis, which has to be for the tree of the
+    /// state.
+    pub fn apply(&mut self, index: u32, chunk: Vec<u8>) -> Result<Option<Restored>, ApplyError> {
+        let index = index as usize;
+        if index >= self.chunks.len() {
+            return Err(ApplyError::BadSnapshot);
+        }
+
+        if Sha256::digest(&chunk).as_slice() != self.snapshot.chunk_hash(index) {
+            return Err(ApplyError::BadChunk);
+        }
+
+        self.chunks[index] = Some(chunk);
+
+        if self.chunks.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let bytes: Vec<u8> = self.chunks.iter().flatten().flatten().copied().collect();
+        if Sha256::digest(&bytes).as_slice() != self.snapshot.hash {
+            return Err(ApplyError::BadSnapshot);
+        }
+
+        let payload = Payload::deserialize_compressed(bytes.as_slice())
+            .map_err(|_| ApplyError::BadSnapshot)?;
+        let state = State::decode(&payload.state).map_err(|_| ApplyError::BadSnapshot)?;
+        if state.hash() != self.app_hash || u64::from(state.height()) != self.snapshot.height {
+            return Err(ApplyError::BadSnapshot);
+        }
+
+        let genesis = match payload.genesis {
+            Some(bytes) => {
+                let genesis = Genesis::parse(&bytes).map_err(|_| ApplyError::BadSnapshot)?;
+                if genesis.config() != state.config()
+                    || genesis.root_window != state.root_window() as u64
+                {
+                    return Err(ApplyError::BadSnapshot);
+                }
+                let keys = genesis
+                    .verifying_keys()
+                    .map_err(|_| ApplyError::BadSnapshot)?;
+
+                Some((bytes, keys))
+            }
+            None => None,
+        };
+
+        Ok(Some(Restored { state, genesis }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use util::keys;
+    use util::keys::Header;
+    use util::keys::KeyKind;
+    use util::tx::Circuit;
+    use util::types::CoinID;
+
+    use super::ApplyError;
+    use super::OfferError;
+    use super::Restore;
+    use super::Snapshot;
+    use super::Snapshots;
+    use crate::genesis::Genesis;
+    use crate::state::State;
+    use crate::state::TreeConfig;
+
+    fn sample_state() -> State {
+        let mut state = State::default();
+        for i in 0..100 {
+            state.append(CoinID::from(i));
+            state.end_block();
+        }
+
+        state
+    }
+
+    #[test]
+    /// Check that a state restored from chunks, in any order, is the snapshotted state
+    fn restore() {
+        let state = sample_state();
+        let (snapshot, chunks) = Snapshot::take(&state, None, 1000);
+        assert!(snapshot.chunks > 2);
+
+        let mut restore = Restore::offer(snapshot, state.hash()).unwrap();
+        let mut restored = None;
+        for (i, chunk) in chunks.into_iter().enumerate().rev() {
+            restored = restore.apply(i as u32, chunk).unwrap();
+        }
+
+        let restored = restored.unwrap();
+        assert_eq!(restored.state.hash(), state.hash());
+        assert!(restored.genesis.is_none());
+    }
+
+    #[test]
+    /// Check that bad chunks and snapshots which don't match the app hash are rejected
+    fn restore_sound() {
+        let state = sample_state();
+        let (snapshot, mut chunks) = Snapshot::take(&state, None, 1000);
+
+        let mut wrong = snapshot.clone();
+        wrong.format += 1;
+        assert_eq!(
+            Restore::offer(wrong, state.hash()).err(),
+            Some(OfferError::Format)
+        );
+
+        let mut restore = Restore::offer(snapshot.clone(), state.hash()).unwrap();
+        let mut chunk = chunks[0].clone();
+        chunk[0] ^= 1;
+        assert_eq!(restore.apply(0, chunk).err(), Some(ApplyError::BadChunk));
+        assert_eq!(
+            restore.apply(snapshot.chunks, Vec::new()).err(),
+            Some(ApplyError::BadSnapshot)
+        );
+
+        let mut restore = Restore::offer(snapshot, [0; 32]).unwrap();
+        let last = chunks.pop().unwrap();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            assert!(matches!(restore.apply(i as u32, chunk), Ok(None)));
+        }
+        assert_eq!(
+            restore.apply(restore.chunks.len() as u32 - 1, last).err(),
+            Some(ApplyError::BadSnapshot)
+        );
+    }
+
+    /// Restore the whole snapshot of `state` taken along with `genesis`.
+    fn restore_with(state: &State, genesis: &Genesis) -> Result<super::Restored, ApplyError> {
+        let (snapshot, chunks) = Snapshot::take(state, Some(genesis.to_json().as_bytes()), 1000);
+
+        let mut restore = Restore::offer(snapshot, state.hash()).unwrap();
+        let mut restored = None;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            restored = restore.apply(i as u32, chunk)?;
+        }
+
+        Ok(restored.unwrap())
+    }
+
+    #[test]
+    /// Check that the genesis comes along with the state, and has to be for the tree of the state
+    fn restore_genesis() -> Result<(), Box<dyn Error>> {
+        let config = TreeConfig {
+            depth: 4,
+            ..Default::default()
+        };
+        let params = config.params().ok_or("no params")?;
+        let (_, vk) = keys::setup(&params, Circuit::Payment, 4, &mut rand::thread_rng())?;
+        let header = Header {
+            kind: KeyKind::Verifying,
+            circuit: Circuit::Payment,
+            depth: config.depth,
+            rate: config.rate,
+            optimized_for_weights: config.optimized_for_weights,
+        };
+        let mut bytes = Vec::new();
+        keys::write(&mut bytes, &header, &vk)?;
+
+        let genesis = Genesis {
+            version: crate::genesis::VERSION,
+            depth: config.depth,
+            poseidon: crate::genesis::Poseidon {
+                rate: config.rate,
+                optimized_for_weights: config.optimized_for_weights,
+            },
+            root_window: 10,
+            premine: Vec::new(),
+            validators: Vec::new(),
+            validator_admin: None,
+            verifying_keys: [(Circuit::Payment.name().into(), hex::encode(bytes))].into(),
+        };
+        let (mut state, _) = genesis.build(1)?;
+        state.append(CoinID::from(1));
+        state.end_block();
+
+        let restored = restore_with(&state, &genesis).unwrap();
+        assert_eq!(restored.state.hash(), state.hash());
+        let (bytes, vks) = restored.genesis.unwrap();
+        assert_eq!(Genesis::parse(&bytes)?, genesis);
+        assert!(vks.contains_key(&Circuit::Payment));
+
+        let mut deeper = genesis.clone();
+        deeper.depth = 5;
+        assert_eq!(
+            restore_with(&state, &deeper).err(),
+            Some(ApplyError::BadSnapshot)
+        );
+
+        let mut wider = genesis.clone();
+        wider.root_window = 20;
+        assert_eq!(
+            restore_with(&state, &wider).err(),
+            Some(ApplyError::BadSnapshot)
+        );
+
+        let mut keyless = genesis;
+        keyless.verifying_keys.clear();
+        assert_eq!(
+            restore_with(&state, &keyless).err(),
+            Some(ApplyError::BadSnapshot)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that snapshots are taken at the interval, and only the recent and retained ones are
+    /// kept
+    fn keep_recent() {
+        let mut snapshots = Snapshots::new(10, 2);
+        let mut state = State::default();
+
+        for i in 0..35 {
+            state.append(CoinID::from(i));
+            state.end_block();
+            snapshots.maybe_take(&state, None);
+        }
+
+        let heights: Vec<u64> = snapshots.list().iter().map(|s| s.height).collect();
+        assert_eq!(heights, [20, 30]);
+        assert!(snapshots.chunk(30, super::FORMAT, 0).is_some());
+        assert!(snapshots.chunk(10, super::FORMAT, 0).is_none());
+
+        snapshots.prune(30);
+        assert_eq!(snapshots.list().len(), 1);
+        snapshots.prune(31);
+        assert!(snapshots.list().is_empty());
+    }
+}
@@ -0,0 +1,1074 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+use ark_bls12_381::Bls12_381;
+use ark_groth16::PreparedVerifyingKey;
+use ark_serialize::CanonicalDeserialize;
+use ark_serialize::CanonicalSerialize;
+use ark_serialize::SerializationError;
+use ed25519_consensus::Signature;
+use ed25519_consensus::VerificationKey;
+use sha2::Digest;
+use sha2::Sha256;
+use util::attestation::Attestations;
+use util::attestation::Roots;
+use util::batch;
+use util::batch::Statement;
+use util::merkletree::IncrementalMerkleTree;
+use util::merkletree::Params;
+use util::merkletree::Root;
+use util::merkletree::TREE_DEPTH;
+use util::poseidon::get_default_poseidon_parameters;
+use util::query;
+use util::query::AppHashPreimage;
+use util::tx::Circuit;
+use util::tx::Transaction;
+use util::tx::Tx;
+use util::tx::TxError;
+use util::tx::ValidatorUpdate;
+use util::tx::MAX_TX_SIZE;
+use util::types::CoinCommitment;
+use util::types::CoinID;
+
+/// Domain separator of the commitment to the validator set.
+const VALIDATORS_DOMAIN: &[u8] = b"protocash/validators/v1";
+
+/// The verifying key of each circuit that payments can be proven with.
+pub type VerifyingKeys = HashMap<Circuit, PreparedVerifyingKey<Bls12_381>>;
+
+/// The number of recent roots a payment proof may be made against, unless set otherwise at
+/// genesis.
+pub const DEFAULT_ROOT_WINDOW: usize = 100;
+
+/// The highest total voting power of the validator set, which cometbft caps at `i64::MAX / 8`.
+pub const MAX_POWER: u64 = i64::MAX as u64 / 8;
+
+/// How the commitment tree is built. Clients have to build their proofs with the same tree, so
+/// this is fixed at genesis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeConfig {
+    /// The depth of the tree, which holds up to `2^depth` coins.
+    pub depth: u32,
+
+    /// The rate of the Poseidon sponge.
+    pub rate: u32,
+
+    /// Whether the Poseidon parameters are optimized for weights rather than constraints.
+    pub optimized_for_weights: bool,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        Self {
+            depth: TREE_DEPTH as u32,
+            rate: 4,
+            optimized_for_weights: true,
+        }
+    }
+}
+
+impl TreeConfig {
+    /// The Poseidon parameters of the tree, if there are any for this rate.
+    pub fn params(&self) -> Option<Params> {
+        get_default_poseidon_parameters(self.rate as usize, self.optimized_for_weights)
+    }
+
+    /// Whether a tree can be built with this config.
+    pub fn is_valid(&self) -> bool {
+        (1..usize::BITS).contains(&self.depth) && self.params().is_some()
+    }
+}
+
+pub struct State {
+    /// The tree of coin commitments. Clients prove that the coin they spend is in here.
+    tree: IncrementalMerkleTree,
+
+    /// How `tree` is built.
+    config: TreeConfig,
+
+    /// The roots of the tree at the end of the last `root_window` blocks, oldest first. A proof
+    /// only commits to a single root, so we accept any of these to give clients some time to get
+    /// their payment included.
+    roots: VecDeque<Root>,
+
+    /// The maximum number of roots kept in `roots`.
+    root_window: usize,
+
+    /// We check in this set to see if a coin is already spent
+    spents: BTreeSet<CoinID>,
+
+    /// The root of `spents`, computed at most once between two changes to it. Rebuilding it costs
+    /// as much as every coin ever spent, and it is needed many times per block.
+    nullifier_root: OnceLock<[u8; 32]>,
+
+    /// The voting power of each validator, by ed25519 public key.
+    validators: BTreeMap<[u8; 32], u64>,
+
+    /// The ed25519 public key which signs validator updates. Without one, the validator set is
+    /// fixed at genesis.
+    validator_admin: Option<[u8; 32]>,
+
+    /// The lowest nonce that the next validator update may use.
+    validator_nonce: u64,
+
+    height: u32,
+    size: u32,
+
+    /// What changed since the last call to [`Self::take_changes`].
+    changes: Changes,
+}
+
+/// What a block changed in the state, in the order it changed it. This is what gets stored on disk
+/// for each block, [replaying](State::replay) it on the state before the block gives the state
+/// after the block.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Changes {
+    /// The coins added to the tree.
+    pub commitments: Vec<CoinCommitment>,
+
+    /// The serial numbers of the coins spent.
+    pub spents: Vec<CoinID>,
+
+    /// The voting power set for each validator, zero for the ones removed.
+    pub validators: Vec<([u8; 32], u64)>,
+
+    /// The validator nonce at the end of the block.
+    pub validator_nonce: u64,
+}
+
+/// The serialized form of a [`State`], as found in snapshots.
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+struct Encoded {
+    depth: u32,
+    rate: u32,
+    optimized_for_weights: bool,
+    leaves: Vec<CoinCommitment>,
+    roots: Vec<Root>,
+    root_window: u64,
+    spents: Vec<CoinID>,
+    validators: Vec<([u8; 32], u64)>,
+    validator_admin: Option<[u8; 32]>,
+    validator_nonce: u64,
+    height: u32,
+    size: u32,
+}
+
+/// Decode a transaction, as submitted to the mempool. See [`Tx::decode`].
+pub fn decode_tx(bytes: &[u8]) -> Result<Tx, TxError> {
+    let tx = Tx::decode(bytes)?;
+    if bytes.len() > MAX_TX_SIZE && !matches!(tx, Tx::Attestations(_)) {
+        return Err(TxError::TooLarge);
+    }
+
+    Ok(tx)
+}
+
+/// Verify the proof of a payment against the key of its circuit. Validator updates have no proof,
+/// their signature is checked with the rest of the update in [`State::check`].
+pub fn verify(vks: &VerifyingKeys, tx: &Tx) -> Result<(), TxError> {
+    match tx {
+        Tx::Payment(tx) => verify_payment(vks, tx),
+        Tx::ValidatorUpdate(_) | Tx::Attestations(_) => Ok(()),
+    }
+}
+
+/// Verify the proof of a payment against the key of its circuit.
+pub fn verify_payment(vks: &VerifyingKeys, tx: &Transaction) -> Result<(), TxError> {
+    let pvk = tx
+        .circuit()
+        .and_then(|circuit| vks.get(&circuit))
+        .ok_or(TxError::UnsupportedCircuit)?;
+
+    if tx.verify(pvk).unwrap_or(false) {
+        Ok(())
+    } else {
+        Err(TxError::InvalidProof)
+    }
+}
+
+/// Verify the proofs of many payments at once, in a batch for each circuit. See
+/// [`batch::find_invalid`]. Returns the result of each payment, in order.
+pub fn verify_payments(vks: &VerifyingKeys, txs: &[&Transaction]) -> Vec<Result<(), TxError>> {
+    let mut results = vec![Ok(()); txs.len()];
+
+    let mut circuits: HashMap<Circuit, Vec<usize>> = HashMap::new();
+    for (i, tx) in txs.iter().enumerate() {
+        match tx.circuit().filter(|circuit| vks.contains_key(circuit)) {
+            Some(circuit) => circuits.entry(circuit).or_default().push(i),
+            None => results[i] = Err(TxError::UnsupportedCircuit),
+        }
+    }
+
+    for (circuit, indices) in circuits {
+        let statements: Vec<Statement> = indices
+            .iter()
+            .map(|&i| Statement {
+                proof: &txs[i].proof,
+                inputs: txs[i].public_inputs(),
+            })
+            .collect();
+
+        for j in batch::find_invalid(&vks[&circuit], &statements) {
+            results[indices[j]] = Err(TxError::InvalidProof);
+        }
+    }
+
+    results
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new(DEFAULT_ROOT_WINDOW)
+    }
+}
+
+impl State {
+    /// Create an empty state which accepts proofs made against any of the last `root_window`
+    /// roots.
+    pub fn new(root_window: usize) -> Self {
+        Self::with_config(TreeConfig::default(), root_window)
+    }
+
+    /// Create an empty state whose tree is built with `config`, which must be
+    /// [valid](TreeConfig::is_valid).
+    pub fn with_config(config: TreeConfig, root_window: usize) -> Self {
+        assert!(root_window > 0, "the root window can't be empty");
+
+        let params = config
+            .params()
+            .expect("failed to build params for poseidon");
+
+        let tree = IncrementalMerkleTree::new(&params, config.depth as usize)
+            .expect("failed to build the tree");
+
+        let roots = VecDeque::from([tree.root()]);
+
+        Self {
+            tree,
+            config,
+            roots,
+            root_window,
+            spents: BTreeSet::new(),
+            nullifier_root: OnceLock::new(),
+            validators: BTreeMap::new(),
+            validator_admin: None,
+            validator_nonce: 0,
+            height: 0,
+            size: 0,
+            changes: Changes::default(),
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn config(&self) -> TreeConfig {
+        self.config
+    }
+
+    /// Close genesis: the chain starts after `height`, and proofs are only accepted against the
+    /// root of the initial coins.
+    pub fn init(&mut self, height: u32) {
+        self.height = height;
+        self.roots = VecDeque::from([self.tree.root()]);
+    }
+
+    /// The voting power of each validator, by ed25519 public key.
+    pub fn validators(&self) -> &BTreeMap<[u8; 32], u64> {
+        &self.validators
+    }
+
+    /// The total voting power of the validator set once the validator `key` has the given power,
+    /// unless it is above [`MAX_POWER`].
+    pub fn total_power_with(&self, key: &[u8; 32], power: u64) -> Option<u64> {
+        self.validators
+            .iter()
+            .filter(|(other, _)| *other != key)
+            .try_fold(power, |total, (_, power)| total.checked_add(*power))
+            .filter(|total| *total <= MAX_POWER)
+    }
+
+    /// Set the voting power of a validator. A power of zero removes it.
+    pub fn set_validator(&mut self, key: [u8; 32], power: u64) {
+        self.changes.validators.push((key, power));
+
+        if power == 0 {
+            self.validators.remove(&key);
+        } else {
+            self.validators.insert(key, power);
+        }
+    }
+
+    /// The key which signs validator updates, if the validator set can change at all.
+    pub fn validator_admin(&self) -> Option<[u8; 32]> {
+        self.validator_admin
+    }
+
+    /// Set the key which signs validator updates. `None` fixes the validator set.
+    pub fn set_validator_admin(&mut self, key: Option<[u8; 32]>) {
+        self.validator_admin = key;
+    }
+
+    /// The roots after the last block, which the validators attest to.
+    pub fn roots(&self) -> Roots {
+        Roots {
+            height: self.height,
+            root: self.root(),
+            nullifier_root: self.nullifier_root(),
+        }
+    }
+
+    /// The tree of coin commitments.
+    pub fn tree(&self) -> &IncrementalMerkleTree {
+        &self.tree
+    }
+
+    /// The root of the commitment tree.
+    pub fn root(&self) -> Root {
+        self.tree.root()
+    }
+
+    /// The recent roots that proofs are accepted against, oldest first.
+    pub fn recent_roots(&self) -> impl Iterator<Item = &Root> {
+        self.roots.iter()
+    }
+
+    /// The number of recent roots that proofs are accepted against.
+    pub fn root_window(&self) -> usize {
+        self.root_window
+    }
+
+    /// The number of coins created since genesis.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Check that a proof made against `root` can still be accepted.
+    pub fn check_root(&self, root: &Root) -> Result<(), TxError> {
+        if self.roots.contains(root) {
+            Ok(())
+        } else {
+            Err(TxError::UnknownRoot)
+        }
+    }
+
+    /// Close the current block: record the root of the tree in the window of recent roots, and
+    /// forget the roots which fall out of it.
+    pub fn end_block(&mut self) {
+        self.height += 1;
+
+        self.roots.push_back(self.tree.root());
+        while self.roots.len() > self.root_window {
+            self.roots.pop_front();
+        }
+    }
+
+    /// A commitment to the set of spent serial numbers. See [`query::nullifier_root`].
+    pub fn nullifier_root(&self) -> [u8; 32] {
+        *self
+            .nullifier_root
+            .get_or_init(|| query::nullifier_root(&self.spents()))
+    }
+
+    /// Mark the coins with these serial numbers as spent.
+    fn spend<'a>(&mut self, serial_numbers: impl IntoIterator<Item = &'a CoinID>) {
+        self.spents.extend(serial_numbers);
+        self.nullifier_root = OnceLock::new();
+    }
+
+    /// The spent serial numbers, in ascending order.
+    pub fn spents(&self) -> Vec<CoinID> {
+        self.spents.iter().copied().collect()
+    }
+
+    /// Whether the coin with this serial number is spent.
+    pub fn is_spent(&self, serial_number: &CoinID) -> bool {
+        self.spents.contains(serial_number)
+    }
+
+    /// The number of spent serial numbers.
+    pub fn nullifier_count(&self) -> usize {
+        self.spents.len()
+    }
+
+    /// Everything that goes into the app hash.
+    pub fn preimage(&self) -> AppHashPreimage {
+        AppHashPreimage {
+            root: self.root(),
+            nullifier_root: self.nullifier_root(),
+            roots_hash: query::roots_hash(self.roots.iter()),
+            validators_hash: self.validators_hash(),
+            height: self.height,
+            size: self.size,
+        }
+    }
+
+    /// The application hash. See [`AppHashPreimage::hash`].
+    pub fn hash(&self) -> [u8; 32] {
+        self.preimage().hash()
+    }
+
+    /// A commitment to the validator set, in ascending order of public keys, and to who may change
+    /// it.
+    fn validators_hash(&self) -> [u8; 32] {
+        let hasher = Sha256::new()
+            .chain_update(VALIDATORS_DOMAIN)
+            .chain_update(self.validator_admin.map_or([0; 33], |key| {
+                let mut bytes = [1; 33];
+                bytes[1..].copy_from_slice(&key);
+                bytes
+            }))
+            .chain_update(self.validator_nonce.to_be_bytes())
+            .chain_update((self.validators.len() as u64).to_be_bytes());
+
+        self.validators
+            .iter()
+            .fold(hasher, |hasher, (key, power)| {
+                hasher.chain_update(key).chain_update(power.to_be_bytes())
+            })
+            .finalize()
+            .into()
+    }
+
+    /// Serialize the whole state, so that another node can restore it with [`Self::decode`].
+    pub fn encode(&self) -> Vec<u8> {
+        let encoded = Encoded {
+            depth: self.config.depth,
+            rate: self.config.rate,
+            optimized_for_weights: self.config.optimized_for_weights,
+            leaves: self.tree.leaves().to_vec(),
+            roots: self.roots.iter().copied().collect(),
+            root_window: self.root_window as u64,
+            spents: self.spents.iter().copied().collect(),
+            validators: self.validators.iter().map(|(k, p)| (*k, *p)).collect(),
+            validator_admin: self.validator_admin,
+            validator_nonce: self.validator_nonce,
+            height: self.height,
+            size: self.size,
+        };
+
+        let mut bytes = Vec::with_capacity(encoded.compressed_size());
+        encoded
+            .serialize_compressed(&mut bytes)
+            .expect("serializing to a Vec can't fail");
+
+        bytes
+    }
+
+    /// Restore a state serialized with [`Self::encode`]. Nothing here says that the state is
+    /// valid, so check its [hash](Self::hash) against a trusted one before using it.
+    pub fn decode(bytes: &[u8]) -> Result<Self, SerializationError> {
+        let encoded = Encoded::deserialize_compressed(bytes)?;
+
+        let config = TreeConfig {
+            depth: encoded.depth,
+            rate: encoded.rate,
+            optimized_for_weights: encoded.optimized_for_weights,
+        };
+
+        if encoded.root_window == 0 || encoded.roots.is_empty() || !config.is_valid() {
+            return Err(SerializationError::InvalidData);
+        }
+
+        let mut state = Self::with_config(config, encoded.root_window as usize);
+        for leaf in encoded.leaves {
+            state
+                .tree
+                .append(leaf)
+                .map_err(|_| SerializationError::InvalidData)?;
+        }
+
+        state.roots = encoded.roots.into();
+        state.spend(&encoded.spents);
+        state.validators = encoded.validators.into_iter().collect();
+        state.validator_admin = encoded.validator_admin;
+        state.validator_nonce = encoded.validator_nonce;
+        state.height = encoded.height;
+        state.size = encoded.size;
+
+        Ok(state)
+    }
+
+    /// What changed since the last call to [`Self::take_changes`].
+    pub fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    /// Take what changed since the last call, usually the block that just ended.
+    pub fn take_changes(&mut self) -> Changes {
+        Changes {
+            validator_nonce: self.validator_nonce,
+            ..std::mem::take(&mut self.changes)
+        }
+    }
+
+    /// Apply the changes of a block taken with [`Self::take_changes`], and end the block. The
+    /// changes aren't recorded again: they are already stored.
+    pub fn replay(&mut self, changes: &Changes) -> Result<(), SerializationError> {
+        for commitment in &changes.commitments {
+            self.tree
+                .append(*commitment)
+                .map_err(|_| SerializationError::InvalidData)?;
+            self.size += 1;
+        }
+
+        self.spend(&changes.spents);
+        for (key, power) in &changes.validators {
+            self.set_validator(*key, *power);
+        }
+        self.validator_nonce = changes.validator_nonce;
+
+        self.end_block();
+        self.changes = Changes::default();
+
+        Ok(())
+    }
+
+    /// Add a coin commitment to the tree, and return its index.
+    pub fn append(&mut self, commitment: CoinCommitment) -> usize {
+        self.size += 1;
+        self.changes.commitments.push(commitment);
+
+        self.tree
+            .append(commitment)
+            .expect("the commitment tree is full")
+    }
+
+    /// Check a transaction against the current state, without verifying the proof of a payment.
+    pub fn check(&self, tx: &Tx) -> Result<(), TxError> {
+        match tx {
+            Tx::Payment(tx) => self.check_payment(tx),
+            Tx::ValidatorUpdate(update) => self.check_update(update),
+            Tx::Attestations(attestations) => self.check_attestations(attestations),
+        }
+    }
+
+    /// Check a payment against the current state, without verifying its proof.
+    pub fn check_payment(&self, tx: &Transaction) -> Result<(), TxError> {
+        self.check_root(&tx.root)?;
+
+        if tx.outputs.len() > self.tree.room() {
+            return Err(TxError::TreeFull);
+        }
+
+        for (i, sn) in tx.serial_numbers.iter().enumerate() {
+            // a transaction can't spend the same coin twice either
+            if self.spents.contains(sn) || tx.serial_numbers[..i].contains(sn) {
+                return Err(TxError::AlreadySpent);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Verify and apply a payment. See [`Self::apply`].
+    pub fn pay(&mut self, vks: &VerifyingKeys, tx: &Transaction) -> Result<usize, TxError> {
+        self.check_payment(tx)?;
+        verify_payment(vks, tx)?;
+
+        Ok(self.apply(tx))
+    }
+
+    /// Apply a payment which was already checked and verified. The serial numbers of the spent
+    /// coins are marked as spent, and the new coins are added to the tree. Returns the index of the
+    /// first new coin in the tree, the others follow it.
+    pub fn apply(&mut self, tx: &Transaction) -> usize {
+        let index = self.tree.len();
+        for output in &tx.outputs {
+            self.append(*output);
+        }
+
+        self.spend(&tx.serial_numbers);
+        self.changes.spents.extend(&tx.serial_numbers);
+
+        index
+    }
+
+    /// Check that a validator update is signed by the validator admin, and that it can be applied.
+    pub fn check_update(&self, update: &ValidatorUpdate) -> Result<(), TxError> {
+        let admin = self
+            .validator_admin
+            .and_then(|key| VerificationKey::try_from(key).ok())
+            .ok_or(TxError::Unauthorized)?;
+
+        admin
+            .verify(&Signature::from(update.signature), &update.message())
+            .map_err(|_| TxError::Unauthorized)?;
+
+        if update.nonce < self.validator_nonce {
+            return Err(TxError::Unauthorized);
+        }
+
+        // the nonce after the last one would overflow
+        if self
+            .total_power_with(&update.pub_key, update.power)
+            .is_none()
+            || update.nonce == u64::MAX
+            || VerificationKey::try_from(update.pub_key).is_err()
+        {
+            return Err(TxError::Malformed);
+        }
+
+        if update.power == 0
+            && self.validators.len() == 1
+            && self.validators.contains_key(&update.pub_key)
+        {
+            return Err(TxError::NoValidators);
+        }
+
+        Ok(())
+    }
+
+    /// Check the attestations that the proposer of a block collected from the votes for the
+    /// previous block, which are about the roots before that one. Every signature must be by a
+    /// current validator, in increasing order of public keys.
+    ///
+    /// The roots themselves aren't checked: they are only as good as the power which signed them.
+    pub fn check_attestations(&self, attestations: &Attestations) -> Result<(), TxError> {
+        let signers = &attestations.signatures;
+        if attestations.roots.height.checked_add(1) != Some(self.height)
+            || signers.is_empty()
+            || signers.windows(2).any(|pair| pair[0].0 >= pair[1].0)
+        {
+            return Err(TxError::Malformed);
+        }
+
+        for (key, signature) in signers {
+            if !self.validators.contains_key(key) || !attestations.roots.verify(key, signature) {
+                return Err(TxError::Unauthorized);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply a validator update which was already checked. Updates with a lower nonce can't be
+    /// applied after it.
+    pub fn apply_update(&mut self, update: &ValidatorUpdate) {
+        self.set_validator(update.pub_key, update.power);
+        self.validator_nonce = update.nonce + 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use ark_bls12_381::Bls12_381;
+    use ark_bls12_381::Fr as BlsFr;
+    use ark_groth16::prepare_verifying_key;
+    use ark_groth16::Groth16;
+    use ark_groth16::ProvingKey;
+    use ark_snark::SNARK;
+    use ark_std::UniformRand;
+    use ed25519_consensus::SigningKey;
+    use util::joinsplit::Output;
+    use util::payment::PaymentProof;
+    use util::poseidon::commitment;
+    use util::query;
+    use util::tx;
+    use util::tx::Circuit;
+    use util::tx::Transaction;
+    use util::tx::Tx;
+    use util::tx::TxError;
+    use util::tx::ValidatorUpdate;
+    use util::types::Coin;
+    use util::types::CoinID;
+    use util::types::Key;
+    use util::user::User;
+
+    use super::Changes;
+    use super::State;
+    use super::TreeConfig;
+    use super::VerifyingKeys;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// A small state that every node should hash to the same value.
+    fn sample_state() -> State {
+        let mut state = State {
+            spents: [5, 4].into_iter().map(CoinID::from).collect(),
+            validators: [([7; 32], 10), ([3; 32], 5)].into_iter().collect(),
+            validator_admin: Some([9; 32]),
+            validator_nonce: 2,
+            height: 7,
+            size: 3,
+            ..Default::default()
+        };
+
+        for i in 1..=3 {
+            state.tree.append(CoinID::from(i)).unwrap();
+        }
+
+        state
+    }
+
+    /// Prove `payment` and wrap it in a transaction with the given memo.
+    fn transaction(
+        pk: &ProvingKey<Bls12_381>,
+        mut payment: PaymentProof,
+        memo: &[u8],
+    ) -> Result<Transaction, Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+
+        payment.tx_digest = tx::digest(payment.fee, memo, &[]);
+
+        Ok(Transaction {
+            root: payment.root,
+            serial_numbers: vec![payment.serial_number],
+            outputs: vec![payment.output],
+            fee: payment.fee,
+            memo: memo.to_vec(),
+            notes: Vec::new(),
+            proof: Groth16::<Bls12_381>::prove(pk, payment, &mut rng)?,
+        })
+    }
+
+    #[test]
+    /// Test vectors for the app hash. If these change, every node on the network has to upgrade.
+    fn hash_vectors() {
+        assert_eq!(
+            hex(&State::default().hash()),
+            "d5123bdeab5091619f4a4dac923f858a3451099ea4084942a56032a7108ad3d7"
+        );
+        assert_eq!(
+            hex(&sample_state().hash()),
+            "045f914795f64207e190aaea714c756e13c83f0ff957bf42649194c45818042b"
+        );
+    }
+
+    #[test]
+    /// Check that every part of the state is covered by the hash
+    fn hash_binding() {
+        let hash = sample_state().hash();
+
+        let mut state = sample_state();
+        state.tree.append(CoinID::from(4)).unwrap();
+        assert_ne!(state.hash(), hash);
+
+        let mut state = sample_state();
+        state.spents.insert(CoinID::from(6));
+        assert_ne!(state.hash(), hash);
+
+        let mut state = sample_state();
+        state.height += 1;
+        assert_ne!(state.hash(), hash);
+
+        let mut state = sample_state();
+        state.size += 1;
+        assert_ne!(state.hash(), hash);
+
+        let mut state = sample_state();
+        state.roots.push_back(state.tree.root());
+        assert_ne!(state.hash(), hash);
+
+        let mut state = sample_state();
+        state.validators.insert([1; 32], 11);
+        assert_ne!(state.hash(), hash);
+
+        let mut state = sample_state();
+        state.validator_admin = None;
+        assert_ne!(state.hash(), hash);
+
+        let mut state = sample_state();
+        state.validator_nonce += 1;
+        assert_ne!(state.hash(), hash);
+    }
+
+    #[test]
+    /// Check that a decoded state is the same as the encoded one
+    fn encode_roundtrip() {
+        let state = sample_state();
+        let decoded = State::decode(&state.encode()).unwrap();
+
+        assert_eq!(decoded.hash(), state.hash());
+        assert_eq!(decoded.tree.leaves(), state.tree.leaves());
+        assert_eq!(decoded.config(), state.config());
+        assert!(State::decode(&state.encode()[1..]).is_err());
+    }
+
+    #[test]
+    /// Check that replaying the changes of a block gives the state after the block
+    fn replay() {
+        let mut state = sample_state();
+        state.take_changes();
+        let mut replayed = State::decode(&state.encode()).unwrap();
+        assert_eq!(replayed.hash(), state.hash());
+
+        state.apply(&Transaction {
+            root: state.root(),
+            serial_numbers: vec![CoinID::from(9)],
+            outputs: vec![CoinID::from(8)],
+            fee: 0,
+            memo: Vec::new(),
+            notes: Vec::new(),
+            proof: Default::default(),
+        });
+        state.set_validator([7; 32], 0);
+        state.set_validator([1; 32], 4);
+        state.validator_nonce += 1;
+        state.end_block();
+
+        let changes = state.take_changes();
+        assert_eq!(changes.commitments, [CoinID::from(8)]);
+        assert_eq!(changes.validator_nonce, state.validator_nonce);
+
+        replayed.replay(&changes).unwrap();
+        assert_eq!(replayed.hash(), state.hash());
+        assert_eq!(
+            replayed.nullifier_root(),
+            query::nullifier_root(&state.spents())
+        );
+        assert_eq!(
+            replayed.take_changes(),
+            Changes {
+                validator_nonce: state.validator_nonce,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    /// Check that only the last `root_window` roots are accepted
+    fn root_window() {
+        let mut state = State::new(2);
+
+        let genesis = state.root();
+        assert!(state.check_root(&genesis).is_ok());
+
+        state.tree.append(CoinID::from(1)).unwrap();
+        state.end_block();
+        let first = state.root();
+
+        // the root of an open block isn't accepted until the block ends
+        state.tree.append(CoinID::from(2)).unwrap();
+        let second = state.root();
+        assert!(matches!(
+            state.check_root(&second),
+            Err(TxError::UnknownRoot)
+        ));
+
+        state.end_block();
+        assert!(state.check_root(&first).is_ok());
+        assert!(state.check_root(&second).is_ok());
+        assert!(matches!(
+            state.check_root(&genesis),
+            Err(TxError::UnknownRoot)
+        ));
+        assert_eq!(state.height(), 2);
+    }
+
+    #[test]
+    /// Check that payments creating more coins than the tree has room for are refused, rather
+    /// than crashing the block
+    fn tree_full() {
+        let config = TreeConfig {
+            depth: 2,
+            ..Default::default()
+        };
+        let mut state = State::with_config(config, 1);
+        let root = state.root();
+
+        let payment = |sn: u64, outputs: usize| Transaction {
+            root,
+            serial_numbers: vec![CoinID::from(sn)],
+            outputs: vec![CoinID::from(sn); outputs],
+            fee: 0,
+            memo: Vec::new(),
+            notes: Vec::new(),
+            proof: Default::default(),
+        };
+
+        assert!(matches!(
+            state.check_payment(&payment(1, 5)),
+            Err(TxError::TreeFull)
+        ));
+
+        state.apply(&payment(1, 3));
+        assert!(state.check_payment(&payment(2, 1)).is_ok());
+        state.apply(&payment(2, 1));
+        assert!(matches!(
+            state.check_payment(&payment(3, 1)),
+            Err(TxError::TreeFull)
+        ));
+    }
+
+    #[test]
+    /// Check that a coin can't be spent twice, even with a fresh proof against a newer root
+    fn double_spend() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let mut state = State::default();
+        let params = state.tree.params().clone();
+
+        let user = User::new(&params, &mut rng)?;
+        let mut coin = Coin::rand(&mut rng);
+        coin.pk = user.pk;
+
+        let index = state
+            .tree
+            .append(commitment::new_commitment(&params, &coin)?)?;
+        state.end_block();
+
+        let recipient = Key::rand(&mut rng);
+        let output = Output::new(recipient, coin.value, &mut rng);
+        let payment = PaymentProof::new(
+            &state.tree,
+            index,
+            coin.clone(),
+            user.clone(),
+            output,
+            BlsFr::default(),
+        )?;
+        let (pk, vk) = Groth16::<Bls12_381>::circuit_specific_setup(payment.clone(), &mut rng)?;
+        let vks = VerifyingKeys::from([(Circuit::Payment, prepare_verifying_key(&vk))]);
+
+        let tx = transaction(&pk, payment, b"first")?;
+
+        // the proof doesn't carry over to a transaction with another memo
+        let mut other = tx.clone();
+        other.memo = b"second".to_vec();
+        assert!(matches!(
+            state.pay(&vks, &other),
+            Err(TxError::InvalidProof)
+        ));
+
+        assert!(state.pay(&vks, &tx).is_ok());
+        assert!(matches!(state.pay(&vks, &tx), Err(TxError::AlreadySpent)));
+        state.end_block();
+
+        let output = Output::new(recipient, coin.value, &mut rng);
+        let payment = PaymentProof::new(&state.tree, index, coin, user, output, BlsFr::default())?;
+        let tx = transaction(&pk, payment, b"second")?;
+
+        assert!(state.check_root(&tx.root).is_ok());
+        assert!(matches!(state.pay(&vks, &tx), Err(TxError::AlreadySpent)));
+
+        // the transaction goes through the wire as is
+        let mut bytes = Tx::Payment(tx.clone()).encode();
+        assert_eq!(
+            super::decode_tx(&bytes)?.serial_numbers(),
+            tx.serial_numbers
+        );
+
+        bytes.push(0);
+        assert_eq!(super::decode_tx(&bytes).err(), Some(TxError::Malformed));
+        assert_eq!(
+            super::decode_tx(&bytes[..10]).err(),
+            Some(TxError::Malformed)
+        );
+
+        bytes[0] = 2;
+        assert_eq!(
+            super::decode_tx(&bytes).err(),
+            Some(TxError::UnknownVersion)
+        );
+
+        let mut large = tx.clone();
+        large.memo = vec![0; tx::MAX_TX_SIZE];
+        assert_eq!(
+            super::decode_tx(&Tx::Payment(large).encode()).err(),
+            Some(TxError::TooLarge)
+        );
+
+        let mut unsupported = tx.clone();
+        unsupported.outputs.push(unsupported.outputs[0]);
+        assert_eq!(
+            super::verify(&vks, &Tx::Payment(unsupported.clone())).err(),
+            Some(TxError::UnsupportedCircuit)
+        );
+
+        // a batch tells the invalid payments apart from the valid ones
+        let mut invalid = tx.clone();
+        invalid.fee += 1;
+        assert_eq!(
+            super::verify_payments(&vks, &[&tx, &invalid, &unsupported, &tx]),
+            [
+                Ok(()),
+                Err(TxError::InvalidProof),
+                Err(TxError::UnsupportedCircuit),
+                Ok(())
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that only the validator admin changes the validator set, and only once per update
+    fn validator_updates() {
+        let mut rng = rand::thread_rng();
+        let admin = SigningKey::new(&mut rng);
+        let validator = SigningKey::new(&mut rng).verification_key().to_bytes();
+
+        let update = |key: &SigningKey, power, nonce| {
+            let mut update = ValidatorUpdate {
+                pub_key: validator,
+                power,
+                nonce,
+                signature: [0; 64],
+            };
+            update.signature = key.sign(&update.message()).to_bytes();
+
+            update
+        };
+
+        let mut state = State::default();
+        assert_eq!(
+            state.check_update(&update(&admin, 10, 0)),
+            Err(TxError::Unauthorized)
+        );
+
+        state.set_validator_admin(Some(admin.verification_key().to_bytes()));
+        let add = update(&admin, 10, 0);
+        assert_eq!(state.check(&Tx::ValidatorUpdate(add.clone())), Ok(()));
+
+        let forged = update(&SigningKey::new(&mut rng), 10, 0);
+        assert_eq!(state.check_update(&forged), Err(TxError::Unauthorized));
+
+        let mut tampered = add.clone();
+        tampered.power += 1;
+        assert_eq!(state.check_update(&tampered), Err(TxError::Unauthorized));
+
+        state.apply_update(&add);
+        assert_eq!(state.validators().get(&validator), Some(&10));
+
+        // an update can't be replayed, but nonces may be skipped
+        assert_eq!(state.check_update(&add), Err(TxError::Unauthorized));
+        assert_eq!(
+            state.check_update(&update(&admin, super::MAX_POWER + 1, 5)),
+            Err(TxError::Malformed)
+        );
+        assert_eq!(
+            state.check_update(&update(&admin, 0, 5)),
+            Err(TxError::NoValidators)
+        );
+
+        // cometbft caps the power of the whole set, so there is no room for a second validator
+        // with all the power there is
+        state.set_validator([1; 32], super::MAX_POWER);
+        assert_eq!(
+            state.check_update(&update(&admin, super::MAX_POWER, 5)),
+            Err(TxError::Malformed)
+        );
+
+        state.set_validator([1; 32], 3);
+        assert_eq!(
+            state.check_update(&update(&admin, super::MAX_POWER - 3, 5)),
+            Ok(())
+        );
+        let remove = update(&admin, 0, 5);
+        assert_eq!(state.check_update(&remove), Ok(()));
+        state.apply_update(&remove);
+        assert!(!state.validators().contains_key(&validator));
+        assert_eq!(
+            state.check_update(&update(&admin, 1, 5)),
+            Err(TxError::Unauthorized)
+        );
+    }
+}
@@ -0,0 +1,497 @@
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use ark_serialize::CanonicalDeserialize;
+use ark_serialize::CanonicalSerialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::state::Changes;
+use crate::state::State;
+
+/// Write a checkpoint of the whole state every this many blocks, so that the log doesn't grow
+/// forever and recovery doesn't replay too many blocks.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u32 = 1000;
+
+/// The whole state, as of some height.
+const CHECKPOINT: &str = "state";
+
+/// The changes of every block committed since the checkpoint, one record per block.
+const LOG: &str = "blocks";
+
+/// The `app_state` that the chain started from.
+const GENESIS: &str = "genesis.json";
+
+/// The first bytes of an export, see [`Store::export`].
+const EXPORT_MAGIC: &[u8] = b"protocash-export/v1";
+
+/// A record of the log, for a single block.
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+struct Record {
+    height: u32,
+    changes: Changes,
+
+    /// The app hash after the block, to check the replay against.
+    app_hash: [u8; 32],
+}
+
+/// The state and the genesis of a store, to start another one from.
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+struct Export {
+    genesis: Option<Vec<u8>>,
+    state: Vec<u8>,
+}
+
+/// Keeps the state on disk. A checkpoint holds the whole state as of some height, and every block
+/// committed since is appended to a log, which is synced before the block is acknowledged to
+/// cometbft.
+///
+/// A block is durable once its record is fully in the log. If the node crashes while writing it,
+/// the torn record fails its checksum and is dropped on recovery, and the state is back at the
+/// previous block, which cometbft replays from.
+pub struct Store {
+    dir: PathBuf,
+    log: File,
+
+    /// Write a checkpoint every this many blocks.
+    checkpoint_interval: u32,
+
+    /// The number of records in the log.
+    records: u32,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+
+    /// The checkpoint doesn't match its checksum, or doesn't decode.
+    Checkpoint,
+
+    /// The stored genesis can't be read.
+    Genesis,
+
+    /// The export doesn't match its checksum, or doesn't decode.
+    Export,
+
+    /// The record of the block at this height doesn't follow the previous block, or doesn't
+    /// replay to its app hash.
+    Replay(u32),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "{e}"),
+            StoreError::Checkpoint => write!(f, "the checkpoint of the state is corrupted"),
+            StoreError::Genesis => write!(f, "the stored genesis is invalid"),
+            StoreError::Export => write!(f, "the export is corrupted"),
+            StoreError::Replay(h) => write!(f, "the block at height {h} doesn't replay"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl Store {
+    /// Open the store in `dir`, creating it if needed, and recover the last committed state. There
+    /// is no state before the chain is initialized.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        checkpoint_interval: u32,
+    ) -> Result<(Self, Option<State>), StoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut state = match fs::read(dir.join(CHECKPOINT)) {
+            Ok(bytes) => Some(
+                open_checksum(&bytes)
+                    .and_then(|bytes| State::decode(bytes).ok())
+                    .ok_or(StoreError::Checkpoint)?,
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(dir.join(LOG))?;
+        let bytes = fs::read(dir.join(LOG))?;
+
+        // the records before the first torn one, and where they end
+        let mut records = Vec::new();
+        let mut end = 0;
+        while let Some((record, len)) = read_record(&bytes[end..]) {
+            records.push(record);
+            end += len;
+        }
+
+        let mut count = 0;
+        if let Some(state) = state.as_mut() {
+            for record in records {
+                // the checkpoint was written, but the log wasn't cleared yet
+                if record.height <= state.height() {
+                    continue;
+                }
+
+                if record.height != state.height() + 1 {
+                    return Err(StoreError::Replay(record.height));
+                }
+
+                state
+                    .replay(&record.changes)
+                    .map_err(|_| StoreError::Replay(record.height))?;
+                if state.hash() != record.app_hash {
+                    return Err(StoreError::Replay(record.height));
+                }
+
+                count += 1;
+            }
+        } else {
+            // without a checkpoint, there is nothing to replay the log on
+            end = 0;
+        }
+
+        if end < bytes.len() {
+            tracing::warn!("dropping {} bytes of torn records", bytes.len() - end);
+        }
+        log.set_len(end as u64)?;
+        log.sync_all()?;
+
+        let store = Self {
+            dir,
+            log,
+            checkpoint_interval: checkpoint_interval.max(1),
+            records: count,
+        };
+
+        Ok((store, state))
+    }
+
+    /// Keep the genesis that the chain starts from, and the state built from it.
+    pub fn init(&mut self, state: &State, genesis: &[u8]) -> Result<(), StoreError> {
+        write_atomic(&self.dir.join(GENESIS), genesis)?;
+
+        self.checkpoint(state)
+    }
+
+    /// The genesis that the chain started from, if it was initialized.
+    pub fn genesis(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        match fs::read(self.dir.join(GENESIS)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Durably commit a block, given the state after it and what it changed.
+    pub fn commit(&mut self, state: &State, changes: Changes) -> Result<(), StoreError> {
+        if self.records + 1 >= self.checkpoint_interval {
+            return self.checkpoint(state);
+        }
+
+        let record = Record {
+            height: state.height(),
+            changes,
+            app_hash: state.hash(),
+        };
+
+        let mut payload = Vec::with_capacity(record.compressed_size());
+        record
+            .serialize_compressed(&mut payload)
+            .expect("serializing to a Vec can't fail");
+
+        let mut bytes = Vec::with_capacity(4 + payload.len() + 32);
+        bytes.extend((payload.len() as u32).to_be_bytes());
+        bytes.extend(&payload);
+        bytes.extend(Sha256::digest(&payload));
+
+        self.log.write_all(&bytes)?;
+        self.log.sync_data()?;
+        self.records += 1;
+
+        Ok(())
+    }
+
+    /// Write `state`, and the genesis that the chain started from, to a single file at `path`,
+    /// which another store can [import](Self::import).
+    pub fn export(&self, state: &State, path: &Path) -> Result<(), StoreError> {
+        let export = Export {
+            genesis: self.genesis()?,
+            state: state.encode(),
+        };
+
+        let mut payload = Vec::with_capacity(export.compressed_size());
+        export
+            .serialize_compressed(&mut payload)
+            .expect("serializing to a Vec can't fail");
+
+        let mut bytes = EXPORT_MAGIC.to_vec();
+        bytes.extend(Sha256::digest(&payload));
+        bytes.extend(payload);
+
+        write_atomic(path, &bytes)?;
+
+        Ok(())
+    }
+
+    /// Start an empty store from the [export](Self::export) at `path`, rather than from InitChain.
+    /// Returns the exported state, which cometbft goes on from.
+    pub fn import(&mut self, path: &Path) -> Result<State, StoreError> {
+        let bytes = fs::read(path)?;
+        let export = bytes
+            .strip_prefix(EXPORT_MAGIC)
+            .and_then(open_checksum)
+            .and_then(|payload| Export::deserialize_compressed(payload).ok())
+            .ok_or(StoreError::Export)?;
+        let state = State::decode(&export.state).map_err(|_| StoreError::Export)?;
+
+        match export.genesis {
+            Some(genesis) => self.init(&state, &genesis)?,
+            None => self.checkpoint(&state)?,
+        }
+
+        Ok(state)
+    }
+
+    /// Replace the checkpoint with the whole `state`, and clear the log.
+    pub fn checkpoint(&mut self, state: &State) -> Result<(), StoreError> {
+        let bytes = state.encode();
+
+        let mut checkpoint = Sha256::digest(&bytes).to_vec();
+        checkpoint.extend(bytes);
+        write_atomic(&self.dir.join(CHECKPOINT), &checkpoint)?;
+
+        // a crash here leaves records that the checkpoint already covers, which are skipped
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.records = 0;
+
+        Ok(())
+    }
+}
+
+/// The bytes after a SHA-256 checksum, if they match it.
+fn open_checksum(bytes: &[u8]) -> Option<&[u8]> {
+    let (checksum, bytes) = bytes.split_at_checked(32)?;
+
+    (Sha256::digest(bytes).as_slice() == checksum).then_some(bytes)
+}
+
+/// Read the record at the start of `bytes`, and its length. A record is the length of its
+/// payload, the payload, and the SHA-256 checksum of the payload.
+fn read_record(bytes: &[u8]) -> Option<(Record, usize)> {
+    let (len, rest) = bytes.split_first_chunk::<4>()?;
+    let len = u32::from_be_bytes(*len) as usize;
+
+    let (payload, rest) = rest.split_at_checked(len)?;
+    let (checksum, _) = rest.split_first_chunk::<32>()?;
+    if Sha256::digest(payload).as_slice() != checksum {
+        return None;
+    }
+
+    let record = Record::deserialize_compressed(payload).ok()?;
+
+    Some((record, 4 + len + 32))
+}
+
+/// Replace the file at `path` with `bytes`, so that it is either all old or all new, even after a
+/// crash.
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp, path)?;
+
+    // the rename is only durable once the directory is synced. The parent of a bare file name is
+    // empty, rather than the current directory
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use rand::Rng;
+    use util::types::CoinID;
+
+    use super::Store;
+    use super::StoreError;
+    use crate::state::State;
+
+    /// A fresh directory for a store.
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "protocash-store-{:x}",
+            rand::thread_rng().gen::<u64>()
+        ))
+    }
+
+    /// End a block which adds a coin, and commit it.
+    fn block(store: &mut Store, state: &mut State, coin: u64) -> Result<(), StoreError> {
+        state.append(CoinID::from(coin));
+        state.end_block();
+
+        let changes = state.take_changes();
+        store.commit(state, changes)
+    }
+
+    #[test]
+    /// Check that the state is recovered from the checkpoint and the log, up to the last complete
+    /// block
+    fn recovery() -> Result<(), StoreError> {
+        let dir = temp_dir();
+
+        let (mut store, state) = Store::open(&dir, 3)?;
+        assert!(state.is_none());
+        assert_eq!(store.genesis()?, None);
+
+        let mut state = State::default();
+        state.set_validator([7; 32], 10);
+        state.init(4);
+        state.take_changes();
+        store.init(&state, b"{}")?;
+
+        // the third block goes to a new checkpoint, the others to the log
+        for coin in 1..=4 {
+            block(&mut store, &mut state, coin)?;
+        }
+        drop(store);
+
+        let (mut store, recovered) = Store::open(&dir, 100)?;
+        let mut recovered = recovered.expect("the chain was initialized");
+        assert_eq!(recovered.height(), 8);
+        assert_eq!(recovered.hash(), state.hash());
+        assert_eq!(store.genesis()?, Some(b"{}".to_vec()));
+
+        // a crash halfway through a record loses that block only
+        block(&mut store, &mut recovered, 5)?;
+        let hash = recovered.hash();
+        block(&mut store, &mut recovered, 6)?;
+        drop(store);
+
+        let log = dir.join(super::LOG);
+        let len = fs::metadata(&log)?.len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log)?
+            .set_len(len - 10)?;
+
+        let (mut store, recovered) = Store::open(&dir, 100)?;
+        let mut recovered = recovered.expect("the chain was initialized");
+        assert_eq!(recovered.height(), 9);
+        assert_eq!(recovered.hash(), hash);
+
+        // and the log goes on from there
+        block(&mut store, &mut recovered, 6)?;
+        drop(store);
+        let (_, again) = Store::open(&dir, 100)?;
+        assert_eq!(again.map(|state| state.hash()), Some(recovered.hash()));
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that a corrupted store is refused, rather than silently rolled back
+    fn corruption() -> Result<(), StoreError> {
+        let dir = temp_dir();
+
+        let (mut store, _) = Store::open(&dir, 100)?;
+        let mut state = State::default();
+        store.init(&state, b"{}")?;
+        block(&mut store, &mut state, 1)?;
+        block(&mut store, &mut state, 2)?;
+        drop(store);
+
+        // the first block missing from the log, both records have the same length
+        let log = dir.join(super::LOG);
+        let bytes = fs::read(&log)?;
+        fs::write(&log, &bytes[bytes.len() / 2..])?;
+        assert!(matches!(Store::open(&dir, 100), Err(StoreError::Replay(2))));
+
+        let checkpoint = dir.join(super::CHECKPOINT);
+        let mut bytes = fs::read(&checkpoint)?;
+        bytes[40] ^= 1;
+        fs::write(&checkpoint, bytes)?;
+        assert!(matches!(
+            Store::open(&dir, 100),
+            Err(StoreError::Checkpoint)
+        ));
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that a store started from an export goes on from the exported state
+    fn export() -> Result<(), StoreError> {
+        let (dir, other) = (temp_dir(), temp_dir());
+
+        let (mut store, _) = Store::open(&dir, 100)?;
+        let mut state = State::default();
+        store.init(&state, b"{}")?;
+        block(&mut store, &mut state, 1)?;
+
+        let path = dir.join("export");
+        store.export(&state, &path)?;
+
+        let (mut imported, none) = Store::open(&other, 100)?;
+        assert!(none.is_none());
+        let mut copy = imported.import(&path)?;
+        assert_eq!(copy.hash(), state.hash());
+        assert_eq!(imported.genesis()?, Some(b"{}".to_vec()));
+
+        block(&mut imported, &mut copy, 2)?;
+        drop(imported);
+        let (_, recovered) = Store::open(&other, 100)?;
+        assert_eq!(recovered.map(|state| state.hash()), Some(copy.hash()));
+
+        // a bare file name is in the current directory
+        let relative = PathBuf::from(format!(
+            "protocash-export-{:x}",
+            rand::thread_rng().gen::<u64>()
+        ));
+        store.export(&state, &relative)?;
+        assert_eq!(fs::read(&relative)?, fs::read(&path)?);
+        fs::remove_file(&relative)?;
+
+        let mut bytes = fs::read(&path)?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, bytes)?;
+        let corrupted = temp_dir();
+        let (mut store, _) = Store::open(&corrupted, 100)?;
+        assert!(matches!(store.import(&path), Err(StoreError::Export)));
+
+        fs::remove_dir_all(&dir)?;
+        fs::remove_dir_all(&other)?;
+        fs::remove_dir_all(&corrupted)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use ed25519_consensus::SigningKey;
+use serde::Deserialize;
+
+/// The type of the ed25519 keys in cometbft's key files.
+const ED25519: &str = "tendermint/PrivKeyEd25519";
+
+/// cometbft's `priv_validator_key.json`, of which we only need the private key.
+#[derive(Deserialize)]
+struct KeyFile {
+    priv_key: TypedKey,
+}
+
+#[derive(Deserialize)]
+struct TypedKey {
+    #[serde(rename = "type")]
+    kind: String,
+
+    /// The seed of the key followed by its public key, in base64.
+    value: String,
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(io::Error),
+
+    /// The file isn't a cometbft key file.
+    Json(serde_json::Error),
+
+    /// The key isn't an ed25519 key, or its public key doesn't match.
+    Invalid,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(e) => write!(f, "{e}"),
+            KeyError::Json(e) => write!(f, "{e}"),
+            KeyError::Invalid => write!(f, "the key isn't a valid ed25519 key"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Load the ed25519 key that the validator signs its votes with from cometbft's
+/// `priv_validator_key.json`, to sign its attestations with the same key.
+pub fn load(path: impl AsRef<Path>) -> Result<SigningKey, KeyError> {
+    let bytes = fs::read(path).map_err(KeyError::Io)?;
+    let file: KeyFile = serde_json::from_slice(&bytes).map_err(KeyError::Json)?;
+    if file.priv_key.kind != ED25519 {
+        return Err(KeyError::Invalid);
+    }
+
+    let bytes = BASE64_STANDARD
+        .decode(&file.priv_key.value)
+        .map_err(|_| KeyError::Invalid)?;
+    let (seed, pub_key) = bytes.split_first_chunk::<32>().ok_or(KeyError::Invalid)?;
+
+    let key = SigningKey::from(*seed);
+    if pub_key != key.verification_key().to_bytes() {
+        return Err(KeyError::Invalid);
+    }
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use base64::prelude::BASE64_STANDARD;
+    use base64::Engine;
+    use ed25519_consensus::SigningKey;
+    use rand::Rng;
+
+    use super::load;
+    use super::KeyError;
+
+    #[test]
+    /// Check that the key of a cometbft key file is loaded, and that it must match its public key
+    fn key_file() -> Result<(), KeyError> {
+        let path = std::env::temp_dir().join(format!(
+            "protocash-key-{:x}.json",
+            rand::thread_rng().gen::<u64>()
+        ));
+
+        let key = SigningKey::from([3; 32]);
+        let mut bytes = key.as_bytes().to_vec();
+        bytes.extend(key.verification_key().to_bytes());
+
+        let write = |bytes: &[u8]| {
+            let json = format!(
+                r#"{{"address":"","pub_key":{{"type":"tendermint/PubKeyEd25519","value":""}},"priv_key":{{"type":"tendermint/PrivKeyEd25519","value":"{}"}}}}"#,
+                BASE64_STANDARD.encode(bytes)
+            );
+            fs::write(&path, json).map_err(KeyError::Io)
+        };
+
+        write(&bytes)?;
+        assert_eq!(load(&path)?.as_bytes(), key.as_bytes());
+
+        bytes[40] ^= 1;
+        write(&bytes)?;
+        assert!(matches!(load(&path), Err(KeyError::Invalid)));
+
+        write(&bytes[..32])?;
+        assert!(matches!(load(&path), Err(KeyError::Invalid)));
+
+        fs::remove_file(&path).map_err(KeyError::Io)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Compare verifying the payment proofs of a block one by one with verifying them in a batch.
+//!
+//! ```
+//! cargo bench -p util --bench batch
+//! ```
+use ark_bls12_381::Bls12_381;
+use ark_bls12_381::Fr as BlsFr;
+use ark_groth16::prepare_verifying_key;
+use ark_groth16::Groth16;
+use ark_groth16::Proof;
+use ark_snark::SNARK;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use util::batch;
+use util::batch::Statement;
+use util::keys;
+use util::poseidon::get_default_poseidon_parameters;
+use util::tx::Circuit;
+
+/// The depth of the tree doesn't change the cost of verifying a proof, so keep the setup quick.
+const DEPTH: usize = 8;
+
+fn verification(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let params = get_default_poseidon_parameters(4, true).expect("no params");
+
+    let (pk, vk) = keys::setup(&params, Circuit::Payment, DEPTH, &mut rng).expect("setup failed");
+    let pvk = prepare_verifying_key(&vk);
+
+    let proofs: Vec<(Proof<Bls12_381>, Vec<BlsFr>)> = (0..256)
+        .map(|_| {
+            let payment = keys::sample_payment(&params, DEPTH, &mut rng).expect("no payment");
+            let inputs = payment.public_inputs();
+            let proof = Groth16::<Bls12_381>::prove(&pk, payment, &mut rng).expect("no proof");
+
+            (proof, inputs)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("verification");
+    for n in [1, 16, 64, 256] {
+        let statements: Vec<Statement> = proofs[..n]
+            .iter()
+            .map(|(proof, inputs)| Statement {
+                proof,
+                inputs: inputs.clone(),
+            })
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("single", n), &statements, |b, statements| {
+            b.iter(|| {
+                statements.iter().all(|s| {
+                    Groth16::<Bls12_381>::verify_proof(&pvk, s.proof, &s.inputs).unwrap_or(false)
+                })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("each", n), &statements, |b, statements| {
+            b.iter(|| batch::verify_each(&pvk, statements))
+        });
+        group.bench_with_input(BenchmarkId::new("batch", n), &statements, |b, statements| {
+            b.iter(|| batch::verify_batch(&pvk, statements))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, verification);
+criterion_main!(benches);
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use ark_serialize::CanonicalDeserialize;
+use ark_serialize::CanonicalSerialize;
+use ed25519_consensus::Signature;
+use ed25519_consensus::SigningKey;
+use ed25519_consensus::VerificationKey;
+
+use crate::merkletree::Root;
+use crate::query::field_bytes;
+
+/// Domain separator of the message signed by the validators.
+const DOMAIN: &[u8] = b"protocash/attestation/v1";
+
+/// The roots of the state after a block, which every validator signs in its vote for the next
+/// block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Roots {
+    /// The height of the block.
+    pub height: u32,
+
+    /// The root of the commitment tree.
+    pub root: Root,
+
+    /// The commitment to the set of spent serial numbers. See [`crate::query::nullifier_root`].
+    pub nullifier_root: [u8; 32],
+}
+
+impl Roots {
+    /// The message that the validators sign.
+    pub fn message(&self) -> Vec<u8> {
+        let mut message = DOMAIN.to_vec();
+        message.extend(self.height.to_be_bytes());
+        message.extend(field_bytes(&self.root));
+        message.extend(self.nullifier_root);
+
+        message
+    }
+
+    /// Whether `signature` is a signature of the roots by the validator with the ed25519 key
+    /// `pub_key`.
+    pub fn verify(&self, pub_key: &[u8; 32], signature: &[u8; 64]) -> bool {
+        VerificationKey::try_from(*pub_key)
+            .and_then(|key| key.verify(&Signature::from(*signature), &self.message()))
+            .is_ok()
+    }
+}
+
+/// The signature of a validator over the roots after a block, which it puts in its vote
+/// extension.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Attestation {
+    pub roots: Roots,
+
+    /// The ed25519 signature of [`Roots::message`] by the validator.
+    pub signature: [u8; 64],
+}
+
+impl Attestation {
+    pub fn sign(key: &SigningKey, roots: Roots) -> Self {
+        Self {
+            roots,
+            signature: key.sign(&roots.message()).to_bytes(),
+        }
+    }
+
+    /// Whether the attestation is signed by the validator with the ed25519 key `pub_key`.
+    pub fn verify(&self, pub_key: &[u8; 32]) -> bool {
+        self.roots.verify(pub_key, &self.signature)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.compressed_size());
+        self.serialize_compressed(&mut bytes)
+            .expect("serializing to a Vec can't fail");
+
+        bytes
+    }
+
+    /// Decode an attestation from its canonical encoding, and nothing else.
+    pub fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let attestation = Self::deserialize_compressed(&mut bytes).ok()?;
+
+        bytes.is_empty().then_some(attestation)
+    }
+}
+
+/// The attestations of the same roots by many validators, which the proposer of a later block
+/// collects from the votes and publishes in it. A wallet which trusts the validator set trusts the
+/// roots once they are attested by a quorum, without following the chain itself.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Attestations {
+    pub roots: Roots,
+
+    /// The ed25519 public key of every validator which signed, in increasing order, and its
+    /// signature.
+    pub signatures: Vec<([u8; 32], [u8; 64])>,
+}
+
+impl Attestations {
+    /// The total power of the validators in `validators`, mapping public keys to powers, which
+    /// signed the roots. A validator which signed twice only counts once.
+    pub fn power(&self, validators: &BTreeMap<[u8; 32], u64>) -> u64 {
+        let signers: BTreeSet<&[u8; 32]> = self
+            .signatures
+            .iter()
+            .filter(|(key, signature)| self.roots.verify(key, signature))
+            .map(|(key, _)| key)
+            .collect();
+
+        signers
+            .into_iter()
+            .filter_map(|key| validators.get(key))
+            .sum()
+    }
+
+    /// Whether validators with more than two thirds of the power of `validators` signed the
+    /// roots.
+    pub fn is_quorum(&self, validators: &BTreeMap<[u8; 32], u64>) -> bool {
+        let total: u128 = validators.values().map(|power| *power as u128).sum();
+
+        3 * self.power(validators) as u128 > 2 * total
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use ed25519_consensus::SigningKey;
+
+    use super::Attestation;
+    use super::Attestations;
+    use super::Roots;
+    use crate::merkletree::Root;
+
+    #[test]
+    /// Check that roots are trusted once validators with more than two thirds of the power signed
+    /// them, and only them
+    fn quorum() {
+        let keys: Vec<SigningKey> = (1..=4).map(|i| SigningKey::from([i; 32])).collect();
+        let validators: BTreeMap<[u8; 32], u64> = keys
+            .iter()
+            .map(|key| (key.verification_key().to_bytes(), 10))
+            .collect();
+
+        let roots = Roots {
+            height: 3,
+            root: Root::from(5),
+            nullifier_root: [6; 32],
+        };
+
+        let mut attestations = Attestations {
+            roots,
+            signatures: Vec::new(),
+        };
+        for key in &keys[..2] {
+            let attestation = Attestation::sign(key, roots);
+            assert!(attestation.verify(&key.verification_key().to_bytes()));
+            assert_eq!(
+                Attestation::decode(&attestation.encode()),
+                Some(attestation.clone())
+            );
+
+            let pub_key = key.verification_key().to_bytes();
+            attestations
+                .signatures
+                .push((pub_key, attestation.signature));
+        }
+        assert_eq!(attestations.power(&validators), 20);
+        assert!(!attestations.is_quorum(&validators));
+
+        // a signature of other roots doesn't count
+        let other = Roots { height: 4, ..roots };
+        let forged = Attestation::sign(&keys[2], other);
+        let pub_key = keys[2].verification_key().to_bytes();
+        attestations.signatures.push((pub_key, forged.signature));
+        assert!(!forged.verify(&keys[3].verification_key().to_bytes()));
+        assert_eq!(attestations.power(&validators), 20);
+
+        // and a validator which signs twice counts once
+        attestations.signatures.push(attestations.signatures[1]);
+        assert_eq!(attestations.power(&validators), 20);
+        attestations.signatures.pop();
+
+        attestations.signatures[2].1 = Attestation::sign(&keys[2], roots).signature;
+        assert!(attestations.is_quorum(&validators));
+
+        let mut bytes = Attestation::sign(&keys[0], roots).encode();
+        bytes.push(0);
+        assert_eq!(Attestation::decode(&bytes), None);
+    }
+}
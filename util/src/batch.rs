@@ -0,0 +1,204 @@
+use ark_bls12_381::Bls12_381;
+use ark_bls12_381::Fr as BlsFr;
+use ark_bls12_381::G1Affine;
+use ark_bls12_381::G1Projective;
+use ark_bls12_381::G2Affine;
+use ark_ec::pairing::MillerLoopOutput;
+use ark_ec::pairing::Pairing;
+use ark_ec::CurveGroup;
+use ark_ff::One;
+use ark_ff::Zero;
+use ark_groth16::Groth16;
+use ark_groth16::PreparedVerifyingKey;
+use ark_groth16::Proof;
+use rand::Rng;
+use rayon::prelude::*;
+
+/// A proof, and the public inputs it is verified against.
+pub struct Statement<'a> {
+    pub proof: &'a Proof<Bls12_381>,
+    pub inputs: Vec<BlsFr>,
+}
+
+/// What a share of a batch adds up to: the product of its Miller loops `e(r_i * A_i, B_i)`, and
+/// the sums of `r_i * L_i`, `r_i * C_i` and `r_i`, where `L_i` are the prepared inputs.
+struct Share {
+    miller_loop: MillerLoopOutput<Bls12_381>,
+    inputs: G1Projective,
+    c: G1Projective,
+    r: BlsFr,
+}
+
+impl Share {
+    fn identity() -> Self {
+        Self {
+            miller_loop: MillerLoopOutput(One::one()),
+            inputs: G1Projective::zero(),
+            c: G1Projective::zero(),
+            r: BlsFr::zero(),
+        }
+    }
+
+    fn combine(self, other: Self) -> Self {
+        Self {
+            miller_loop: MillerLoopOutput(self.miller_loop.0 * other.miller_loop.0),
+            inputs: self.inputs + other.inputs,
+            c: self.c + other.c,
+            r: self.r + other.r,
+        }
+    }
+}
+
+/// Verify every proof against `pvk` at once, spread across threads.
+///
+/// A proof verifies when `e(A, B) = e(alpha, beta) * e(L, gamma) * e(C, delta)`, where `L` is the
+/// combination of the public inputs with the verifying key. Each equation is raised to a random
+/// 128-bit `r_i` and they are all multiplied together, which takes a single final exponentiation
+/// and a single pairing with `alpha`, `gamma` and `delta` rather than one of each per proof. If
+/// any proof is invalid, the product only holds with probability `2^-128`.
+///
+/// A failed batch doesn't tell which proof is invalid: see [`find_invalid`].
+pub fn verify_batch(pvk: &PreparedVerifyingKey<Bls12_381>, statements: &[Statement]) -> bool {
+    if statements.is_empty() {
+        return true;
+    }
+
+    let chunk_size = statements.len().div_ceil(rayon::current_num_threads());
+    let share = statements
+        .par_chunks(chunk_size)
+        .map(|chunk| {
+            let mut rng = rand::thread_rng();
+
+            let mut a: Vec<G1Affine> = Vec::with_capacity(chunk.len());
+            let mut b: Vec<G2Affine> = Vec::with_capacity(chunk.len());
+            let mut share = Share::identity();
+
+            for statement in chunk {
+                let inputs = Groth16::<Bls12_381>::prepare_inputs(pvk, &statement.inputs).ok()?;
+                let r = BlsFr::from(rng.gen::<u128>());
+
+                a.push((statement.proof.a * r).into_affine());
+                b.push(statement.proof.b);
+                share.inputs += inputs * r;
+                share.c += statement.proof.c * r;
+                share.r += r;
+            }
+
+            share.miller_loop = Bls12_381::multi_miller_loop(a, b);
+
+            Some(share)
+        })
+        .try_reduce(Share::identity, |x, y| Some(x.combine(y)));
+
+    // the number of inputs doesn't match the key
+    let Some(share) = share else {
+        return false;
+    };
+
+    // pairing `-sum(r_i) * alpha` with `beta` is cheaper than raising `e(alpha, beta)` to it
+    let rest = Bls12_381::multi_miller_loop(
+        [
+            share.inputs.into_affine(),
+            share.c.into_affine(),
+            (pvk.vk.alpha_g1 * -share.r).into_affine(),
+        ],
+        [
+            pvk.gamma_g2_neg_pc.clone(),
+            pvk.delta_g2_neg_pc.clone(),
+            pvk.vk.beta_g2.into(),
+        ],
+    );
+    let miller_loop = MillerLoopOutput(share.miller_loop.0 * rest.0);
+
+    Bls12_381::final_exponentiation(miller_loop).is_some_and(|test| test.is_zero())
+}
+
+/// Verify every proof against `pvk` on its own, spread across threads.
+pub fn verify_each(pvk: &PreparedVerifyingKey<Bls12_381>, statements: &[Statement]) -> Vec<bool> {
+    statements
+        .par_iter()
+        .map(|statement| {
+            Groth16::<Bls12_381>::verify_proof(pvk, statement.proof, &statement.inputs)
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// The indices of the invalid proofs, in ascending order. The whole batch is verified first, and
+/// the proofs are only verified one by one if it fails. A single proof is cheaper to verify on its
+/// own.
+pub fn find_invalid(pvk: &PreparedVerifyingKey<Bls12_381>, statements: &[Statement]) -> Vec<usize> {
+    if statements.len() > 1 && verify_batch(pvk, statements) {
+        return Vec::new();
+    }
+
+    verify_each(pvk, statements)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, valid)| (!valid).then_some(i))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use ark_bls12_381::Bls12_381;
+    use ark_bls12_381::Fr as BlsFr;
+    use ark_groth16::prepare_verifying_key;
+    use ark_groth16::Groth16;
+    use ark_groth16::Proof;
+    use ark_snark::SNARK;
+
+    use super::find_invalid;
+    use super::verify_batch;
+    use super::Statement;
+    use crate::keys;
+    use crate::poseidon::get_default_poseidon_parameters;
+    use crate::tx::Circuit;
+
+    fn statements(proofs: &[(Proof<Bls12_381>, Vec<BlsFr>)]) -> Vec<Statement<'_>> {
+        proofs
+            .iter()
+            .map(|(proof, inputs)| Statement {
+                proof,
+                inputs: inputs.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    /// Check that a batch verifies if and only if every proof in it does
+    fn batch() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let params = get_default_poseidon_parameters(4, true).ok_or("no params")?;
+
+        let (pk, vk) = keys::setup(&params, Circuit::Payment, 4, &mut rng)?;
+        let pvk = prepare_verifying_key(&vk);
+
+        let mut proofs: Vec<(Proof<Bls12_381>, Vec<BlsFr>)> = Vec::new();
+        for _ in 0..5 {
+            let payment = keys::sample_payment(&params, 4, &mut rng)?;
+            let inputs = payment.public_inputs();
+
+            proofs.push((Groth16::<Bls12_381>::prove(&pk, payment, &mut rng)?, inputs));
+        }
+
+        assert!(verify_batch(&pvk, &statements(&proofs)));
+        assert!(verify_batch(&pvk, &[]));
+        assert!(find_invalid(&pvk, &statements(&proofs)).is_empty());
+
+        // a proof against the inputs of another
+        proofs[1].1 = proofs[3].1.clone();
+        proofs[4].1[3] += BlsFr::from(1);
+        assert!(!verify_batch(&pvk, &statements(&proofs)));
+        assert_eq!(find_invalid(&pvk, &statements(&proofs)), [1, 4]);
+
+        // inputs which don't match the key
+        proofs[1].1.pop();
+        assert!(!verify_batch(&pvk, &statements(&proofs[..2])));
+        assert!(verify_batch(&pvk, &statements(&proofs[2..4])));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1039 @@
+//! A multi-party setup ceremony for the Groth16 keys of the payment circuits.
+//!
+//! Whoever runs [`keys::setup`](crate::keys::setup) alone learns enough to forge proofs, and so
+//! coins. Here, no one ever holds the secrets of the keys. The ceremony follows
+//! <https://eprint.iacr.org/2017/1050>, in two phases:
+//!
+//! 1. The powers of tau. Starting from the generators, every participant in turn multiplies
+//!    `tau`, `alpha` and `beta` by secrets of their own, and proves that they know them.
+//! 2. Once the powers are [closed](Ceremony::close), the keys of the circuit are derived from
+//!    them, with `gamma` and `delta` set to one. Anyone can check the derivation. Every participant
+//!    in turn then multiplies `delta` by a secret of their own, and proves that they know it.
+//!
+//! The final keys are sound as long as a single participant of each phase threw their secrets
+//! away. Starting the ceremony takes no randomness at all.
+use std::fmt;
+use std::iter;
+
+use ark_bls12_381::Bls12_381;
+use ark_bls12_381::Fr as BlsFr;
+use ark_bls12_381::G1Affine;
+use ark_bls12_381::G1Projective;
+use ark_bls12_381::G2Affine;
+use ark_bls12_381::G2Projective;
+use ark_crypto_primitives::Error as ArkError;
+use ark_ec::pairing::Pairing;
+use ark_ec::AffineRepr;
+use ark_ec::CurveGroup;
+use ark_ec::VariableBaseMSM;
+use ark_ff::Field;
+use ark_ff::One;
+use ark_ff::Zero;
+use ark_groth16::ProvingKey;
+use ark_groth16::VerifyingKey;
+use ark_poly::EvaluationDomain;
+use ark_poly::GeneralEvaluationDomain;
+use ark_relations::r1cs::ConstraintMatrices;
+use ark_relations::r1cs::ConstraintSynthesizer;
+use ark_relations::r1cs::ConstraintSystem;
+use ark_relations::r1cs::OptimizationGoal;
+use ark_relations::r1cs::SynthesisMode;
+use ark_serialize::CanonicalDeserialize;
+use ark_serialize::CanonicalSerialize;
+use ark_std::UniformRand;
+use rand::CryptoRng;
+use rand::RngCore;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use rayon::prelude::*;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::keys;
+use crate::merkletree::Params;
+use crate::tx::Circuit;
+
+/// Domain separator of the challenge of a contribution.
+const CHALLENGE_DOMAIN: &[u8] = b"protocash/ceremony/v2";
+
+/// The secrets that a participant knows, which name the challenges of their proofs.
+const TAU: &[u8] = b"tau";
+const ALPHA: &[u8] = b"alpha";
+const BETA: &[u8] = b"beta";
+const DELTA: &[u8] = b"delta";
+
+/// The parameters of a ceremony in progress, along with every contribution made so far.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Ceremony {
+    /// The powers of tau after the last contribution to the first phase.
+    powers: Powers,
+
+    powers_contributions: Vec<PowersContribution>,
+
+    /// The powers in the Lagrange basis, once they are closed.
+    lagrange: Option<Lagrange>,
+
+    /// The keys after the last contribution to the second phase, once the powers are closed.
+    current: Option<ProvingKey<Bls12_381>>,
+
+    contributions: Vec<Contribution>,
+}
+
+/// The powers of secrets `tau`, `alpha` and `beta`, for a domain of size `n`, from which the keys
+/// of any circuit fitting in the domain can be derived.
+#[derive(Clone, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Powers {
+    /// `tau^i * G1`, for `i` up to `2n - 2`.
+    tau_g1: Vec<G1Affine>,
+
+    /// `tau^i * G2`, for `i` below `n`.
+    tau_g2: Vec<G2Affine>,
+
+    /// `alpha * tau^i * G1`, for `i` below `n`.
+    alpha_tau_g1: Vec<G1Affine>,
+
+    /// `beta * tau^i * G1`, for `i` below `n`.
+    beta_tau_g1: Vec<G1Affine>,
+
+    beta_g2: G2Affine,
+}
+
+/// The powers of tau in the Lagrange basis of the domain: `L_j(tau) * G1` and so on.
+#[derive(Clone, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+struct Lagrange {
+    g1: Vec<G1Affine>,
+    g2: Vec<G2Affine>,
+    alpha_g1: Vec<G1Affine>,
+    beta_g1: Vec<G1Affine>,
+}
+
+/// A proof that a participant multiplied a point by a secret `x` that they know.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Knowledge {
+    /// The point after the contribution.
+    pub point: G1Affine,
+
+    /// A random point.
+    pub s: G1Affine,
+
+    /// `x * s`
+    pub s_x: G1Affine,
+
+    /// `x * r`, where `r` is derived from the point before the contribution and `s`.
+    pub r_x: G2Affine,
+}
+
+/// A proof that a participant multiplied `tau`, `alpha` and `beta` by secrets that they know.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct PowersContribution {
+    /// Proves the new `tau * G1`.
+    pub tau: Knowledge,
+
+    /// Proves the new `alpha * G1`.
+    pub alpha: Knowledge,
+
+    /// Proves the new `beta * G1`.
+    pub beta: Knowledge,
+}
+
+/// A proof that a participant multiplied `delta` by a secret that they know.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Contribution {
+    /// Proves the new `delta * G1`.
+    pub delta: Knowledge,
+}
+
+#[derive(Debug)]
+pub enum CeremonyError {
+    /// The powers aren't the powers of a single `tau`, times `alpha` and `beta`, they don't fit the
+    /// circuit, or they don't match their Lagrange basis.
+    MalformedPowers,
+
+    /// The contribution to the powers at this index doesn't prove knowledge of its secrets.
+    BadPowersContribution(usize),
+
+    /// The powers don't match the last contribution to them.
+    PowersMismatch,
+
+    /// The powers aren't closed yet, so there are no keys.
+    Open,
+
+    /// Something else than `delta` and the queries divided by `delta` changed since the keys were
+    /// derived from the powers.
+    Modified,
+
+    /// `delta` in G1 and `delta` in G2 weren't multiplied by the same secrets, or don't match the
+    /// last contribution.
+    DeltaMismatch,
+
+    /// The contribution at this index doesn't prove knowledge of the secret it multiplied `delta`
+    /// by.
+    BadContribution(usize),
+
+    /// The queries weren't divided by the same secrets as `delta` was multiplied by.
+    QueryMismatch,
+
+    /// The circuit couldn't be synthesized.
+    Circuit(ArkError),
+}
+
+impl fmt::Display for CeremonyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CeremonyError::MalformedPowers => write!(f, "the powers of tau are malformed"),
+            CeremonyError::BadPowersContribution(i) => {
+                write!(f, "contribution {i} to the powers of tau is invalid")
+            }
+            CeremonyError::PowersMismatch => {
+                write!(f, "the powers of tau don't match the contributions to them")
+            }
+            CeremonyError::Open => write!(f, "the powers of tau aren't closed yet"),
+            CeremonyError::Modified => write!(f, "the parameters were modified outside of delta"),
+            CeremonyError::DeltaMismatch => write!(f, "delta doesn't match in G1 and G2"),
+            CeremonyError::BadContribution(i) => write!(f, "contribution {i} is invalid"),
+            CeremonyError::QueryMismatch => {
+                write!(f, "the queries don't match the contributions to delta")
+            }
+            CeremonyError::Circuit(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for CeremonyError {}
+
+impl Knowledge {
+    /// Multiply `point` by `x`, and prove it.
+    fn prove<R: RngCore>(label: &[u8], point: G1Affine, x: BlsFr, rng: &mut R) -> Self {
+        let s = G1Projective::rand(rng).into_affine();
+        let s_x = (s * x).into_affine();
+        let r = challenge(label, &point, &s, &s_x);
+
+        Self {
+            point: (point * x).into_affine(),
+            s,
+            s_x,
+            r_x: (r * x).into_affine(),
+        }
+    }
+
+    /// Whether [`Self::point`] is `previous` multiplied by a secret that the participant knew.
+    fn verify(&self, label: &[u8], previous: &G1Affine) -> bool {
+        let r = challenge(label, previous, &self.s, &self.s_x);
+
+        !self.s.is_zero()
+            && !self.s_x.is_zero()
+            && !self.point.is_zero()
+            && Bls12_381::pairing(self.s, self.r_x) == Bls12_381::pairing(self.s_x, r)
+            && Bls12_381::pairing(previous, self.r_x) == Bls12_381::pairing(self.point, r)
+    }
+}
+
+impl PowersContribution {
+    /// A hash of the contribution, which participants publish so that they can check that their
+    /// contribution made it into the final keys.
+    pub fn hash(&self) -> [u8; 32] {
+        hash(self)
+    }
+}
+
+impl Contribution {
+    /// A hash of the contribution, which participants publish so that they can check that their
+    /// contribution made it into the final keys.
+    pub fn hash(&self) -> [u8; 32] {
+        hash(self)
+    }
+}
+
+impl Powers {
+    /// The powers of `tau = alpha = beta = 1` for a domain of size `n`: nothing is secret yet.
+    fn new(n: usize) -> Self {
+        let g1 = G1Affine::generator();
+        let g2 = G2Affine::generator();
+
+        Self {
+            tau_g1: vec![g1; 2 * n - 1],
+            tau_g2: vec![g2; n],
+            alpha_tau_g1: vec![g1; n],
+            beta_tau_g1: vec![g1; n],
+            beta_g2: g2,
+        }
+    }
+
+    /// The size of the domain.
+    fn size(&self) -> usize {
+        self.tau_g2.len()
+    }
+
+    /// Multiply `tau`, `alpha` and `beta` by the given secrets.
+    fn multiply(&mut self, tau: BlsFr, alpha: BlsFr, beta: BlsFr) {
+        let powers: Vec<BlsFr> = iter::successors(Some(BlsFr::one()), |p| Some(*p * tau))
+            .take(self.tau_g1.len())
+            .collect();
+
+        self.tau_g1 = scale_each(&self.tau_g1, &powers, BlsFr::one());
+        self.alpha_tau_g1 = scale_each(&self.alpha_tau_g1, &powers, alpha);
+        self.beta_tau_g1 = scale_each(&self.beta_tau_g1, &powers, beta);
+
+        let tau_g2: Vec<G2Projective> = self
+            .tau_g2
+            .par_iter()
+            .zip(&powers)
+            .map(|(point, power)| *point * power)
+            .collect();
+        self.tau_g2 = G2Projective::normalize_batch(&tau_g2);
+        self.beta_g2 = (self.beta_g2 * beta).into_affine();
+    }
+
+    /// Check that the powers are the powers of a single `tau`, and that `beta` is the same in G1
+    /// and G2. Every sequence is checked at once, with a random combination of its points.
+    fn verify<R: RngCore>(&self, rng: &mut R) -> Result<(), CeremonyError> {
+        let n = self.size();
+        let g1 = G1Affine::generator();
+        let g2 = G2Affine::generator();
+
+        if n < 2
+            || self.tau_g1.len() != 2 * n - 1
+            || self.alpha_tau_g1.len() != n
+            || self.beta_tau_g1.len() != n
+            || self.tau_g1[0] != g1
+            || self.tau_g2[0] != g2
+            || self.tau_g1[1].is_zero()
+            || self.alpha_tau_g1[0].is_zero()
+            || self.beta_tau_g1[0].is_zero()
+        {
+            return Err(CeremonyError::MalformedPowers);
+        }
+
+        let tau_g2 = self.tau_g2[1];
+        let shifted = |points: &[G1Affine], rng: &mut R| {
+            let coeffs: Vec<BlsFr> = (1..points.len()).map(|_| BlsFr::rand(rng)).collect();
+            let before = G1Projective::msm_unchecked(&points[..points.len() - 1], &coeffs);
+            let after = G1Projective::msm_unchecked(&points[1..], &coeffs);
+
+            Bls12_381::pairing(before, tau_g2) == Bls12_381::pairing(after, g2)
+        };
+
+        let coeffs: Vec<BlsFr> = (1..n).map(|_| BlsFr::rand(rng)).collect();
+        let before = G2Projective::msm_unchecked(&self.tau_g2[..n - 1], &coeffs);
+        let after = G2Projective::msm_unchecked(&self.tau_g2[1..], &coeffs);
+        let tau_g2_shifted =
+            Bls12_381::pairing(self.tau_g1[1], before) == Bls12_381::pairing(g1, after);
+
+        let valid = shifted(&self.tau_g1, rng)
+            && shifted(&self.alpha_tau_g1, rng)
+            && shifted(&self.beta_tau_g1, rng)
+            && tau_g2_shifted
+            && Bls12_381::pairing(self.beta_tau_g1[0], g2) == Bls12_381::pairing(g1, self.beta_g2);
+        if !valid {
+            return Err(CeremonyError::MalformedPowers);
+        }
+
+        Ok(())
+    }
+
+    /// The powers in the Lagrange basis of `domain`: the Lagrange polynomials of the domain at
+    /// `tau` are the inverse FFT of the powers of `tau`. This is by far the slowest step of
+    /// deriving the keys, so [`Ceremony::close`] does it once for everyone.
+    fn lagrange(&self, domain: &GeneralEvaluationDomain<BlsFr>) -> Lagrange {
+        let n = self.size();
+        let ifft_g1 = |points: &[G1Affine]| {
+            let points: Vec<G1Projective> = points.iter().map(|p| p.into_group()).collect();
+            G1Projective::normalize_batch(&domain.ifft(&points))
+        };
+        let tau_g2: Vec<G2Projective> = self.tau_g2.iter().map(|p| p.into_group()).collect();
+
+        Lagrange {
+            g1: ifft_g1(&self.tau_g1[..n]),
+            g2: G2Projective::normalize_batch(&domain.ifft(&tau_g2)),
+            alpha_g1: ifft_g1(&self.alpha_tau_g1),
+            beta_g1: ifft_g1(&self.beta_tau_g1),
+        }
+    }
+}
+
+impl Lagrange {
+    /// Whether these are the `powers` in the Lagrange basis of `domain`. Since
+    /// `tau^k = sum_j L_j(tau) * w^(jk)`, a random combination of the powers is the combination of
+    /// the Lagrange points by the FFT of its coefficients.
+    fn verify<R: RngCore>(
+        &self,
+        powers: &Powers,
+        domain: &GeneralEvaluationDomain<BlsFr>,
+        rng: &mut R,
+    ) -> bool {
+        let n = powers.size();
+        let coeffs = |rng: &mut R| {
+            let coeffs: Vec<BlsFr> = (0..n).map(|_| BlsFr::rand(rng)).collect();
+            let evals = domain.fft(&coeffs);
+
+            (coeffs, evals)
+        };
+        let matches_g1 = |lagrange: &[G1Affine], powers: &[G1Affine], rng: &mut R| {
+            let (coeffs, evals) = coeffs(rng);
+
+            G1Projective::msm_unchecked(&powers[..n], &coeffs)
+                == G1Projective::msm_unchecked(lagrange, &evals)
+        };
+
+        if self.g1.len() != n
+            || self.g2.len() != n
+            || self.alpha_g1.len() != n
+            || self.beta_g1.len() != n
+            || !matches_g1(&self.g1, &powers.tau_g1, rng)
+            || !matches_g1(&self.alpha_g1, &powers.alpha_tau_g1, rng)
+            || !matches_g1(&self.beta_g1, &powers.beta_tau_g1, rng)
+        {
+            return false;
+        }
+
+        let (coeffs, evals) = coeffs(rng);
+        G2Projective::msm_unchecked(&powers.tau_g2, &coeffs)
+            == G2Projective::msm_unchecked(&self.g2, &evals)
+    }
+
+    /// Derive the keys of the circuit in `cs` from the powers, with `gamma` and `delta` set to
+    /// one. This follows the setup of arkworks, which evaluates the polynomials of the circuit at
+    /// `tau` directly, but in the exponent.
+    fn derive(&self, powers: &Powers, cs: &Synthesized) -> ProvingKey<Bls12_381> {
+        let n = powers.size();
+        let num_variables = cs.num_instance_variables + cs.matrices.num_witness_variables;
+        let mut a = columns(&cs.matrices.a, num_variables);
+        let b = columns(&cs.matrices.b, num_variables);
+        let c = columns(&cs.matrices.c, num_variables);
+
+        // arkworks binds every public input with an extra row of A
+        for (i, column) in a.iter_mut().take(cs.num_instance_variables).enumerate() {
+            column.push((cs.matrices.num_constraints + i, BlsFr::one()));
+        }
+
+        let a_query: Vec<G1Projective> = a.par_iter().map(|a| msm(&self.g1, a)).collect();
+        let b_g1_query: Vec<G1Projective> = b.par_iter().map(|b| msm(&self.g1, b)).collect();
+        let b_g2_query: Vec<G2Projective> = b
+            .par_iter()
+            .map(|b| {
+                let (bases, scalars): (Vec<G2Affine>, Vec<BlsFr>) =
+                    b.iter().map(|(row, coeff)| (self.g2[*row], *coeff)).unzip();
+                G2Projective::msm_unchecked(&bases, &scalars)
+            })
+            .collect();
+
+        // `beta * a_i(tau) + alpha * b_i(tau) + c_i(tau)`, over gamma for the public inputs and
+        // over delta for the rest
+        let abc: Vec<G1Projective> = (0..num_variables)
+            .into_par_iter()
+            .map(|i| msm(&self.beta_g1, &a[i]) + msm(&self.alpha_g1, &b[i]) + msm(&self.g1, &c[i]))
+            .collect();
+        let abc = G1Projective::normalize_batch(&abc);
+
+        // `tau^i * t(tau)`, where `t(x) = x^n - 1` vanishes on the domain
+        let h_query: Vec<G1Projective> = (0..n - 1)
+            .map(|i| powers.tau_g1[i + n] - powers.tau_g1[i])
+            .collect();
+
+        let vk = VerifyingKey {
+            alpha_g1: powers.alpha_tau_g1[0],
+            beta_g2: powers.beta_g2,
+            gamma_g2: G2Affine::generator(),
+            delta_g2: G2Affine::generator(),
+            gamma_abc_g1: abc[..cs.num_instance_variables].to_vec(),
+        };
+
+        ProvingKey {
+            vk,
+            beta_g1: powers.beta_tau_g1[0],
+            delta_g1: G1Affine::generator(),
+            a_query: G1Projective::normalize_batch(&a_query),
+            b_g1_query: G1Projective::normalize_batch(&b_g1_query),
+            b_g2_query: G2Projective::normalize_batch(&b_g2_query),
+            h_query: G1Projective::normalize_batch(&h_query),
+            l_query: abc[cs.num_instance_variables..].to_vec(),
+        }
+    }
+}
+
+/// The constraints of a circuit, as the setup of arkworks sees them.
+struct Synthesized {
+    matrices: ConstraintMatrices<BlsFr>,
+    num_instance_variables: usize,
+
+    /// The number of rows of the QAP, which the domain has to fit.
+    domain_size: usize,
+}
+
+impl Synthesized {
+    /// The domain of the QAP, which has to be the domain of the `powers`.
+    fn domain(&self, powers: &Powers) -> Result<GeneralEvaluationDomain<BlsFr>, CeremonyError> {
+        GeneralEvaluationDomain::new(self.domain_size)
+            .filter(|domain| domain.size() == powers.size())
+            .ok_or(CeremonyError::MalformedPowers)
+    }
+
+    fn new(params: &Params, circuit: Circuit, depth: usize) -> Result<Self, ArkError> {
+        // only the shape of the circuit matters, not the values in it
+        let mut rng = ChaCha20Rng::from_seed([0; 32]);
+
+        let cs = ConstraintSystem::new_ref();
+        cs.set_optimization_goal(OptimizationGoal::Constraints);
+        cs.set_mode(SynthesisMode::Setup);
+        match circuit {
+            Circuit::Payment => {
+                keys::sample_payment(params, depth, &mut rng)?.generate_constraints(cs.clone())?
+            }
+            Circuit::JoinSplit => {
+                keys::sample_joinsplit(params, depth, &mut rng)?.generate_constraints(cs.clone())?
+            }
+        }
+        cs.finalize();
+
+        let matrices = cs.to_matrices().ok_or("the circuit has no constraints")?;
+        let num_instance_variables = cs.num_instance_variables();
+
+        Ok(Self {
+            domain_size: matrices.num_constraints + num_instance_variables,
+            matrices,
+            num_instance_variables,
+        })
+    }
+}
+
+impl Ceremony {
+    /// Start a ceremony for `circuit` over a tree of the given `depth`, with powers of tau large
+    /// enough for it.
+    pub fn new(params: &Params, circuit: Circuit, depth: usize) -> Result<Self, ArkError> {
+        let cs = Synthesized::new(params, circuit, depth)?;
+        let domain = GeneralEvaluationDomain::<BlsFr>::new(cs.domain_size)
+            .ok_or("the circuit is too large")?;
+
+        Ok(Self {
+            powers: Powers::new(domain.size()),
+            powers_contributions: Vec::new(),
+            lagrange: None,
+            current: None,
+            contributions: Vec::new(),
+        })
+    }
+
+    pub fn powers_contributions(&self) -> &[PowersContribution] {
+        &self.powers_contributions
+    }
+
+    pub fn contributions(&self) -> &[Contribution] {
+        &self.contributions
+    }
+
+    /// Whether the powers are closed, and the contributions go to `delta`.
+    pub fn is_closed(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Contribute fresh secrets, which are dropped at the end of this call: `tau`, `alpha` and
+    /// `beta` until the powers are closed, and `delta` after that. Returns the hash of the
+    /// contribution.
+    pub fn contribute<R: RngCore + CryptoRng>(&mut self, rng: &mut R) -> [u8; 32] {
+        if self.is_closed() {
+            let d = secret(rng);
+
+            self.add_delta(d, rng).hash()
+        } else {
+            let (tau, alpha, beta) = (secret(rng), secret(rng), secret(rng));
+
+            self.add_powers(tau, alpha, beta, rng).hash()
+        }
+    }
+
+    fn add_powers<R: RngCore>(
+        &mut self,
+        tau: BlsFr,
+        alpha: BlsFr,
+        beta: BlsFr,
+        rng: &mut R,
+    ) -> &PowersContribution {
+        let powers = &mut self.powers;
+        let contribution = PowersContribution {
+            tau: Knowledge::prove(TAU, powers.tau_g1[1], tau, rng),
+            alpha: Knowledge::prove(ALPHA, powers.alpha_tau_g1[0], alpha, rng),
+            beta: Knowledge::prove(BETA, powers.beta_tau_g1[0], beta, rng),
+        };
+        powers.multiply(tau, alpha, beta);
+
+        self.powers_contributions.push(contribution);
+        self.powers_contributions.last().unwrap()
+    }
+
+    fn add_delta<R: RngCore>(&mut self, d: BlsFr, rng: &mut R) -> &Contribution {
+        let pk = self.current.as_mut().expect("the powers are closed");
+        let d_inv = d.inverse().expect("d is not zero");
+
+        let contribution = Contribution {
+            delta: Knowledge::prove(DELTA, pk.delta_g1, d, rng),
+        };
+        pk.delta_g1 = contribution.delta.point;
+        pk.vk.delta_g2 = (pk.vk.delta_g2 * d).into_affine();
+        pk.h_query = scale(&pk.h_query, d_inv);
+        pk.l_query = scale(&pk.l_query, d_inv);
+
+        self.contributions.push(contribution);
+        self.contributions.last().unwrap()
+    }
+
+    /// End the first phase, and derive the keys of `circuit` over a tree of the given `depth` from
+    /// the powers. Closing the powers takes no secret, so anyone can do it.
+    pub fn close(
+        &mut self,
+        params: &Params,
+        circuit: Circuit,
+        depth: usize,
+    ) -> Result<(), CeremonyError> {
+        if self.is_closed() {
+            return Err(CeremonyError::Modified);
+        }
+
+        let cs = Synthesized::new(params, circuit, depth).map_err(CeremonyError::Circuit)?;
+        let lagrange = self.powers.lagrange(&cs.domain(&self.powers)?);
+
+        self.current = Some(lagrange.derive(&self.powers, &cs));
+        self.lagrange = Some(lagrange);
+
+        Ok(())
+    }
+
+    /// Check that the powers are built from the secret of every contribution to them, that the
+    /// keys are derived from the powers for `circuit` over a tree of the given `depth`, that
+    /// `delta` was then multiplied by the secret of every contribution to it, and that every
+    /// participant knew their secrets.
+    pub fn verify<R: RngCore>(
+        &self,
+        params: &Params,
+        circuit: Circuit,
+        depth: usize,
+        rng: &mut R,
+    ) -> Result<(), CeremonyError> {
+        self.powers.verify(rng)?;
+
+        let g1 = G1Affine::generator();
+        let (mut tau, mut alpha, mut beta) = (g1, g1, g1);
+        for (i, c) in self.powers_contributions.iter().enumerate() {
+            if !c.tau.verify(TAU, &tau)
+                || !c.alpha.verify(ALPHA, &alpha)
+                || !c.beta.verify(BETA, &beta)
+            {
+                return Err(CeremonyError::BadPowersContribution(i));
+            }
+
+            (tau, alpha, beta) = (c.tau.point, c.alpha.point, c.beta.point);
+        }
+
+        let powers = &self.powers;
+        if tau != powers.tau_g1[1]
+            || alpha != powers.alpha_tau_g1[0]
+            || beta != powers.beta_tau_g1[0]
+        {
+            return Err(CeremonyError::PowersMismatch);
+        }
+
+        let (Some(lagrange), Some(current)) = (&self.lagrange, &self.current) else {
+            return if self.lagrange.is_none()
+                && self.current.is_none()
+                && self.contributions.is_empty()
+            {
+                Ok(())
+            } else {
+                Err(CeremonyError::Open)
+            };
+        };
+
+        let cs = Synthesized::new(params, circuit, depth).map_err(CeremonyError::Circuit)?;
+        if !lagrange.verify(powers, &cs.domain(powers)?, rng) {
+            return Err(CeremonyError::MalformedPowers);
+        }
+        let initial = &lagrange.derive(powers, &cs);
+
+        if initial.h_query.len() != current.h_query.len()
+            || initial.l_query.len() != current.l_query.len()
+        {
+            return Err(CeremonyError::Modified);
+        }
+
+        let mut expected = initial.clone();
+        expected.delta_g1 = current.delta_g1;
+        expected.vk.delta_g2 = current.vk.delta_g2;
+        expected.h_query.clone_from(&current.h_query);
+        expected.l_query.clone_from(&current.l_query);
+        if expected != *current {
+            return Err(CeremonyError::Modified);
+        }
+
+        // `delta` was multiplied by the same secrets in G1 and in G2.
+        if current.delta_g1.is_zero()
+            || Bls12_381::pairing(current.delta_g1, initial.vk.delta_g2)
+                != Bls12_381::pairing(initial.delta_g1, current.vk.delta_g2)
+        {
+            return Err(CeremonyError::DeltaMismatch);
+        }
+
+        let mut previous = initial.delta_g1;
+        for (i, c) in self.contributions.iter().enumerate() {
+            if !c.delta.verify(DELTA, &previous) {
+                return Err(CeremonyError::BadContribution(i));
+            }
+
+            previous = c.delta.point;
+        }
+
+        if previous != current.delta_g1 {
+            return Err(CeremonyError::DeltaMismatch);
+        }
+
+        // The queries are divided by the same secrets as `delta` is multiplied by, so
+        // `query * delta` doesn't change. We check this for a random combination of the queries.
+        let queries = |pk: &ProvingKey<Bls12_381>| {
+            let mut query = pk.h_query.clone();
+            query.extend(&pk.l_query);
+
+            query
+        };
+        let coeffs: Vec<BlsFr> = (0..initial.h_query.len() + initial.l_query.len())
+            .map(|_| BlsFr::rand(rng))
+            .collect();
+
+        let before = G1Projective::msm_unchecked(&queries(initial), &coeffs);
+        let after = G1Projective::msm_unchecked(&queries(current), &coeffs);
+        if Bls12_381::pairing(before, initial.vk.delta_g2)
+            != Bls12_381::pairing(after, current.vk.delta_g2)
+        {
+            return Err(CeremonyError::QueryMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// The keys produced by the ceremony so far, once the powers are closed. Call [`Self::verify`]
+    /// before using them.
+    pub fn keys(&self) -> Result<(ProvingKey<Bls12_381>, VerifyingKey<Bls12_381>), CeremonyError> {
+        let pk = self.current.clone().ok_or(CeremonyError::Open)?;
+        let vk = pk.vk.clone();
+
+        Ok((pk, vk))
+    }
+}
+
+/// A random secret, which can't be zero.
+fn secret<R: RngCore>(rng: &mut R) -> BlsFr {
+    loop {
+        let x = BlsFr::rand(rng);
+        if !x.is_zero() {
+            return x;
+        }
+    }
+}
+
+/// The point `r` that a contributor has to multiply their secret `label` by. It is derived from
+/// the point before the contribution and `s`, so that it can't be chosen in advance.
+fn challenge(label: &[u8], previous: &G1Affine, s: &G1Affine, s_x: &G1Affine) -> G2Affine {
+    let mut bytes = CHALLENGE_DOMAIN.to_vec();
+    bytes.extend(label);
+    for point in [previous, s, s_x] {
+        point
+            .serialize_compressed(&mut bytes)
+            .expect("serializing to a Vec can't fail");
+    }
+
+    let mut rng = ChaCha20Rng::from_seed(Sha256::digest(&bytes).into());
+
+    G2Affine::rand(&mut rng)
+}
+
+fn hash(contribution: &impl CanonicalSerialize) -> [u8; 32] {
+    let mut bytes = Vec::new();
+    contribution
+        .serialize_compressed(&mut bytes)
+        .expect("serializing to a Vec can't fail");
+
+    Sha256::digest(&bytes).into()
+}
+
+fn scale(points: &[G1Affine], by: BlsFr) -> Vec<G1Affine> {
+    let scaled: Vec<G1Projective> = points.par_iter().map(|p| *p * by).collect();
+
+    G1Projective::normalize_batch(&scaled)
+}
+
+/// Multiply each point by its own scalar, and all of them by `by`.
+fn scale_each(points: &[G1Affine], scalars: &[BlsFr], by: BlsFr) -> Vec<G1Affine> {
+    let scaled: Vec<G1Projective> = points
+        .par_iter()
+        .zip(scalars)
+        .map(|(point, scalar)| *point * (*scalar * by))
+        .collect();
+
+    G1Projective::normalize_batch(&scaled)
+}
+
+/// The rows of a constraint matrix where each variable appears, with its coefficient.
+fn columns(matrix: &[Vec<(BlsFr, usize)>], num_variables: usize) -> Vec<Vec<(usize, BlsFr)>> {
+    let mut columns = vec![Vec::new(); num_variables];
+    for (row, terms) in matrix.iter().enumerate() {
+        for (coeff, variable) in terms {
+            columns[*variable].push((row, *coeff));
+        }
+    }
+
+    columns
+}
+
+/// The combination of the `bases` at the rows of `column`.
+fn msm(bases: &[G1Affine], column: &[(usize, BlsFr)]) -> G1Projective {
+    let (bases, scalars): (Vec<G1Affine>, Vec<BlsFr>) = column
+        .iter()
+        .map(|(row, coeff)| (bases[*row], *coeff))
+        .unzip();
+
+    G1Projective::msm_unchecked(&bases, &scalars)
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use ark_bls12_381::Bls12_381;
+    use ark_bls12_381::Fr as BlsFr;
+    use ark_bls12_381::G1Affine;
+    use ark_bls12_381::G2Affine;
+    use ark_ec::AffineRepr;
+    use ark_ec::CurveGroup;
+    use ark_ff::Field;
+    use ark_groth16::r1cs_to_qap::LibsnarkReduction;
+    use ark_groth16::r1cs_to_qap::R1CSToQAP;
+    use ark_groth16::Groth16;
+    use ark_groth16::Proof;
+    use ark_groth16::VerifyingKey;
+    use ark_poly::GeneralEvaluationDomain;
+    use ark_relations::r1cs::ConstraintSynthesizer;
+    use ark_relations::r1cs::ConstraintSystem;
+    use ark_relations::r1cs::OptimizationGoal;
+    use ark_relations::r1cs::SynthesisMode;
+    use ark_snark::SNARK;
+    use ark_std::UniformRand;
+
+    use super::Ceremony;
+    use super::CeremonyError;
+    use crate::keys;
+    use crate::merkletree::Params;
+    use crate::poseidon::get_default_poseidon_parameters;
+    use crate::tx::Circuit;
+
+    const DEPTH: usize = 4;
+
+    fn params() -> Params {
+        get_default_poseidon_parameters(4, true).expect("failed to build params for poseidon")
+    }
+
+    /// A ceremony with the given number of contributions to each phase.
+    fn ceremony(powers: usize, contributions: usize) -> Result<Ceremony, Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let params = params();
+
+        let mut ceremony = Ceremony::new(&params, Circuit::Payment, DEPTH)?;
+        for _ in 0..powers {
+            ceremony.contribute(&mut rng);
+        }
+        ceremony.close(&params, Circuit::Payment, DEPTH)?;
+        for _ in 0..contributions {
+            ceremony.contribute(&mut rng);
+        }
+
+        Ok(ceremony)
+    }
+
+    /// A proof of `inputs` made with the secrets of the keys rather than a witness: `A = a`,
+    /// `B = b`, and `C` makes up for the rest of the verification equation.
+    fn forge(
+        vk: &VerifyingKey<Bls12_381>,
+        inputs: &[BlsFr],
+        (tau, alpha, beta, delta): (BlsFr, BlsFr, BlsFr, BlsFr),
+    ) -> Result<Proof<Bls12_381>, Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+
+        let cs = ConstraintSystem::new_ref();
+        cs.set_optimization_goal(OptimizationGoal::Constraints);
+        cs.set_mode(SynthesisMode::Setup);
+        keys::sample_payment(&params(), DEPTH, &mut rng)?.generate_constraints(cs.clone())?;
+        cs.finalize();
+
+        let (a, b, c, ..) = LibsnarkReduction::instance_map_with_evaluation::<
+            BlsFr,
+            GeneralEvaluationDomain<BlsFr>,
+        >(cs, &tau)?;
+
+        let mut sum = beta * a[0] + alpha * b[0] + c[0];
+        for (i, input) in inputs.iter().enumerate() {
+            sum += *input * (beta * a[i + 1] + alpha * b[i + 1] + c[i + 1]);
+        }
+
+        let (x, y) = (BlsFr::rand(&mut rng), BlsFr::rand(&mut rng));
+        let c = (x * y - alpha * beta - sum) * delta.inverse().ok_or("delta is zero")?;
+        assert_eq!(vk.gamma_abc_g1.len(), inputs.len() + 1);
+
+        Ok(Proof {
+            a: (G1Affine::generator() * x).into_affine(),
+            b: (G2Affine::generator() * y).into_affine(),
+            c: (G1Affine::generator() * c).into_affine(),
+        })
+    }
+
+    #[test]
+    /// Check that the keys still work after a few contributions to each phase
+    fn complete() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let params = params();
+
+        let ceremony = ceremony(2, 2)?;
+        ceremony.verify(&params, Circuit::Payment, DEPTH, &mut rng)?;
+
+        let (pk, vk) = ceremony.keys()?;
+        let payment = keys::sample_payment(&params, DEPTH, &mut rng)?;
+        let proof = Groth16::<Bls12_381>::prove(&pk, payment.clone(), &mut rng)?;
+
+        assert!(Groth16::<Bls12_381>::verify(
+            &vk,
+            &payment.public_inputs(),
+            &proof
+        )?);
+
+        let mut other = payment.public_inputs();
+        other[3] += BlsFr::from(1);
+        assert!(!Groth16::<Bls12_381>::verify(&vk, &other, &proof)?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that whoever starts the ceremony, and contributes first to both phases, can't forge
+    /// proofs once someone else contributed after them
+    fn initializer_cant_forge() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let params = params();
+        let payment = keys::sample_payment(&params, DEPTH, &mut rng)?;
+        let inputs = payment.public_inputs();
+
+        // the secrets of the initializer
+        let secrets = (
+            BlsFr::from(3),
+            BlsFr::from(5),
+            BlsFr::from(7),
+            BlsFr::from(11),
+        );
+        let (tau, alpha, beta, delta) = secrets;
+
+        let start = || -> Result<Ceremony, Box<dyn Error>> {
+            let mut rng = rand::thread_rng();
+
+            let mut ceremony = Ceremony::new(&params, Circuit::Payment, DEPTH)?;
+            ceremony.add_powers(tau, alpha, beta, &mut rng);
+            Ok(ceremony)
+        };
+
+        // alone, the initializer knows every secret, and forges a proof
+        let mut alone = start()?;
+        alone.close(&params, Circuit::Payment, DEPTH)?;
+        alone.add_delta(delta, &mut rng);
+        alone.verify(&params, Circuit::Payment, DEPTH, &mut rng)?;
+
+        let (_, vk) = alone.keys()?;
+        let forged = forge(&vk, &inputs, secrets)?;
+        assert!(Groth16::<Bls12_381>::verify(&vk, &inputs, &forged)?);
+
+        // but not once someone else contributed to both phases
+        let mut ceremony = start()?;
+        ceremony.contribute(&mut rng);
+        ceremony.close(&params, Circuit::Payment, DEPTH)?;
+        ceremony.add_delta(delta, &mut rng);
+        ceremony.contribute(&mut rng);
+        ceremony.verify(&params, Circuit::Payment, DEPTH, &mut rng)?;
+
+        let (_, vk) = ceremony.keys()?;
+        let forged = forge(&vk, &inputs, secrets)?;
+        assert!(!Groth16::<Bls12_381>::verify(&vk, &inputs, &forged)?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that a transcript which doesn't match the keys is rejected
+    fn sound() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let params = params();
+        let verify = |c: &Ceremony, rng: &mut rand::rngs::ThreadRng| {
+            c.verify(&params, Circuit::Payment, DEPTH, rng)
+        };
+
+        let ceremony = ceremony(2, 2)?;
+
+        // a contribution to the powers which doesn't know its secret
+        let mut c = ceremony.clone();
+        let knowledge = &mut c.powers_contributions[1].alpha;
+        knowledge.s_x = (knowledge.s_x * BlsFr::from(2)).into_affine();
+        assert!(matches!(
+            verify(&c, &mut rng),
+            Err(CeremonyError::BadPowersContribution(1))
+        ));
+
+        // powers which aren't powers of the same tau
+        let mut c = ceremony.clone();
+        c.powers.tau_g1[3] = c.powers.tau_g1[2];
+        assert!(matches!(
+            verify(&c, &mut rng),
+            Err(CeremonyError::MalformedPowers)
+        ));
+
+        // a contribution to the powers removed from the transcript
+        let mut c = ceremony.clone();
+        c.powers_contributions.remove(0);
+        assert!(matches!(
+            verify(&c, &mut rng),
+            Err(CeremonyError::BadPowersContribution(0))
+        ));
+
+        // a Lagrange basis which doesn't match the powers
+        let mut c = ceremony.clone();
+        let lagrange = c.lagrange.as_mut().unwrap();
+        lagrange.alpha_g1.swap(0, 1);
+        assert!(matches!(
+            verify(&c, &mut rng),
+            Err(CeremonyError::MalformedPowers)
+        ));
+
+        // a contribution to delta which doesn't know its secret
+        let mut c = ceremony.clone();
+        let knowledge = &mut c.contributions[1].delta;
+        knowledge.s_x = (knowledge.s_x * BlsFr::from(2)).into_affine();
+        assert!(matches!(
+            verify(&c, &mut rng),
+            Err(CeremonyError::BadContribution(1))
+        ));
+
+        // a contribution to delta removed from the transcript
+        let mut c = ceremony.clone();
+        c.contributions.remove(0);
+        assert!(matches!(
+            verify(&c, &mut rng),
+            Err(CeremonyError::BadContribution(0))
+        ));
+
+        // a query which isn't divided by delta
+        let mut c = ceremony.clone();
+        let pk = c.current.as_mut().unwrap();
+        pk.h_query[0] = pk.h_query[1];
+        assert!(matches!(
+            verify(&c, &mut rng),
+            Err(CeremonyError::QueryMismatch)
+        ));
+
+        // keys which aren't derived from the powers
+        let mut c = ceremony;
+        c.current.as_mut().unwrap().vk.alpha_g1 = G1Affine::generator();
+        assert!(matches!(verify(&c, &mut rng), Err(CeremonyError::Modified)));
+
+        Ok(())
+    }
+}
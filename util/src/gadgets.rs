@@ -0,0 +1,17 @@
+//! Gadgets shared by the payment circuits.
+use ark_bls12_381::Fr as BlsFr;
+use ark_r1cs_std::alloc::AllocVar;
+use ark_r1cs_std::boolean::Boolean;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::uint64::UInt64;
+use ark_relations::r1cs::Namespace;
+use ark_relations::r1cs::Result;
+
+use crate::types::Value;
+
+/// Allocate a value as a 64 bit integer, which range checks it, and return it as a field element.
+pub fn value_witness(cs: impl Into<Namespace<BlsFr>>, value: Value) -> Result<FpVar<BlsFr>> {
+    let bits = UInt64::new_witness(cs, || Ok(value))?.to_bits_le();
+
+    Boolean::le_bits_to_fp_var(&bits)
+}
@@ -0,0 +1,514 @@
+use ark_bls12_381::Fr as BlsFr;
+use ark_crypto_primitives::crh::CRHScheme;
+use ark_crypto_primitives::crh::CRHSchemeGadget;
+use ark_crypto_primitives::Error as ArkError;
+use ark_r1cs_std::alloc::AllocVar;
+use ark_r1cs_std::boolean::Boolean;
+use ark_r1cs_std::eq::EqGadget;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::fields::FieldVar;
+use ark_relations::r1cs::ConstraintSynthesizer;
+use ark_relations::r1cs::ConstraintSystemRef;
+use ark_relations::r1cs::Result;
+use ark_std::UniformRand;
+use rand::Rng;
+
+use crate::gadgets;
+use crate::merkletree::IncrementalMerkleTree;
+use crate::merkletree::Params;
+use crate::merkletree::ParamsVar;
+use crate::merkletree::Root;
+use crate::merkletree::RootVar;
+use crate::merkletree::TreePath;
+use crate::merkletree::TreePathVar;
+use crate::poseidon::commitment;
+use crate::poseidon::BlsPoseidon;
+use crate::poseidon::BlsPoseidonGadget;
+use crate::poseidon::CoinCommitment;
+use crate::poseidon::CoinCommitmentVar;
+use crate::types::Coin;
+use crate::types::CoinID;
+use crate::types::Key;
+use crate::types::Rand;
+use crate::types::Value;
+use crate::user::User;
+
+/// The number of coins spent by a [`JoinSplitProof`].
+pub const NUM_INPUTS: usize = 2;
+
+/// The number of coins created by a [`JoinSplitProof`].
+pub const NUM_OUTPUTS: usize = 2;
+
+/// A coin spent by a [`JoinSplitProof`].
+#[derive(Clone)]
+pub struct Input {
+    /// The path down the `MerkleTree` which leads to the commitment of `coin`.
+    pub path: TreePath,
+
+    /// The `Coin` being spent.
+    pub coin: Coin,
+
+    /// The [`User`] who the coin belongs to.
+    pub user: User,
+}
+
+impl Input {
+    /// Spend the coin at `index` in `tree`, which belongs to `user`.
+    pub fn new(
+        tree: &IncrementalMerkleTree,
+        index: usize,
+        coin: Coin,
+        user: User,
+    ) -> std::result::Result<Self, ArkError> {
+        let path = tree.generate_proof(index)?;
+
+        Ok(Self { path, coin, user })
+    }
+
+    /// A coin worth nothing, which doesn't need to be in the tree. This is used to spend a single
+    /// coin.
+    pub fn dummy<R: Rng>(
+        tree: &IncrementalMerkleTree,
+        rng: &mut R,
+    ) -> std::result::Result<Self, ArkError> {
+        let user = User::new(tree.params(), rng)?;
+
+        let mut coin = Coin::rand(rng);
+        coin.pk = user.pk;
+        coin.value = 0;
+
+        let path = TreePath {
+            leaf_sibling_hash: BlsFr::default(),
+            auth_path: vec![BlsFr::default(); tree.depth() - 1],
+            leaf_index: 0,
+        };
+
+        Ok(Self { path, coin, user })
+    }
+}
+
+/// A coin created by a [`JoinSplitProof`].
+#[derive(Clone)]
+pub struct Output {
+    /// The public key of the recipient.
+    pub recipient: Key,
+
+    /// How much the new coin is worth.
+    pub value: Value,
+
+    /// The noise of the output commitment.
+    pub com_rnd: Rand,
+}
+
+impl Output {
+    pub fn new<R: Rng>(recipient: Key, value: Value, rng: &mut R) -> Self {
+        Self {
+            recipient,
+            value,
+            com_rnd: BlsFr::rand(rng),
+        }
+    }
+}
+
+/// Proves a payment which spends [`NUM_INPUTS`] coins and creates [`NUM_OUTPUTS`] coins worth as
+/// much in total, less a fee. This is what allows paying any amount, and getting the change back.
+///
+/// The `i`th output coin gets `pre_serial_no' = H(serial_no)` of the `i`th input coin, which is
+/// unique since serial numbers can only be revealed once.
+#[derive(Clone)]
+pub struct JoinSplitProof {
+    params: Params,
+
+    // Public Inputs
+    /// The root of the Merkle Tree
+    pub root: Root,
+
+    /// The serial numbers of the input coins.
+    pub serial_numbers: [CoinID; NUM_INPUTS],
+
+    /// The commitments of the output coins.
+    pub outputs: [CoinCommitment; NUM_OUTPUTS],
+
+    /// The difference between the value of the input coins and the value of the output coins.
+    pub fee: Value,
+
+    /// The [digest](crate::tx::digest) of the transaction this proof is made for, so that it can't
+    /// be reused in another one.
+    pub tx_digest: BlsFr,
+
+    // Private Witnesses
+    /// The coins being spent.
+    pub input_coins: [Input; NUM_INPUTS],
+
+    /// The coins being created.
+    pub output_coins: [Output; NUM_OUTPUTS],
+}
+
+impl JoinSplitProof {
+    /// Build the statement that the `input_coins` are paid to the `output_coins` in the
+    /// transaction with digest `tx_digest`. Whatever is left of the inputs is the fee.
+    pub fn new(
+        tree: &IncrementalMerkleTree,
+        input_coins: [Input; NUM_INPUTS],
+        output_coins: [Output; NUM_OUTPUTS],
+        tx_digest: BlsFr,
+    ) -> std::result::Result<Self, ArkError> {
+        let params = tree.params().clone();
+
+        let input_sum: u128 = input_coins.iter().map(|i| i.coin.value as u128).sum();
+        let output_sum: u128 = output_coins.iter().map(|o| o.value as u128).sum();
+        let fee = input_sum
+            .checked_sub(output_sum)
+            .and_then(|fee| Value::try_from(fee).ok())
+            .ok_or(
+                "the outputs are worth more than the inputs, or the fee doesn't fit in 64 bits",
+            )?;
+
+        let mut serial_numbers = [CoinID::default(); NUM_INPUTS];
+        for (sn, input) in serial_numbers.iter_mut().zip(&input_coins) {
+            *sn = BlsPoseidon::evaluate(&params, [input.user.sk, input.coin.pre_serial_number])?;
+        }
+
+        let mut proof = Self {
+            params,
+            root: tree.root(),
+            serial_numbers,
+            outputs: [CoinCommitment::default(); NUM_OUTPUTS],
+            fee,
+            tx_digest,
+            input_coins,
+            output_coins,
+        };
+
+        for (i, coin) in proof.new_coins()?.iter().enumerate() {
+            proof.outputs[i] = commitment::new_commitment(&proof.params, coin)?;
+        }
+
+        Ok(proof)
+    }
+
+    /// The coins created by this payment. The payer has to send them to their recipients
+    /// privately, so that they can find them in the tree and spend them.
+    pub fn new_coins(&self) -> std::result::Result<Vec<Coin>, ArkError> {
+        self.output_coins
+            .iter()
+            .zip(self.serial_numbers)
+            .map(|(output, sn)| {
+                Ok(Coin {
+                    pk: output.recipient,
+                    pre_serial_number: commitment::output_pre_serial_number(&self.params, sn)?,
+                    com_rnd: output.com_rnd,
+                    value: output.value,
+                })
+            })
+            .collect()
+    }
+
+    /// The public inputs of the proof, in the order they are allocated.
+    pub fn public_inputs(&self) -> Vec<BlsFr> {
+        let mut inputs = vec![self.root];
+        inputs.extend(self.serial_numbers);
+        inputs.extend(self.outputs);
+        inputs.push(BlsFr::from(self.fee));
+        inputs.push(self.tx_digest);
+
+        inputs
+    }
+}
+
+impl ConstraintSynthesizer<BlsFr> for JoinSplitProof {
+    fn generate_constraints(self, cs: ConstraintSystemRef<BlsFr>) -> Result<()> {
+        // public inputs
+        let root = RootVar::new_input(ark_relations::ns!(cs, "merkle_root"), || Ok(self.root))?;
+        let serial_numbers = self
+            .serial_numbers
+            .iter()
+            .map(|sn| FpVar::new_input(ark_relations::ns!(cs, "serial_number"), || Ok(sn)))
+            .collect::<Result<Vec<_>>>()?;
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|c| {
+                CoinCommitmentVar::new_input(ark_relations::ns!(cs, "output_commitment"), || Ok(c))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let fee = FpVar::new_input(ark_relations::ns!(cs, "fee"), || Ok(BlsFr::from(self.fee)))?;
+        // no constraint uses the digest: like every public input, Groth16 binds the proof to it
+        // through the verifying key, so the proof is only valid for the transaction it was made
+        // for
+        let _tx_digest =
+            FpVar::new_input(ark_relations::ns!(cs, "tx_digest"), || Ok(self.tx_digest))?;
+
+        let params =
+            ParamsVar::new_constant(ark_relations::ns!(cs, "poseidon_params"), self.params)?;
+
+        let mut input_sum = FpVar::<BlsFr>::zero();
+        for (input, serial_number) in self.input_coins.into_iter().zip(&serial_numbers) {
+            let path =
+                TreePathVar::new_witness(ark_relations::ns!(cs, "merkle_tree_path"), || {
+                    Ok(input.path)
+                })?;
+            let pk = FpVar::new_witness(ark_relations::ns!(cs, "pub_key"), || Ok(input.coin.pk))?;
+            let pre_serial_number =
+                FpVar::new_witness(ark_relations::ns!(cs, "pre_serial_number"), || {
+                    Ok(input.coin.pre_serial_number)
+                })?;
+            let com_rnd =
+                FpVar::new_witness(ark_relations::ns!(cs, "com_rnd"), || Ok(input.coin.com_rnd))?;
+            let value = gadgets::value_witness(ark_relations::ns!(cs, "value"), input.coin.value)?;
+
+            let sk = FpVar::new_witness(ark_relations::ns!(cs, "sec_key"), || Ok(input.user.sk))?;
+            let noise =
+                FpVar::new_witness(ark_relations::ns!(cs, "key_noise"), || Ok(input.user.noise))?;
+
+            // 1. The commitment of the coin is in the tree, unless the coin is worth nothing. This
+            //    lets a payment spend a single coin by adding a dummy input.
+            let leaf = BlsPoseidonGadget::evaluate(
+                &params,
+                &[
+                    pk.clone(),
+                    pre_serial_number.clone(),
+                    com_rnd,
+                    value.clone(),
+                ],
+            )?;
+            let is_member = path.verify_membership(&params, &params, &root, &[leaf])?;
+            let is_real = value.is_neq(&FpVar::zero())?;
+            is_member.conditional_enforce_equal(&Boolean::TRUE, &is_real)?;
+
+            // 2. `serial_number = prf(sk, pre_serial_number)`
+            let expected_serial_number =
+                BlsPoseidonGadget::evaluate(&params, &[sk.clone(), pre_serial_number])?;
+            expected_serial_number.enforce_equal(serial_number)?;
+
+            // 3. `pk = H(sk)`
+            let expected_pk = BlsPoseidonGadget::evaluate(&params, &[sk, noise])?;
+            expected_pk.enforce_equal(&pk)?;
+
+            input_sum += value;
+        }
+
+        let mut output_sum = FpVar::<BlsFr>::zero();
+        for ((output, commitment), serial_number) in self
+            .output_coins
+            .into_iter()
+            .zip(&outputs)
+            .zip(&serial_numbers)
+        {
+            let recipient =
+                FpVar::new_witness(ark_relations::ns!(cs, "recipient_pub_key"), || {
+                    Ok(output.recipient)
+                })?;
+            let com_rnd = FpVar::new_witness(ark_relations::ns!(cs, "output_com_rnd"), || {
+                Ok(output.com_rnd)
+            })?;
+            let value =
+                gadgets::value_witness(ark_relations::ns!(cs, "output_value"), output.value)?;
+
+            // 4. The output commitment is `c' = H(pk_B, pre_serial_no', com_rnd', value')` where
+            //    `pre_serial_no' = H(serial_no)`.
+            let pre_serial_number =
+                BlsPoseidonGadget::evaluate(&params, std::slice::from_ref(serial_number))?;
+            let expected_commitment = BlsPoseidonGadget::evaluate(
+                &params,
+                &[recipient, pre_serial_number, com_rnd, value.clone()],
+            )?;
+            expected_commitment.enforce_equal(commitment)?;
+
+            output_sum += value;
+        }
+
+        // 5. No value is created or destroyed, except for the fee. Every value is less than 2^64,
+        //    and nodes only accept 64 bit fees, so the sums can't wrap around the field.
+        input_sum.enforce_equal(&(output_sum + fee))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use ark_bls12_381::Bls12_381;
+    use ark_groth16::r1cs_to_qap::LibsnarkReduction;
+    use ark_groth16::Groth16;
+    use ark_relations::r1cs::ConstraintSynthesizer;
+    use ark_relations::r1cs::ConstraintSystem;
+    use ark_snark::SNARK;
+    use ark_std::UniformRand;
+
+    use super::Input;
+    use super::JoinSplitProof;
+    use super::Output;
+    use crate::merkletree::IncrementalMerkleTree;
+    use crate::poseidon::commitment;
+    use crate::poseidon::get_default_poseidon_parameters;
+    use crate::tx;
+    use crate::types::Coin;
+    use crate::types::Key;
+    use crate::types::Value;
+    use crate::user::User;
+
+    /// Build a tree of `2^height` random coins, where the coins at `indices` belong to us and are
+    /// worth `values`.
+    fn generate_tree(
+        height: usize,
+        indices: [usize; 2],
+        values: [Value; 2],
+    ) -> Result<(IncrementalMerkleTree, [Input; 2]), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+
+        let params =
+            get_default_poseidon_parameters(4, true).expect("failed to build params for poseidon");
+
+        let mut tree = IncrementalMerkleTree::new(&params, height)?;
+        let mut owned = Vec::new();
+
+        for i in 0..1 << height {
+            let mut coin = Coin::rand(&mut rng);
+
+            if let Some(j) = indices.iter().position(|&index| index == i) {
+                let user = User::new(&params, &mut rng)?;
+                coin.pk = user.pk;
+                coin.value = values[j];
+
+                owned.push((i, coin.clone(), user));
+            }
+
+            tree.append(commitment::new_commitment(&params, &coin)?)?;
+        }
+
+        let inputs = owned
+            .into_iter()
+            .map(|(index, coin, user)| Input::new(&tree, index, coin, user))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((tree, inputs.try_into().map_err(|_| "expected two inputs")?))
+    }
+
+    fn outputs(values: [Value; 2]) -> [Output; 2] {
+        let mut rng = rand::thread_rng();
+
+        values.map(|value| Output::new(Key::rand(&mut rng), value, &mut rng))
+    }
+
+    #[test]
+    fn cs_sat() -> Result<(), Box<dyn Error>> {
+        let (tree, inputs) = generate_tree(4, [3, 9], [10, 32])?;
+        let payment =
+            JoinSplitProof::new(&tree, inputs, outputs([40, 1]), tx::digest(1, b"", &[]))?;
+
+        let cs = ConstraintSystem::new_ref();
+        payment.generate_constraints(cs.clone())?;
+
+        assert!(cs.is_satisfied()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that a single coin can be spent with a dummy input
+    fn cs_sat_dummy() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+
+        let (tree, [input, _]) = generate_tree(4, [3, 9], [10, 32])?;
+        let dummy = Input::dummy(&tree, &mut rng)?;
+        let payment = JoinSplitProof::new(
+            &tree,
+            [input, dummy],
+            outputs([7, 3]),
+            tx::digest(0, b"", &[]),
+        )?;
+
+        let cs = ConstraintSystem::new_ref();
+        payment.generate_constraints(cs.clone())?;
+
+        assert!(cs.is_satisfied()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that a coin which isn't in the tree can't be spent
+    fn dummy_sound() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+
+        let (tree, [input, _]) = generate_tree(4, [3, 9], [10, 32])?;
+        let mut fake = Input::dummy(&tree, &mut rng)?;
+        fake.coin.value = 5;
+
+        let payment = JoinSplitProof::new(
+            &tree,
+            [input, fake],
+            outputs([10, 5]),
+            tx::digest(0, b"", &[]),
+        )?;
+
+        let cs = ConstraintSystem::new_ref();
+        payment.generate_constraints(cs.clone())?;
+
+        assert!(!cs.is_satisfied()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that the outputs and the fee can't be worth more than the inputs
+    fn value_conserved() -> Result<(), Box<dyn Error>> {
+        let (tree, inputs) = generate_tree(4, [3, 9], [10, 32])?;
+        let digest = tx::digest(0, b"", &[]);
+
+        assert!(JoinSplitProof::new(&tree, inputs.clone(), outputs([40, 3]), digest).is_err());
+
+        let mut payment = JoinSplitProof::new(&tree, inputs, outputs([40, 2]), digest)?;
+        payment.fee += 1;
+
+        let cs = ConstraintSystem::new_ref();
+        payment.generate_constraints(cs.clone())?;
+
+        assert!(!cs.is_satisfied()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that the largest values add up without wrapping around the field
+    fn value_max() -> Result<(), Box<dyn Error>> {
+        let (tree, inputs) = generate_tree(4, [3, 9], [u64::MAX, u64::MAX])?;
+        let payment = JoinSplitProof::new(
+            &tree,
+            inputs,
+            outputs([u64::MAX, u64::MAX]),
+            tx::digest(0, b"", &[]),
+        )?;
+
+        let cs = ConstraintSystem::new_ref();
+        payment.generate_constraints(cs.clone())?;
+
+        assert!(cs.is_satisfied()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that a true proof is valid
+    fn proof_complete() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+
+        let (tree, inputs) = generate_tree(5, [1, 30], [1, 2])?;
+        let payment = JoinSplitProof::new(&tree, inputs, outputs([0, 2]), tx::digest(1, b"", &[]))?;
+
+        let (pk, vk) = Groth16::<Bls12_381, LibsnarkReduction>::circuit_specific_setup(
+            payment.clone(),
+            &mut rng,
+        )?;
+
+        let proof = Groth16::<Bls12_381, LibsnarkReduction>::prove(&pk, payment.clone(), &mut rng)?;
+        let is_valid = Groth16::<Bls12_381>::verify(&vk, &payment.public_inputs(), &proof)?;
+
+        assert!(is_valid);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,561 @@
+//! Groth16 keys of the payment circuits, and the files they are stored in.
+//!
+//! A key file is laid out as follows, with integers in big endian:
+//!
+//! | field   | size | content                                         |
+//! |---------|------|-------------------------------------------------|
+//! | magic   | 8    | `PCASHKEY`                                      |
+//! | version | 2    | [`VERSION`]                                     |
+//! | kind    | 1    | the [kind](KeyKind) of key                      |
+//! | circuit | 1    | the [id](Circuit::id) of the circuit            |
+//! | depth   | 4    | the depth of the tree the circuit was built for |
+//! | rate    | 4    | the rate of the Poseidon sponge of the tree     |
+//! | weights | 1    | 1 if the Poseidon parameters are optimized for  |
+//! |         |      | weights, 0 if for constraints                   |
+//! | length  | 8    | the length of the key                           |
+//! | key     | ...  | the key, compressed with `ark-serialize`        |
+//! | sum     | 32   | SHA-256 of everything above                     |
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use ark_bls12_381::Bls12_381;
+use ark_bls12_381::Fr as BlsFr;
+use ark_crypto_primitives::Error as ArkError;
+use ark_groth16::prepare_verifying_key;
+use ark_groth16::Groth16;
+use ark_groth16::PreparedVerifyingKey;
+use ark_groth16::ProvingKey;
+use ark_groth16::VerifyingKey;
+use ark_serialize::CanonicalDeserialize;
+use ark_serialize::CanonicalSerialize;
+use ark_serialize::SerializationError;
+use ark_snark::SNARK;
+use ark_std::UniformRand;
+use rand::CryptoRng;
+use rand::RngCore;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::joinsplit::Input;
+use crate::joinsplit::JoinSplitProof;
+use crate::joinsplit::Output;
+use crate::merkletree::IncrementalMerkleTree;
+use crate::merkletree::Params;
+use crate::payment::PaymentProof;
+use crate::poseidon::commitment;
+use crate::poseidon::get_default_poseidon_parameters;
+use crate::tx::Circuit;
+use crate::types::Coin;
+use crate::types::Key;
+use crate::user::User;
+
+const MAGIC: &[u8; 8] = b"PCASHKEY";
+
+/// The version of the key file format. Bump it whenever the layout changes.
+pub const VERSION: u16 = 2;
+
+/// The size of the header, before the key itself.
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 1 + 1 + 4 + 4 + 1 + 8;
+
+/// Whether a key is used to prove or to verify.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    Proving,
+    Verifying,
+
+    /// Not a key yet, but a [`Ceremony`](crate::ceremony::Ceremony) which will produce both.
+    Ceremony,
+}
+
+impl KeyKind {
+    const ALL: [KeyKind; 3] = [KeyKind::Proving, KeyKind::Verifying, KeyKind::Ceremony];
+
+    fn id(&self) -> u8 {
+        match self {
+            KeyKind::Proving => 0,
+            KeyKind::Verifying => 1,
+            KeyKind::Ceremony => 2,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            KeyKind::Proving => "pk",
+            KeyKind::Verifying => "vk",
+            KeyKind::Ceremony => "ceremony",
+        }
+    }
+}
+
+/// What a key is for. This is stored in front of every key, so that a key is never used with
+/// another circuit, or with a tree hashed with other Poseidon parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub kind: KeyKind,
+    pub circuit: Circuit,
+    pub depth: u32,
+
+    /// The rate of the Poseidon sponge of the tree.
+    pub rate: u32,
+
+    /// Whether the Poseidon parameters of the tree are optimized for weights rather than
+    /// constraints.
+    pub optimized_for_weights: bool,
+}
+
+impl Header {
+    /// The Poseidon parameters of the tree that the circuit was built for, if there are any for
+    /// this rate.
+    pub fn params(&self) -> Option<Params> {
+        get_default_poseidon_parameters(self.rate as usize, self.optimized_for_weights)
+    }
+
+    /// The name of the file holding this key in a key directory, such as `payment.vk`.
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.circuit.name(), self.kind.extension())
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(io::Error),
+
+    /// The key couldn't be serialized or deserialized.
+    Serialization(SerializationError),
+
+    /// The file doesn't start with the magic bytes, so it isn't a key file.
+    NotAKey,
+
+    /// The file was written with another version of the format.
+    UnsupportedVersion(u16),
+
+    /// The checksum doesn't match the contents of the file, which are corrupted.
+    BadChecksum,
+
+    /// The key isn't the one that was asked for.
+    WrongKey {
+        expected: Header,
+        found: Header,
+    },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(e) => write!(f, "{e}"),
+            KeyError::Serialization(e) => write!(f, "failed to (de)serialize the key: {e}"),
+            KeyError::NotAKey => write!(f, "not a key file"),
+            KeyError::UnsupportedVersion(v) => {
+                write!(f, "unsupported key file version {v}, expected {VERSION}")
+            }
+            KeyError::BadChecksum => write!(f, "the checksum of the key file doesn't match"),
+            KeyError::WrongKey { expected, found } => {
+                write!(
+                    f,
+                    "expected a key for {expected:?}, found one for {found:?}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl From<io::Error> for KeyError {
+    fn from(e: io::Error) -> Self {
+        KeyError::Io(e)
+    }
+}
+
+impl From<SerializationError> for KeyError {
+    fn from(e: SerializationError) -> Self {
+        KeyError::Serialization(e)
+    }
+}
+
+/// Generate the Groth16 keys of `circuit` for a tree of the given `depth`.
+///
+/// Whoever knows the randomness used here can forge proofs, so it must be thrown away.
+pub fn setup<R: RngCore + CryptoRng>(
+    params: &Params,
+    circuit: Circuit,
+    depth: usize,
+    rng: &mut R,
+) -> Result<(ProvingKey<Bls12_381>, VerifyingKey<Bls12_381>), ArkError> {
+    let keys = match circuit {
+        Circuit::Payment => {
+            Groth16::<Bls12_381>::circuit_specific_setup(sample_payment(params, depth, rng)?, rng)?
+        }
+        Circuit::JoinSplit => Groth16::<Bls12_381>::circuit_specific_setup(
+            sample_joinsplit(params, depth, rng)?,
+            rng,
+        )?,
+    };
+
+    Ok(keys)
+}
+
+/// A payment in a tree of the given depth. Only its shape matters for the setup.
+pub fn sample_payment<R: RngCore>(
+    params: &Params,
+    depth: usize,
+    rng: &mut R,
+) -> Result<PaymentProof, ArkError> {
+    let (tree, coin, user) = sample_tree(params, depth, rng)?;
+    let output = Output::new(Key::rand(rng), 0, rng);
+
+    PaymentProof::new(&tree, 0, coin, user, output, BlsFr::default())
+}
+
+/// A join split payment in a tree of the given depth. Only its shape matters for the setup.
+pub fn sample_joinsplit<R: RngCore>(
+    params: &Params,
+    depth: usize,
+    rng: &mut R,
+) -> Result<JoinSplitProof, ArkError> {
+    let (tree, coin, user) = sample_tree(params, depth, rng)?;
+    let inputs = [Input::new(&tree, 0, coin, user)?, Input::dummy(&tree, rng)?];
+    let outputs = [
+        Output::new(Key::rand(rng), 0, rng),
+        Output::new(Key::rand(rng), 0, rng),
+    ];
+
+    JoinSplitProof::new(&tree, inputs, outputs, BlsFr::default())
+}
+
+fn sample_tree<R: RngCore>(
+    params: &Params,
+    depth: usize,
+    rng: &mut R,
+) -> Result<(IncrementalMerkleTree, Coin, User), ArkError> {
+    let user = User::new(params, rng)?;
+
+    let mut coin = Coin::rand(rng);
+    coin.pk = user.pk;
+    coin.value = 0;
+
+    let mut tree = IncrementalMerkleTree::new(params, depth)?;
+    tree.append(commitment::new_commitment(params, &coin)?)?;
+
+    Ok((tree, coin, user))
+}
+
+/// Write `key` to `w`, along with its header and checksum.
+pub fn write<K: CanonicalSerialize>(
+    mut w: impl Write,
+    header: &Header,
+    key: &K,
+) -> Result<(), KeyError> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + key.compressed_size() + 32);
+    bytes.extend(MAGIC);
+    bytes.extend(VERSION.to_be_bytes());
+    bytes.push(header.kind.id());
+    bytes.push(header.circuit.id());
+    bytes.extend(header.depth.to_be_bytes());
+    bytes.extend(header.rate.to_be_bytes());
+    bytes.push(header.optimized_for_weights.into());
+    bytes.extend((key.compressed_size() as u64).to_be_bytes());
+    key.serialize_compressed(&mut bytes)?;
+
+    let sum = Sha256::digest(&bytes);
+    bytes.extend(sum);
+
+    w.write_all(&bytes)?;
+
+    Ok(())
+}
+
+/// Read a key from `r`, checking that it is the one described by `expected`.
+pub fn read<K: CanonicalDeserialize>(r: impl Read, expected: &Header) -> Result<K, KeyError> {
+    let (found, key) = read_any(r)?;
+    if found != *expected {
+        return Err(KeyError::WrongKey {
+            expected: *expected,
+            found,
+        });
+    }
+
+    Ok(key)
+}
+
+/// Read a key from `r`, whatever it is for.
+pub fn read_any<K: CanonicalDeserialize>(mut r: impl Read) -> Result<(Header, K), KeyError> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+
+    if bytes.len() < HEADER_SIZE + 32 || !bytes.starts_with(MAGIC) {
+        return Err(KeyError::NotAKey);
+    }
+
+    let (contents, sum) = bytes.split_at(bytes.len() - 32);
+    let (header, key) = contents.split_at(HEADER_SIZE);
+
+    let version = u16::from_be_bytes([header[8], header[9]]);
+    if version != VERSION {
+        return Err(KeyError::UnsupportedVersion(version));
+    }
+
+    if Sha256::digest(contents).as_slice() != sum {
+        return Err(KeyError::BadChecksum);
+    }
+
+    let kind = KeyKind::ALL
+        .into_iter()
+        .find(|kind| kind.id() == header[10])
+        .ok_or(KeyError::NotAKey)?;
+    let circuit = Circuit::from_id(header[11]).ok_or(KeyError::NotAKey)?;
+    let depth = u32::from_be_bytes(header[12..16].try_into().unwrap());
+    let rate = u32::from_be_bytes(header[16..20].try_into().unwrap());
+    let optimized_for_weights = match header[20] {
+        0 => false,
+        1 => true,
+        _ => return Err(KeyError::NotAKey),
+    };
+    let len = u64::from_be_bytes(header[21..29].try_into().unwrap());
+
+    if len != key.len() as u64 {
+        return Err(KeyError::NotAKey);
+    }
+
+    let header = Header {
+        kind,
+        circuit,
+        depth,
+        rate,
+        optimized_for_weights,
+    };
+
+    Ok((header, K::deserialize_compressed(key)?))
+}
+
+/// Write `key` to the file at `path`.
+pub fn save<K: CanonicalSerialize>(
+    path: impl AsRef<Path>,
+    header: &Header,
+    key: &K,
+) -> Result<(), KeyError> {
+    write(io::BufWriter::new(fs::File::create(path)?), header, key)
+}
+
+/// Read the key described by `expected` from the file at `path`.
+pub fn load<K: CanonicalDeserialize>(
+    path: impl AsRef<Path>,
+    expected: &Header,
+) -> Result<K, KeyError> {
+    read(io::BufReader::new(fs::File::open(path)?), expected)
+}
+
+/// Read the key in the file at `path`, whatever it is for.
+pub fn load_any<K: CanonicalDeserialize>(path: impl AsRef<Path>) -> Result<(Header, K), KeyError> {
+    read_any(io::BufReader::new(fs::File::open(path)?))
+}
+
+/// Generate the keys of every circuit for a tree of the given `depth`, hashed with the Poseidon
+/// parameters of the given `rate`, and write them to `dir`. Returns the paths of the files
+/// written.
+pub fn generate<R: RngCore + CryptoRng>(
+    dir: impl AsRef<Path>,
+    depth: usize,
+    rate: u32,
+    optimized_for_weights: bool,
+    rng: &mut R,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    let mut paths = Vec::new();
+    for circuit in Circuit::ALL {
+        let header = Header {
+            kind: KeyKind::Proving,
+            circuit,
+            depth: depth as u32,
+            rate,
+            optimized_for_weights,
+        };
+        let params = header
+            .params()
+            .ok_or("no Poseidon parameters for this rate")?;
+        let (pk, vk) = setup(&params, circuit, depth, rng)?;
+
+        let path = dir.join(header.file_name());
+        save(&path, &header, &pk)?;
+        paths.push(path);
+
+        let header = Header {
+            kind: KeyKind::Verifying,
+            ..header
+        };
+        let path = dir.join(header.file_name());
+        save(&path, &header, &vk)?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+/// Load the proving keys of every circuit from `dir`, as written by [`generate`].
+pub fn load_proving_keys(
+    dir: impl AsRef<Path>,
+    depth: usize,
+    rate: u32,
+    optimized_for_weights: bool,
+) -> Result<HashMap<Circuit, ProvingKey<Bls12_381>>, KeyError> {
+    Circuit::ALL
+        .into_iter()
+        .map(|circuit| {
+            let header = Header {
+                kind: KeyKind::Proving,
+                circuit,
+                depth: depth as u32,
+                rate,
+                optimized_for_weights,
+            };
+
+            Ok((
+                circuit,
+                load(dir.as_ref().join(header.file_name()), &header)?,
+            ))
+        })
+        .collect()
+}
+
+/// Load the verifying keys of every circuit from `dir`, as written by [`generate`].
+pub fn load_verifying_keys(
+    dir: impl AsRef<Path>,
+    depth: usize,
+    rate: u32,
+    optimized_for_weights: bool,
+) -> Result<HashMap<Circuit, PreparedVerifyingKey<Bls12_381>>, KeyError> {
+    Circuit::ALL
+        .into_iter()
+        .map(|circuit| {
+            let header = Header {
+                kind: KeyKind::Verifying,
+                circuit,
+                depth: depth as u32,
+                rate,
+                optimized_for_weights,
+            };
+            let vk: VerifyingKey<Bls12_381> = load(dir.as_ref().join(header.file_name()), &header)?;
+
+            Ok((circuit, prepare_verifying_key(&vk)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use ark_bls12_381::Bls12_381;
+    use ark_groth16::Groth16;
+    use ark_groth16::ProvingKey;
+    use ark_groth16::VerifyingKey;
+    use ark_snark::SNARK;
+
+    use super::Header;
+    use super::KeyError;
+    use super::KeyKind;
+    use crate::poseidon::get_default_poseidon_parameters;
+    use crate::tx::Circuit;
+
+    const DEPTH: usize = 4;
+
+    fn header(kind: KeyKind) -> Header {
+        Header {
+            kind,
+            circuit: Circuit::Payment,
+            depth: DEPTH as u32,
+            rate: 4,
+            optimized_for_weights: true,
+        }
+    }
+
+    #[test]
+    /// Check that keys read back from a file still prove and verify payments
+    fn roundtrip() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let params =
+            get_default_poseidon_parameters(4, true).expect("failed to build params for poseidon");
+
+        let (pk, vk) = super::setup(&params, Circuit::Payment, DEPTH, &mut rng)?;
+
+        let mut pk_bytes = Vec::new();
+        super::write(&mut pk_bytes, &header(KeyKind::Proving), &pk)?;
+        let mut vk_bytes = Vec::new();
+        super::write(&mut vk_bytes, &header(KeyKind::Verifying), &vk)?;
+
+        let pk: ProvingKey<Bls12_381> = super::read(&pk_bytes[..], &header(KeyKind::Proving))?;
+        let vk: VerifyingKey<Bls12_381> = super::read(&vk_bytes[..], &header(KeyKind::Verifying))?;
+
+        let payment = super::sample_payment(&params, DEPTH, &mut rng)?;
+        let proof = Groth16::<Bls12_381>::prove(&pk, payment.clone(), &mut rng)?;
+        assert!(Groth16::<Bls12_381>::verify(
+            &vk,
+            &payment.public_inputs(),
+            &proof
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that corrupted, outdated or mismatched key files are rejected
+    fn reject_bad_files() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let params =
+            get_default_poseidon_parameters(4, true).expect("failed to build params for poseidon");
+
+        let (_, vk) = super::setup(&params, Circuit::Payment, DEPTH, &mut rng)?;
+        let header = header(KeyKind::Verifying);
+
+        let mut bytes = Vec::new();
+        super::write(&mut bytes, &header, &vk)?;
+
+        let mut corrupted = bytes.clone();
+        corrupted[40] ^= 1;
+        assert!(matches!(
+            super::read::<VerifyingKey<Bls12_381>>(&corrupted[..], &header),
+            Err(KeyError::BadChecksum)
+        ));
+
+        let mut outdated = bytes.clone();
+        outdated[9] += 1;
+        assert!(matches!(
+            super::read::<VerifyingKey<Bls12_381>>(&outdated[..], &header),
+            Err(KeyError::UnsupportedVersion(3))
+        ));
+
+        let deeper = Header {
+            depth: header.depth + 1,
+            ..header
+        };
+        assert!(matches!(
+            super::read::<VerifyingKey<Bls12_381>>(&bytes[..], &deeper),
+            Err(KeyError::WrongKey { .. })
+        ));
+
+        // the circuit hashes the tree with other Poseidon parameters
+        let other = Header {
+            optimized_for_weights: false,
+            ..header
+        };
+        assert!(matches!(
+            super::read::<VerifyingKey<Bls12_381>>(&bytes[..], &other),
+            Err(KeyError::WrongKey { .. })
+        ));
+
+        assert!(matches!(
+            super::read::<VerifyingKey<Bls12_381>>(&bytes[8..], &header),
+            Err(KeyError::NotAKey)
+        ));
+
+        Ok(())
+    }
+}
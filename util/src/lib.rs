@@ -1,5 +1,13 @@
 //! This library provides utilities shared by both the nodes and the clients.
+pub mod attestation;
+pub mod batch;
+pub mod ceremony;
+mod gadgets;
+pub mod joinsplit;
+pub mod keys;
 pub mod payment;
+pub mod query;
+pub mod tx;
 pub mod types;
 pub mod user;
 
@@ -1,5 +1,7 @@
 use ark_bls12_381::Fr as BlsFr;
+use ark_crypto_primitives::crh::CRHScheme;
 use ark_crypto_primitives::crh::CRHSchemeGadget;
+use ark_crypto_primitives::Error as ArkError;
 use ark_r1cs_std::alloc::AllocVar;
 use ark_r1cs_std::boolean::Boolean;
 use ark_r1cs_std::eq::EqGadget;
@@ -8,19 +10,27 @@ use ark_relations::r1cs::ConstraintSynthesizer;
 use ark_relations::r1cs::ConstraintSystemRef;
 use ark_relations::r1cs::Result;
 
+use crate::gadgets;
+use crate::joinsplit::Output;
+use crate::merkletree::IncrementalMerkleTree;
 use crate::merkletree::Params;
 use crate::merkletree::ParamsVar;
 use crate::merkletree::Root;
 use crate::merkletree::RootVar;
 use crate::merkletree::TreePath;
 use crate::merkletree::TreePathVar;
+use crate::poseidon::commitment;
+use crate::poseidon::BlsPoseidon;
 use crate::poseidon::BlsPoseidonGadget;
 use crate::poseidon::CoinCommitment;
 use crate::poseidon::CoinCommitmentVar;
 use crate::types::Coin;
 use crate::types::CoinID;
+use crate::types::Value;
 use crate::user::User;
 
+/// Proves the payment of a single coin, less a fee, to a single recipient. This is a special case
+/// of [`JoinSplitProof`](crate::joinsplit::JoinSplitProof) with a much smaller circuit.
 #[derive(Clone)]
 pub struct PaymentProof {
     params: Params,
@@ -29,10 +39,27 @@ pub struct PaymentProof {
     /// The root of the Merkle Tree
     pub root: Root,
 
-    /// The leaf corresponding to the Coin Commitment belonging to the user.
-    pub commitment: CoinCommitment,
+    /// The serial number revealed by the user. We prove that `serial_no = prf(sk, pre_serial_no)`,
+    /// so that nodes can use it to reject double spends.
+    pub serial_number: CoinID,
+
+    /// The commitment of the coin created for the recipient, `c' = H(pk_B, pre_serial_no',
+    /// com_rnd', value)` where `pre_serial_no' = H(serial_no)`. This is what the nodes add to the
+    /// tree.
+    pub output: CoinCommitment,
+
+    /// The difference between the value of the spent coin and the value of the output coin.
+    pub fee: Value,
+
+    /// The [digest](crate::tx::digest) of the transaction this proof is made for, so that it can't
+    /// be reused in another one.
+    pub tx_digest: BlsFr,
 
     // Private Witnesses
+    /// The leaf corresponding to the Coin Commitment belonging to the user. This is kept private so
+    /// that a payment can't be linked to the coin it spends.
+    pub commitment: CoinCommitment,
+
     /// The path down the `MerkleTree` which leads to `leaf`.
     pub path: TreePath,
 
@@ -42,38 +69,131 @@ pub struct PaymentProof {
     /// The [`User`] who the coin belongs to.
     pub user: User,
 
-    /// The serial number to be revealed by the user. We prove that `serial_no = prf(sk, pre_serial_no)`.
-    pub serial_number: CoinID,
+    /// The coin created for the recipient of the payment, `pk_B`.
+    pub output_coin: Output,
+}
+
+impl PaymentProof {
+    /// Build the statement that `user` owns the coin at `index` in `tree`, and pays it to
+    /// `output_coin.recipient` in the transaction with digest `tx_digest`. Whatever is left of the
+    /// coin is the fee.
+    pub fn new(
+        tree: &IncrementalMerkleTree,
+        index: usize,
+        coin: Coin,
+        user: User,
+        output_coin: Output,
+        tx_digest: BlsFr,
+    ) -> std::result::Result<Self, ArkError> {
+        let params = tree.params().clone();
+
+        let fee = coin
+            .value
+            .checked_sub(output_coin.value)
+            .ok_or("the output is worth more than the coin")?;
+
+        let root = tree.root();
+        let path = tree.generate_proof(index)?;
+        let commitment = commitment::new_commitment(&params, &coin)?;
+        let serial_number = BlsPoseidon::evaluate(&params, [user.sk, coin.pre_serial_number])?;
+
+        let mut payment = Self {
+            params,
+            root,
+            serial_number,
+            output: CoinCommitment::default(),
+            fee,
+            tx_digest,
+            commitment,
+            path,
+            coin,
+            user,
+            output_coin,
+        };
+        payment.output = commitment::new_commitment(&payment.params, &payment.new_coin()?)?;
+
+        Ok(payment)
+    }
+
+    /// The coin created by this payment. The payer has to send it to the recipient privately, so
+    /// that they can find it in the tree and spend it.
+    pub fn new_coin(&self) -> std::result::Result<Coin, ArkError> {
+        Ok(Coin {
+            pk: self.output_coin.recipient,
+            pre_serial_number: commitment::output_pre_serial_number(
+                &self.params,
+                self.serial_number,
+            )?,
+            com_rnd: self.output_coin.com_rnd,
+            value: self.output_coin.value,
+        })
+    }
+
+    /// The public inputs of the proof, in the order they are allocated.
+    pub fn public_inputs(&self) -> Vec<BlsFr> {
+        vec![
+            self.root,
+            self.serial_number,
+            self.output,
+            BlsFr::from(self.fee),
+            self.tx_digest,
+        ]
+    }
 }
 
 impl ConstraintSynthesizer<BlsFr> for PaymentProof {
     fn generate_constraints(self, cs: ConstraintSystemRef<BlsFr>) -> Result<()> {
         // public inputs
         let root = RootVar::new_input(ark_relations::ns!(cs, "merkle_root"), || Ok(self.root))?;
-        let leaf = CoinCommitmentVar::new_input(ark_relations::ns!(cs, "merkle_leaf"), || {
-            Ok(self.commitment)
+        let serial_number = FpVar::new_input(ark_relations::ns!(cs, "serial_number"), || {
+            Ok(&self.serial_number)
         })?;
+        let output =
+            CoinCommitmentVar::new_input(ark_relations::ns!(cs, "output_commitment"), || {
+                Ok(self.output)
+            })?;
+        let fee = FpVar::new_input(ark_relations::ns!(cs, "fee"), || Ok(BlsFr::from(self.fee)))?;
+        // no constraint uses the digest: like every public input, Groth16 binds the proof to it
+        // through the verifying key, so the proof is only valid for the transaction it was made
+        // for
+        let _tx_digest =
+            FpVar::new_input(ark_relations::ns!(cs, "tx_digest"), || Ok(self.tx_digest))?;
 
         // private witnesses
 
+        // The commitment of the coin being spent. Revealing it would tell everyone which coin
+        // is being spent.
+        let leaf = CoinCommitmentVar::new_witness(ark_relations::ns!(cs, "merkle_leaf"), || {
+            Ok(self.commitment)
+        })?;
+
         // A private witness of the path down the MerkleTree which leads to the commitment.
         let path =
             TreePathVar::new_witness(ark_relations::ns!(cs, "merkle_tree_path"), || Ok(self.path))?;
         let pk = FpVar::new_witness(ark_relations::ns!(cs, "pub_key"), || Ok(self.coin.pk))?;
-        let serial_number = FpVar::new_witness(ark_relations::ns!(cs, "serial_number"), || {
-            Ok(&self.serial_number)
-        })?;
         let pre_serial_number =
             FpVar::new_witness(ark_relations::ns!(cs, "pre_serial_number"), || {
                 Ok(&self.coin.pre_serial_number)
             })?;
         let com_rnd =
             FpVar::new_witness(ark_relations::ns!(cs, "com_rnd"), || Ok(&self.coin.com_rnd))?;
+        let value = gadgets::value_witness(ark_relations::ns!(cs, "value"), self.coin.value)?;
 
         let sk = FpVar::new_witness(ark_relations::ns!(cs, "sec_key"), || Ok(&self.user.sk))?;
         let noise =
             FpVar::new_witness(ark_relations::ns!(cs, "key_noise"), || Ok(&self.user.noise))?;
 
+        let recipient = FpVar::new_witness(ark_relations::ns!(cs, "recipient_pub_key"), || {
+            Ok(self.output_coin.recipient)
+        })?;
+        let output_rnd = FpVar::new_witness(ark_relations::ns!(cs, "output_com_rnd"), || {
+            Ok(self.output_coin.com_rnd)
+        })?;
+        let output_value = gadgets::value_witness(
+            ark_relations::ns!(cs, "output_value"),
+            self.output_coin.value,
+        )?;
+
         let params =
             ParamsVar::new_constant(ark_relations::ns!(cs, "poseidon_params"), self.params)?;
 
@@ -82,14 +202,21 @@ impl ConstraintSynthesizer<BlsFr> for PaymentProof {
         //    - pk
         //    - pre_serial_no
         //    - com_rnd
-        let is_member = path.verify_membership(&params, &params, &root, &[leaf.clone()])?;
+        //    - value
+        let is_member =
+            path.verify_membership(&params, &params, &root, std::slice::from_ref(&leaf))?;
         is_member.enforce_equal(&Boolean::TRUE)?;
 
         // Of course, the commitment that we point to in the tree has to be made of the things we
         // claim it is.
         let expected_commitment_hash = BlsPoseidonGadget::evaluate(
             &params,
-            &[pk.clone(), pre_serial_number.clone(), com_rnd],
+            &[
+                pk.clone(),
+                pre_serial_number.clone(),
+                com_rnd,
+                value.clone(),
+            ],
         )?;
         expected_commitment_hash.enforce_equal(&leaf)?;
 
@@ -105,6 +232,25 @@ impl ConstraintSynthesizer<BlsFr> for PaymentProof {
         let expected_pk = BlsPoseidonGadget::evaluate(&params, &[sk, noise])?;
         expected_pk.enforce_equal(&pk)?;
 
+        // 4. We prove that the output commitment is `c' = H(pk_B, pre_serial_no', com_rnd', value')`
+        //    where `pre_serial_no' = H(serial_no)`, so that the nodes can add it to the tree as is,
+        //    and the payee can trust how `pre_serial_no'` was derived.
+        let output_pre_serial_number = BlsPoseidonGadget::evaluate(&params, &[serial_number])?;
+        let expected_output = BlsPoseidonGadget::evaluate(
+            &params,
+            &[
+                recipient,
+                output_pre_serial_number,
+                output_rnd,
+                output_value.clone(),
+            ],
+        )?;
+        expected_output.enforce_equal(&output)?;
+
+        // 5. What isn't paid to the payee is the fee. Both values are less than 2^64, and nodes
+        //    only accept 64 bit fees, so this can't wrap around the field.
+        value.enforce_equal(&(output_value + fee))?;
+
         Ok(())
     }
 }
@@ -121,16 +267,22 @@ mod test {
     use ark_relations::r1cs::ConstraintSynthesizer;
     use ark_relations::r1cs::ConstraintSystem;
     use ark_snark::SNARK;
+    use ark_std::UniformRand;
     use rand;
 
     use super::PaymentProof;
+    use crate::joinsplit::Output;
+    use crate::merkletree::IncrementalMerkleTree;
     use crate::merkletree::MerkleTree;
     use crate::merkletree::Root;
     use crate::merkletree::TreePath;
     use crate::poseidon::commitment;
     use crate::poseidon::get_default_poseidon_parameters;
     use crate::poseidon::BlsPoseidon;
+    use crate::tx;
+    use crate::tx::Transaction;
     use crate::types::Coin;
+    use crate::types::Key;
     use crate::user::User;
 
     pub fn generate_new_payment(
@@ -153,35 +305,26 @@ mod test {
 
         let mut coin = Coin::rand(&mut rng);
         coin.pk = user.pk;
+        coin.value = 100;
 
         let commitment =
             commitment::new_commitment(&params, &coin).expect("failed to create commitment");
-        let mut leaves: Vec<Vec<BlsFr>> = range
-            .map(|_| {
-                let com = commitment::rand(&params, &mut rng)
-                    .expect("failed to create poseidon commitment");
 
-                vec![com]
-            })
-            .collect();
+        let mut tree = IncrementalMerkleTree::new(&params, tree_height as usize)?;
+        for i in range {
+            let com = if i == index {
+                commitment
+            } else {
+                commitment::rand(&params, &mut rng).expect("failed to create poseidon commitment")
+            };
 
-        leaves[index] = vec![commitment];
+            tree.append(com)?;
+        }
 
-        let tree = MerkleTree::new(&params, &params, leaves)?;
-        let root: Root = tree.root();
-        let path: TreePath = tree.generate_proof(index)?;
-
-        let serial_number = BlsPoseidon::evaluate(&params, [user.sk, coin.pre_serial_number])?;
+        let output = Output::new(Key::rand(&mut rng), 90, &mut rng);
+        let tx_digest = tx::digest(10, b"", &[]);
 
-        Ok(PaymentProof {
-            params,
-            root,
-            commitment,
-            path,
-            coin,
-            user,
-            serial_number,
-        })
+        PaymentProof::new(&tree, index, coin, user, output, tx_digest)
     }
 
     #[test]
@@ -239,15 +382,20 @@ mod test {
 
         let user = User::new(&params, &mut rng)?;
         let serial_number = BlsPoseidon::evaluate(&params, [user.sk, coin.pre_serial_number])?;
+        let output = commitment::rand(&params, &mut rng)?;
 
         let payment = PaymentProof {
             params,
             root,
+            serial_number,
+            output,
+            fee: 0,
+            tx_digest: BlsFr::rand(&mut rng),
             commitment,
             path,
+            output_coin: Output::new(Key::rand(&mut rng), coin.value, &mut rng),
             coin,
             user,
-            serial_number,
         };
 
         let cs = ConstraintSystem::new_ref();
@@ -275,14 +423,177 @@ mod test {
 
         let proof = Groth16::<Bls12_381, LibsnarkReduction>::prove(&pk, payment.clone(), &mut rng)?;
 
-        let public_inputs = [payment.root, payment.commitment];
-        let is_valid = Groth16::<Bls12_381>::verify(&vk, &public_inputs, &proof)?;
+        let is_valid = Groth16::<Bls12_381>::verify(&vk, &payment.public_inputs(), &proof)?;
 
         assert!(is_valid);
 
         Ok(())
     }
 
+    #[test]
+    /// Check that the proof doesn't verify against another serial number
+    fn proof_binds_serial_number() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+
+        let index = 1;
+        let height = 5; // 32 leaves
+        let payment = generate_new_payment(index, height)?;
+        let (pk, vk) = Groth16::<Bls12_381, LibsnarkReduction>::circuit_specific_setup(
+            payment.clone(),
+            &mut rng,
+        )?;
+
+        let proof = Groth16::<Bls12_381, LibsnarkReduction>::prove(&pk, payment.clone(), &mut rng)?;
+
+        let mut public_inputs = payment.public_inputs();
+        public_inputs[1] = BlsFr::from(42);
+        let is_valid = Groth16::<Bls12_381>::verify(&vk, &public_inputs, &proof)?;
+
+        assert!(!is_valid);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that the output commitment must be derived from the serial number
+    fn output_sound() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let index = 1;
+        let height = 5; // 32 leaves
+
+        let mut payment = generate_new_payment(index, height)?;
+
+        // a well formed coin for the recipient, but with a `pre_serial_number` of our choosing
+        let mut coin = payment.new_coin()?;
+        coin.pre_serial_number = BlsFr::rand(&mut rng);
+        payment.output = commitment::new_commitment(&payment.params, &coin)?;
+
+        let cs = ConstraintSystem::new_ref();
+        payment.generate_constraints(cs.clone())?;
+
+        assert!(!cs.is_satisfied()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that the payee can't be paid more than what's left of the coin after the fee
+    fn fee_sound() -> Result<(), Box<dyn Error>> {
+        let index = 1;
+        let height = 5; // 32 leaves
+
+        let mut payment = generate_new_payment(index, height)?;
+        payment.fee -= 1;
+
+        let cs = ConstraintSystem::new_ref();
+        payment.generate_constraints(cs.clone())?;
+
+        assert!(!cs.is_satisfied()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that a proof can't be moved to a transaction with another fee, memo or notes
+    fn proof_binds_tx() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+
+        let index = 1;
+        let height = 5; // 32 leaves
+        let mut payment = generate_new_payment(index, height)?;
+
+        let memo = b"rent".to_vec();
+        let notes = vec![b"note for the payee".to_vec()];
+        payment.tx_digest = tx::digest(payment.fee, &memo, &notes);
+
+        let (pk, vk) = Groth16::<Bls12_381, LibsnarkReduction>::circuit_specific_setup(
+            payment.clone(),
+            &mut rng,
+        )?;
+        let pvk = Groth16::<Bls12_381>::process_vk(&vk)?;
+
+        let tx = Transaction {
+            root: payment.root,
+            serial_numbers: vec![payment.serial_number],
+            outputs: vec![payment.output],
+            fee: payment.fee,
+            memo,
+            notes,
+            proof: Groth16::<Bls12_381, LibsnarkReduction>::prove(&pk, payment, &mut rng)?,
+        };
+        assert!(tx.verify(&pvk)?);
+
+        let mut other = tx.clone();
+        other.fee -= 1;
+        assert!(!other.verify(&pvk)?);
+
+        let mut other = tx.clone();
+        other.memo = b"refund".to_vec();
+        assert!(!other.verify(&pvk)?);
+
+        let mut other = tx.clone();
+        other.notes.clear();
+        assert!(!other.verify(&pvk)?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Check that the verifier learns nothing about which leaf is spent: two coins in the same tree
+    /// are spent against the same root, and the only other thing revealed is their serial numbers,
+    /// which don't depend on the leaves.
+    fn proof_hides_leaf() -> Result<(), Box<dyn Error>> {
+        let mut rng = rand::thread_rng();
+        let height = 5; // 32 leaves
+
+        let params =
+            get_default_poseidon_parameters(4, true).expect("failed to build params for poseidon");
+
+        let mut tree = IncrementalMerkleTree::new(&params, height)?;
+        let mut owned = Vec::new();
+        for _ in 0..4 {
+            let user = User::new(&params, &mut rng)?;
+            let mut coin = Coin::rand(&mut rng);
+            coin.pk = user.pk;
+
+            let index = tree.append(commitment::new_commitment(&params, &coin)?)?;
+            owned.push((index, coin, user));
+        }
+
+        let payments = owned
+            .into_iter()
+            .map(|(index, coin, user)| {
+                let output = Output::new(Key::rand(&mut rng), coin.value, &mut rng);
+
+                PaymentProof::new(&tree, index, coin, user, output, tx::digest(0, b"", &[]))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (pk, vk) = Groth16::<Bls12_381, LibsnarkReduction>::circuit_specific_setup(
+            payments[0].clone(),
+            &mut rng,
+        )?;
+
+        for payment in payments {
+            let public_inputs = payment.public_inputs();
+
+            assert_eq!(
+                public_inputs[..3],
+                [tree.root(), payment.serial_number, payment.output]
+            );
+            assert!(tree
+                .leaves()
+                .iter()
+                .all(|leaf| !public_inputs.contains(leaf)));
+
+            let proof =
+                Groth16::<Bls12_381, LibsnarkReduction>::prove(&pk, payment.clone(), &mut rng)?;
+            assert!(Groth16::<Bls12_381>::verify(&vk, &public_inputs, &proof)?);
+        }
+
+        Ok(())
+    }
+
     #[test]
     /// Check that the proof size is not too big
     fn proof_size() -> Result<(), Box<dyn Error>> {
@@ -7,16 +7,31 @@ use super::BlsPoseidon;
 use super::CoinCommitment;
 use super::PoseidonParams;
 use crate::types::Coin;
+use crate::types::CoinID;
 
 pub fn new_commitment(
     parameters: &PoseidonParams,
     coin: &Coin,
 ) -> Result<CoinCommitment, ArkError> {
-    let input: [BlsFr; 3] = [coin.pk, coin.pre_serial_number, coin.com_rnd];
+    let input: [BlsFr; 4] = [
+        coin.pk,
+        coin.pre_serial_number,
+        coin.com_rnd,
+        BlsFr::from(coin.value),
+    ];
 
     BlsPoseidon::evaluate(parameters, input)
 }
 
+/// The `pre_serial_number` of the coin created by spending the coin with `serial_number`, i.e.
+/// `pre_serial_no' = H(serial_no)`.
+pub fn output_pre_serial_number(
+    parameters: &PoseidonParams,
+    serial_number: CoinID,
+) -> Result<CoinID, ArkError> {
+    BlsPoseidon::evaluate(parameters, [serial_number])
+}
+
 pub fn rand<R: Rng>(parameters: &PoseidonParams, rng: &mut R) -> Result<CoinCommitment, ArkError> {
     new_commitment(parameters, &Coin::rand(rng))
 }
@@ -4,6 +4,7 @@ use ark_crypto_primitives::crh::poseidon::constraints::CRHParametersVar;
 use ark_crypto_primitives::crh::poseidon::constraints::TwoToOneCRHGadget as PoseidonTwoToOneCRHGadget;
 use ark_crypto_primitives::crh::poseidon::TwoToOneCRH as PoseidonTwoToOneCRH;
 use ark_crypto_primitives::crh::poseidon::CRH as PoseidonCRH;
+use ark_crypto_primitives::crh::CRHScheme;
 use ark_crypto_primitives::crh::TwoToOneCRHScheme;
 use ark_crypto_primitives::crh::TwoToOneCRHSchemeGadget;
 use ark_crypto_primitives::merkle_tree::constraints::ConfigGadget;
@@ -12,6 +13,7 @@ use ark_crypto_primitives::merkle_tree::Config;
 use ark_crypto_primitives::merkle_tree::MerkleTree as ArkMerkleTree;
 use ark_crypto_primitives::merkle_tree::Path;
 use ark_crypto_primitives::sponge::poseidon::PoseidonConfig;
+use ark_crypto_primitives::Error as ArkError;
 
 use super::digest::PoseidonDigest;
 use super::digest::PoseidonDigestVar;
@@ -19,6 +21,7 @@ use super::CRHInput;
 use super::CRHInputVar;
 use super::CRHOutput;
 use super::CRHOutputVar;
+use super::CoinCommitment;
 use super::TwoToOneCRHOutput;
 use super::TwoToOneCRHOutputVar;
 
@@ -73,3 +76,195 @@ pub type TreePath = Path<MerkleConfig>;
 pub type TreePathVar = PathVar<MerkleConfig, BlsFr, MerkleConfigVar>;
 
 pub type MerkleTree = ArkMerkleTree<MerkleConfig>;
+
+/// The depth of the commitment tree used by the network, which holds up to `2^TREE_DEPTH` coins.
+pub const TREE_DEPTH: usize = 32;
+
+/// An append-only Merkle tree of fixed depth, with the same hashing as [`MerkleTree`].
+///
+/// Unlike [`MerkleTree`], the tree doesn't need all of its leaves up front. Empty leaves hold the
+/// zero commitment, and appending a commitment only rehashes the `depth` nodes above it.
+#[derive(Clone)]
+pub struct IncrementalMerkleTree {
+    params: Params,
+
+    /// The number of levels above the leaves. The tree holds up to `2^depth` commitments.
+    depth: usize,
+
+    /// The commitments appended so far, from left to right.
+    leaves: Vec<CoinCommitment>,
+
+    /// The non-empty nodes of every level, from left to right. `nodes[0]` are the leaf digests and
+    /// `nodes[depth]` is the root.
+    nodes: Vec<Vec<BlsFr>>,
+
+    /// `empty[level]` is the digest of an empty subtree whose root sits at `level`.
+    empty: Vec<BlsFr>,
+}
+
+impl IncrementalMerkleTree {
+    /// Create an empty tree which holds up to `2^depth` commitments.
+    pub fn new(params: &Params, depth: usize) -> Result<Self, ArkError> {
+        if depth == 0 || depth >= usize::BITS as usize {
+            return Err(format!("invalid commitment tree depth {depth}").into());
+        }
+
+        let mut empty = Vec::with_capacity(depth + 1);
+        empty.push(LeafHash::evaluate(params, [CoinCommitment::default()])?);
+        for level in 1..=depth {
+            let child = empty[level - 1];
+            empty.push(TwoToOneHash::compress(params, child, child)?);
+        }
+
+        Ok(Self {
+            params: params.clone(),
+            depth,
+            leaves: Vec::new(),
+            nodes: vec![Vec::new(); depth + 1],
+            empty,
+        })
+    }
+
+    /// The Poseidon parameters used to hash the tree.
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The number of commitments in the tree.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// The number of commitments that can still be appended.
+    pub fn room(&self) -> usize {
+        (1 << self.depth) - self.leaves.len()
+    }
+
+    /// The commitments in the tree, in the order they were appended.
+    pub fn leaves(&self) -> &[CoinCommitment] {
+        &self.leaves
+    }
+
+    pub fn root(&self) -> Root {
+        self.node(self.depth, 0)
+    }
+
+    /// Append a commitment to the tree, and return its index.
+    pub fn append(&mut self, commitment: CoinCommitment) -> Result<usize, ArkError> {
+        let index = self.leaves.len();
+        if index >> self.depth != 0 {
+            return Err("the commitment tree is full".into());
+        }
+
+        let digest = LeafHash::evaluate(&self.params, [commitment])?;
+        self.leaves.push(commitment);
+        self.nodes[0].push(digest);
+
+        let mut i = index;
+        for level in 1..=self.depth {
+            i >>= 1;
+
+            let left = self.node(level - 1, 2 * i);
+            let right = self.node(level - 1, 2 * i + 1);
+            let parent = TwoToOneHash::compress(&self.params, left, right)?;
+
+            match self.nodes[level].get_mut(i) {
+                Some(node) => *node = parent,
+                None => self.nodes[level].push(parent),
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Returns the authentication path from the commitment at `index` to the current root.
+    pub fn generate_proof(&self, index: usize) -> Result<TreePath, ArkError> {
+        if index >= self.leaves.len() {
+            return Err(format!("no commitment at index {index}").into());
+        }
+
+        // `auth_path` goes from the top of the tree down to the level above the leaves
+        let auth_path = (1..self.depth)
+            .rev()
+            .map(|level| self.node(level, (index >> level) ^ 1))
+            .collect();
+
+        Ok(TreePath {
+            leaf_sibling_hash: self.node(0, index ^ 1),
+            auth_path,
+            leaf_index: index,
+        })
+    }
+
+    fn node(&self, level: usize, i: usize) -> BlsFr {
+        self.nodes[level]
+            .get(i)
+            .copied()
+            .unwrap_or(self.empty[level])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use ark_bls12_381::Fr as BlsFr;
+
+    use super::IncrementalMerkleTree;
+    use super::MerkleTree;
+    use crate::poseidon::get_default_poseidon_parameters;
+
+    #[test]
+    /// Check that the incremental tree agrees with arkworks' tree padded with zero commitments
+    fn matches_merkle_tree() -> Result<(), Box<dyn Error>> {
+        let depth = 3;
+        let params = get_default_poseidon_parameters(4, true).expect("failed to build params");
+
+        let mut tree = IncrementalMerkleTree::new(&params, depth)?;
+        let mut leaves = vec![[BlsFr::from(0)]; 1 << depth];
+
+        for i in 0..5 {
+            let commitment = BlsFr::from(i as u64 + 1);
+            leaves[i] = [commitment];
+            assert_eq!(tree.append(commitment)?, i);
+
+            let expected = MerkleTree::new(&params, &params, leaves.clone())?;
+            assert_eq!(tree.root(), expected.root());
+
+            for (j, leaf) in leaves.iter().enumerate().take(i + 1) {
+                let path = tree.generate_proof(j)?;
+                let expected_path = expected.generate_proof(j)?;
+
+                assert_eq!(path.leaf_sibling_hash, expected_path.leaf_sibling_hash);
+                assert_eq!(path.auth_path, expected_path.auth_path);
+                assert!(path.verify(&params, &params, &tree.root(), *leaf)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn full_tree() -> Result<(), Box<dyn Error>> {
+        let depth = 2;
+        let params = get_default_poseidon_parameters(4, true).expect("failed to build params");
+
+        let mut tree = IncrementalMerkleTree::new(&params, depth)?;
+        for i in 0..4 {
+            tree.append(BlsFr::from(i))?;
+        }
+
+        assert!(tree.append(BlsFr::from(4)).is_err());
+        assert!(tree.generate_proof(4).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+//! The answers of the nodes to ABCI queries, and the proofs that come with them. Nodes and clients
+//! both need this: nodes to encode their answers, and clients to decode them and check them
+//! against the app hash of a block they trust.
+use ark_serialize::CanonicalDeserialize;
+use ark_serialize::CanonicalSerialize;
+use ark_serialize::SerializationError;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::merkletree::Root;
+use crate::types::CoinID;
+
+/// The version of the encoding of query answers and proofs. It is the first byte of every one of
+/// them, and bumped whenever one of them changes.
+pub const VERSION: u8 = 1;
+
+/// A proof op opening the app hash to an [`AppHashPreimage`].
+pub const APP_OP: &str = "protocash/app";
+
+/// A proof op holding the path of a commitment to the root of the commitment tree.
+pub const COMMITMENT_OP: &str = "protocash/commitment";
+
+/// A proof op holding a [`NullifierProof`].
+pub const NULLIFIER_OP: &str = "protocash/nullifier";
+
+/// Domain separator of the application hash. Bump the version whenever the preimage changes.
+const APP_HASH_DOMAIN: &[u8] = b"protocash/app-hash/v2";
+
+/// Domain separator of the nullifier set commitment.
+const NULLIFIER_DOMAIN: &[u8] = b"protocash/nullifiers/v1";
+
+/// Domain separator of the commitment to the window of recent roots.
+const ROOTS_DOMAIN: &[u8] = b"protocash/roots/v1";
+
+/// Encode an answer or a proof, behind the version byte.
+pub fn encode<T: CanonicalSerialize>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + value.compressed_size());
+    bytes.push(VERSION);
+    value
+        .serialize_compressed(&mut bytes)
+        .expect("serializing to a Vec can't fail");
+
+    bytes
+}
+
+/// Decode an answer or a proof encoded with [`encode`].
+pub fn decode<T: CanonicalDeserialize>(bytes: &[u8]) -> Result<T, SerializationError> {
+    let Some((&VERSION, mut bytes)) = bytes.split_first() else {
+        return Err(SerializationError::InvalidData);
+    };
+
+    let value = T::deserialize_compressed(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(SerializationError::InvalidData);
+    }
+
+    Ok(value)
+}
+
+/// The answer to `/stats`.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Stats {
+    /// The height of the last committed block.
+    pub height: u32,
+
+    /// The number of coins created since genesis.
+    pub size: u32,
+
+    /// The number of commitments in the tree.
+    pub commitments: u64,
+
+    /// The number of spent coins.
+    pub nullifiers: u64,
+
+    /// The number of recent roots that proofs are accepted against.
+    pub root_window: u64,
+}
+
+/// Everything that goes into the app hash. A proof of a query answer ends with this, which the
+/// client hashes and compares to the app hash of a block it trusts.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct AppHashPreimage {
+    /// The root of the commitment tree.
+    pub root: Root,
+
+    /// See [`nullifier_root`].
+    pub nullifier_root: [u8; 32],
+
+    /// See [`roots_hash`].
+    pub roots_hash: [u8; 32],
+
+    /// A commitment to the validator set.
+    pub validators_hash: [u8; 32],
+
+    pub height: u32,
+    pub size: u32,
+}
+
+impl AppHashPreimage {
+    /// The application hash. Every validator must compute the same hash for the same state, so
+    /// everything in here is serialized explicitly: field elements in their canonical compressed
+    /// form, and integers in big endian.
+    pub fn hash(&self) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(APP_HASH_DOMAIN)
+            .chain_update(field_bytes(&self.root))
+            .chain_update(self.nullifier_root)
+            .chain_update(self.roots_hash)
+            .chain_update(self.validators_hash)
+            .chain_update(self.height.to_be_bytes())
+            .chain_update(self.size.to_be_bytes())
+            .finalize()
+            .into()
+    }
+}
+
+/// The canonical encoding of a field element.
+pub fn field_bytes<T: CanonicalSerialize>(x: &T) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(x.compressed_size());
+    x.serialize_compressed(&mut bytes)
+        .expect("serializing to a Vec can't fail");
+
+    bytes
+}
+
+/// A commitment to the window of recent roots, in order.
+pub fn roots_hash<'a>(roots: impl ExactSizeIterator<Item = &'a Root>) -> [u8; 32] {
+    let hasher = Sha256::new()
+        .chain_update(ROOTS_DOMAIN)
+        .chain_update((roots.len() as u64).to_be_bytes());
+
+    roots
+        .fold(hasher, |hasher, root| {
+            hasher.chain_update(field_bytes(root))
+        })
+        .finalize()
+        .into()
+}
+
+/// The leaf of a serial number in the nullifier tree.
+fn nullifier_leaf(sn: &CoinID) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([0x00])
+        .chain_update(NULLIFIER_DOMAIN)
+        .chain_update(field_bytes(sn))
+        .finalize()
+        .into()
+}
+
+/// A commitment to a set of spent serial numbers, given in ascending order. This is the root of
+/// an RFC 6962 Merkle tree whose leaves are the serial numbers in that order, so it does not
+/// depend on the order in which they were spent.
+pub fn nullifier_root(spents: &[CoinID]) -> [u8; 32] {
+    let leaves: Vec<[u8; 32]> = spents.iter().map(nullifier_leaf).collect();
+
+    merkle_hash(&leaves)
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([0x01])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// The largest power of two smaller than `n`, which is where RFC 6962 splits `n` leaves.
+fn split(n: usize) -> usize {
+    1 << (n - 1).ilog2()
+}
+
+/// RFC 6962 Merkle tree hash of already hashed leaves.
+fn merkle_hash(leaves: &[[u8; 32]]) -> [u8; 32] {
+    match leaves {
+        [] => Sha256::digest([]).into(),
+        [leaf] => *leaf,
+        _ => {
+            let k = split(leaves.len());
+
+            node_hash(&merkle_hash(&leaves[..k]), &merkle_hash(&leaves[k..]))
+        }
+    }
+}
+
+/// The RFC 6962 audit path of the leaf at `index`, from the bottom up.
+fn audit_path(leaves: &[[u8; 32]], index: usize) -> Vec<[u8; 32]> {
+    if leaves.len() <= 1 {
+        return Vec::new();
+    }
+
+    let k = split(leaves.len());
+    let (mut path, sibling) = if index < k {
+        (audit_path(&leaves[..k], index), merkle_hash(&leaves[k..]))
+    } else {
+        (
+            audit_path(&leaves[k..], index - k),
+            merkle_hash(&leaves[..k]),
+        )
+    };
+    path.push(sibling);
+
+    path
+}
+
+/// Check an audit path, as described in RFC 9162, section 2.1.3.2.
+fn verify_audit_path(
+    leaf: [u8; 32],
+    index: u64,
+    size: u64,
+    path: &[[u8; 32]],
+    root: &[u8; 32],
+) -> bool {
+    if index >= size {
+        return false;
+    }
+
+    let (mut f, mut s) = (index, size - 1);
+    let mut hash = leaf;
+    for sibling in path {
+        if s == 0 {
+            return false;
+        }
+
+        if f & 1 == 1 || f == s {
+            hash = node_hash(sibling, &hash);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            hash = node_hash(&hash, sibling);
+        }
+
+        f >>= 1;
+        s >>= 1;
+    }
+
+    s == 0 && hash == *root
+}
+
+/// A spent serial number, and its audit path in the nullifier tree.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Inclusion {
+    pub serial_number: CoinID,
+    pub index: u64,
+    pub path: Vec<[u8; 32]>,
+}
+
+/// A proof that a serial number is spent or not, against the [nullifier root](nullifier_root).
+///
+/// A spent serial number is proven by its own inclusion in the tree. Since the leaves are sorted,
+/// a serial number which isn't spent is proven by the inclusion of the two spent serial numbers
+/// around it, which sit next to each other in the tree.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct NullifierProof {
+    /// The number of leaves in the tree.
+    pub size: u64,
+
+    /// The serial number itself if it is spent, otherwise the largest spent one below it.
+    pub left: Option<Inclusion>,
+
+    /// The smallest spent serial number above it, if it isn't spent.
+    pub right: Option<Inclusion>,
+}
+
+impl NullifierProof {
+    /// Prove whether `sn` is in `spents`, which are in ascending order.
+    pub fn new(spents: &[CoinID], sn: &CoinID) -> Self {
+        let leaves: Vec<[u8; 32]> = spents.iter().map(nullifier_leaf).collect();
+        let inclusion = |index: usize| Inclusion {
+            serial_number: spents[index],
+            index: index as u64,
+            path: audit_path(&leaves, index),
+        };
+
+        let (left, right) = match spents.binary_search(sn) {
+            Ok(i) => (Some(inclusion(i)), None),
+            Err(i) => (
+                i.checked_sub(1).map(inclusion),
+                (i < spents.len()).then(|| inclusion(i)),
+            ),
+        };
+
+        Self {
+            size: spents.len() as u64,
+            left,
+            right,
+        }
+    }
+
+    /// Check the proof against the nullifier root. Returns whether `sn` is spent, or `None` if the
+    /// proof is invalid.
+    pub fn verify(&self, sn: &CoinID, root: &[u8; 32]) -> Option<bool> {
+        let valid = |inclusion: &Inclusion| {
+            let leaf = nullifier_leaf(&inclusion.serial_number);
+
+            verify_audit_path(leaf, inclusion.index, self.size, &inclusion.path, root)
+        };
+
+        if let (Some(left), None) = (&self.left, &self.right) {
+            if left.serial_number == *sn {
+                return valid(left).then_some(true);
+            }
+        }
+
+        let below = self
+            .left
+            .as_ref()
+            .is_none_or(|left| left.serial_number < *sn && valid(left));
+        let above = self
+            .right
+            .as_ref()
+            .is_none_or(|right| *sn < right.serial_number && valid(right));
+        let adjacent = match (&self.left, &self.right) {
+            (Some(left), Some(right)) => left.index + 1 == right.index,
+            (Some(left), None) => left.index + 1 == self.size,
+            (None, Some(right)) => right.index == 0,
+            (None, None) => self.size == 0 && *root == merkle_hash(&[]),
+        };
+
+        (below && above && adjacent).then_some(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::decode;
+    use super::encode;
+    use super::nullifier_root;
+    use super::NullifierProof;
+    use crate::types::CoinID;
+
+    #[test]
+    /// Check that spent and unspent serial numbers are proven, for every size of tree
+    fn nullifier_proofs() {
+        for n in 0..10u64 {
+            let spents: Vec<CoinID> = (0..n).map(|i| CoinID::from(2 * i + 1)).collect();
+            let root = nullifier_root(&spents);
+
+            for sn in (0..2 * n + 2).map(CoinID::from) {
+                let proof = NullifierProof::new(&spents, &sn);
+                let spent = spents.contains(&sn);
+                assert_eq!(proof.verify(&sn, &root), Some(spent));
+
+                // the proof doesn't lie about another serial number
+                let other = sn + CoinID::from(1);
+                assert_ne!(proof.verify(&other, &root), Some(!spents.contains(&other)));
+            }
+        }
+    }
+
+    #[test]
+    /// Check that forged nullifier proofs are rejected
+    fn nullifier_sound() {
+        let spents: Vec<CoinID> = (1..=5).map(|i| CoinID::from(2 * i)).collect();
+        let root = nullifier_root(&spents);
+
+        // hiding a spent serial number behind neighbours which aren't next to each other
+        let sn = CoinID::from(6);
+        let mut proof = NullifierProof::new(&spents, &CoinID::from(5));
+        proof.right = NullifierProof::new(&spents, &CoinID::from(7)).right;
+        assert_eq!(proof.verify(&sn, &root), None);
+
+        // hiding the last serial number by pretending the tree ends before it
+        let last = CoinID::from(10);
+        let mut proof = NullifierProof::new(&spents, &CoinID::from(9));
+        proof.right = None;
+        proof.size -= 1;
+        assert_eq!(proof.verify(&last, &root), None);
+
+        let proof = NullifierProof::new(&spents, &sn);
+        assert_eq!(proof.verify(&sn, &[0; 32]), None);
+    }
+
+    #[test]
+    /// Check that answers only decode under the current version, without trailing bytes
+    fn versioned() -> Result<(), Box<dyn Error>> {
+        let value = vec![CoinID::from(3)];
+        let mut bytes = encode(&value);
+        assert_eq!(decode::<Vec<CoinID>>(&bytes)?, value);
+
+        bytes.push(0);
+        assert!(decode::<Vec<CoinID>>(&bytes).is_err());
+
+        bytes.pop();
+        bytes[0] += 1;
+        assert!(decode::<Vec<CoinID>>(&bytes).is_err());
+
+        Ok(())
+    }
+}
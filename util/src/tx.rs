@@ -0,0 +1,439 @@
+use std::fmt;
+
+use ark_bls12_381::Bls12_381;
+use ark_bls12_381::Fr as BlsFr;
+use ark_ff::PrimeField;
+use ark_groth16::Groth16;
+use ark_groth16::PreparedVerifyingKey;
+use ark_groth16::Proof;
+use ark_relations::r1cs::SynthesisError;
+use ark_serialize::CanonicalDeserialize;
+use ark_serialize::CanonicalSerialize;
+use ark_snark::SNARK;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::attestation::Attestations;
+use crate::joinsplit;
+use crate::merkletree::Root;
+use crate::types::CoinCommitment;
+use crate::types::CoinID;
+use crate::types::Value;
+
+const DIGEST_DOMAIN: &[u8] = b"protocash/tx-digest/v1";
+
+/// Domain separator of the message signed by the validator admin.
+const VALIDATOR_UPDATE_DOMAIN: &[u8] = b"protocash/validator-update/v1";
+
+/// The version of the encoding of [`Tx`].
+pub const VERSION: u8 = 1;
+
+/// The nodes refuse transactions larger than this, in bytes. Attestations aren't submitted by the
+/// users, and grow with the validator set, so they aren't limited.
+pub const MAX_TX_SIZE: usize = 64 * 1024;
+
+/// The codespace of the ABCI codes of [`TxError`].
+pub const CODESPACE: &str = "protocash";
+
+/// The circuits a transaction can be proven with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Circuit {
+    /// A [`PaymentProof`](crate::payment::PaymentProof), spending one coin and creating one.
+    Payment,
+
+    /// A [`JoinSplitProof`](crate::joinsplit::JoinSplitProof), spending two coins and creating two.
+    JoinSplit,
+}
+
+impl Circuit {
+    /// Every circuit, in the order of their ids.
+    pub const ALL: [Circuit; 2] = [Circuit::Payment, Circuit::JoinSplit];
+
+    /// A short name for the circuit, which is used in file names.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Circuit::Payment => "payment",
+            Circuit::JoinSplit => "joinsplit",
+        }
+    }
+
+    /// The id of the circuit, which is used in serialized data.
+    pub fn id(&self) -> u8 {
+        match self {
+            Circuit::Payment => 0,
+            Circuit::JoinSplit => 1,
+        }
+    }
+
+    /// The circuit with the given [id](Self::id).
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.id() == id)
+    }
+}
+
+/// A payment, as submitted to the nodes.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Transaction {
+    /// The root of the tree that the proof was made against.
+    pub root: Root,
+
+    /// The serial numbers of the coins being spent. Nodes keep track of these to prevent double
+    /// spends.
+    pub serial_numbers: Vec<CoinID>,
+
+    /// The commitments of the coins being created. The proof guarantees that they are well
+    /// formed, so they are added to the tree as is.
+    pub outputs: Vec<CoinCommitment>,
+
+    /// The value of the inputs which isn't in the outputs, paid to the network.
+    pub fee: Value,
+
+    /// Arbitrary data attached by the sender.
+    pub memo: Vec<u8>,
+
+    /// The output coins, encrypted for their recipients so that they can spend them. There is at
+    /// most one per output.
+    pub notes: Vec<Vec<u8>>,
+
+    /// A proof for the public inputs above, made with the circuit given by [`Self::circuit`].
+    pub proof: Proof<Bls12_381>,
+}
+
+impl Transaction {
+    /// The circuit this transaction was proven with, which is given by its number of inputs and
+    /// outputs.
+    pub fn circuit(&self) -> Option<Circuit> {
+        match (self.serial_numbers.len(), self.outputs.len()) {
+            (1, 1) => Some(Circuit::Payment),
+            (joinsplit::NUM_INPUTS, joinsplit::NUM_OUTPUTS) => Some(Circuit::JoinSplit),
+            _ => None,
+        }
+    }
+
+    /// The public inputs of the proof, in the order the circuits allocate them.
+    pub fn public_inputs(&self) -> Vec<BlsFr> {
+        let mut inputs = vec![self.root];
+        inputs.extend(&self.serial_numbers);
+        inputs.extend(&self.outputs);
+        inputs.push(BlsFr::from(self.fee));
+        inputs.push(digest(self.fee, &self.memo, &self.notes));
+
+        inputs
+    }
+
+    /// Verify the proof of this transaction against the key of its circuit.
+    pub fn verify(&self, pvk: &PreparedVerifyingKey<Bls12_381>) -> Result<bool, SynthesisError> {
+        Groth16::<Bls12_381>::verify_with_processed_vk(pvk, &self.public_inputs(), &self.proof)
+    }
+}
+
+/// A change to the validator set, authorized by the validator admin set at genesis.
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct ValidatorUpdate {
+    /// The ed25519 public key of the validator.
+    pub pub_key: [u8; 32],
+
+    /// The new voting power of the validator. Zero removes it.
+    pub power: u64,
+
+    /// Updates are applied in increasing order of nonces, so that none of them can be replayed.
+    pub nonce: u64,
+
+    /// The ed25519 signature of [`Self::message`] by the validator admin.
+    pub signature: [u8; 64],
+}
+
+impl ValidatorUpdate {
+    /// The message that the validator admin signs.
+    pub fn message(&self) -> Vec<u8> {
+        let mut message = VALIDATOR_UPDATE_DOMAIN.to_vec();
+        message.extend(self.pub_key);
+        message.extend(self.power.to_be_bytes());
+        message.extend(self.nonce.to_be_bytes());
+
+        message
+    }
+}
+
+/// Anything that can be submitted to the nodes. On the wire, a transaction is a version byte,
+/// a byte for its kind, and the canonical encoding of its contents.
+// payments are by far the most common transactions, boxing them would only add an allocation
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub enum Tx {
+    Payment(Transaction),
+    ValidatorUpdate(ValidatorUpdate),
+
+    /// Only the proposer of a block publishes attestations, first thing in the block.
+    Attestations(Attestations),
+}
+
+/// Why bytes don't decode to a [`Tx`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The transaction is encoded with a version that we don't know about.
+    UnknownVersion(u8),
+
+    /// The transaction isn't the canonical encoding of any transaction.
+    Malformed,
+}
+
+/// Why the nodes refuse a transaction. Each error has a stable ABCI code under [`CODESPACE`], so
+/// that clients can tell them apart. See [`TxError::code`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxError {
+    /// The transaction isn't the canonical encoding of any transaction.
+    Malformed,
+
+    /// The transaction is encoded with a version that the node doesn't know about.
+    UnknownVersion,
+
+    /// The proof doesn't verify against the public inputs of the transaction.
+    InvalidProof,
+
+    /// The proof was made against a root that isn't in the window of recent roots, either because
+    /// it expired or because it was never a root of the tree.
+    UnknownRoot,
+
+    /// The serial number of the coin was already revealed by an earlier payment.
+    AlreadySpent,
+
+    /// A coin is already spent by a transaction waiting in the mempool.
+    MempoolConflict,
+
+    /// The transaction is larger than [`MAX_TX_SIZE`].
+    TooLarge,
+
+    /// The fee is below the minimum that the node lets into its mempool.
+    InsufficientFee,
+
+    /// The transaction has a number of inputs and outputs that no circuit proves.
+    UnsupportedCircuit,
+
+    /// The validator update isn't signed by the validator admin, or its nonce was already used.
+    /// Or an attestation isn't signed by a validator.
+    Unauthorized,
+
+    /// The validator update would remove the last validator.
+    NoValidators,
+
+    /// The commitment tree has no room left for the coins that the payment creates.
+    TreeFull,
+}
+
+impl TxError {
+    /// Every error, in the order of their codes.
+    pub const ALL: [TxError; 12] = [
+        TxError::Malformed,
+        TxError::UnknownVersion,
+        TxError::InvalidProof,
+        TxError::UnknownRoot,
+        TxError::AlreadySpent,
+        TxError::MempoolConflict,
+        TxError::TooLarge,
+        TxError::InsufficientFee,
+        TxError::UnsupportedCircuit,
+        TxError::Unauthorized,
+        TxError::NoValidators,
+        TxError::TreeFull,
+    ];
+
+    /// The ABCI code of the error, under [`CODESPACE`]. Zero is a success, and a code never
+    /// changes meaning: new errors get new codes.
+    pub fn code(&self) -> u32 {
+        match self {
+            TxError::Malformed => 1,
+            TxError::UnknownVersion => 2,
+            TxError::InvalidProof => 3,
+            TxError::UnknownRoot => 4,
+            TxError::AlreadySpent => 5,
+            TxError::MempoolConflict => 6,
+            TxError::TooLarge => 7,
+            TxError::InsufficientFee => 8,
+            TxError::UnsupportedCircuit => 9,
+            TxError::Unauthorized => 10,
+            TxError::NoValidators => 11,
+            TxError::TreeFull => 12,
+        }
+    }
+
+    /// The error that a node answered with, given the codespace and the [code](Self::code) of
+    /// its result. `None` for a success, or for an error that isn't ours, such as when cometbft
+    /// itself refuses the transaction.
+    pub fn from_code(codespace: &str, code: u32) -> Option<Self> {
+        if codespace != CODESPACE {
+            return None;
+        }
+
+        Self::ALL.into_iter().find(|e| e.code() == code)
+    }
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::Malformed => write!(f, "the transaction can't be decoded"),
+            TxError::UnknownVersion => write!(f, "the transaction has an unknown version"),
+            TxError::InvalidProof => write!(f, "the proof is invalid"),
+            TxError::UnknownRoot => write!(f, "the root is unknown or expired"),
+            TxError::AlreadySpent => write!(f, "a coin is already spent"),
+            TxError::MempoolConflict => {
+                write!(f, "a coin is already spent by a pending transaction")
+            }
+            TxError::TooLarge => write!(f, "the transaction is larger than {MAX_TX_SIZE} bytes"),
+            TxError::InsufficientFee => write!(f, "the fee is too low"),
+            TxError::UnsupportedCircuit => write!(f, "no circuit proves this transaction"),
+            TxError::Unauthorized => write!(f, "the transaction isn't authorized"),
+            TxError::NoValidators => write!(f, "the last validator can't be removed"),
+            TxError::TreeFull => write!(f, "the commitment tree is full"),
+        }
+    }
+}
+
+impl std::error::Error for TxError {}
+
+impl From<DecodeError> for TxError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::UnknownVersion(_) => TxError::UnknownVersion,
+            DecodeError::Malformed => TxError::Malformed,
+        }
+    }
+}
+
+impl Tx {
+    fn kind(&self) -> u8 {
+        match self {
+            Tx::Payment(_) => 0,
+            Tx::ValidatorUpdate(_) => 1,
+            Tx::Attestations(_) => 2,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![VERSION, self.kind()];
+        match self {
+            Tx::Payment(tx) => tx.serialize_compressed(&mut bytes),
+            Tx::ValidatorUpdate(update) => update.serialize_compressed(&mut bytes),
+            Tx::Attestations(attestations) => attestations.serialize_compressed(&mut bytes),
+        }
+        .expect("serializing to a Vec can't fail");
+
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (version, kind, mut bytes) = match bytes {
+            [version, kind, rest @ ..] => (*version, *kind, rest),
+            _ => return Err(DecodeError::Malformed),
+        };
+
+        if version != VERSION {
+            return Err(DecodeError::UnknownVersion(version));
+        }
+
+        let tx = match kind {
+            0 => Transaction::deserialize_compressed(&mut bytes).map(Tx::Payment),
+            1 => ValidatorUpdate::deserialize_compressed(&mut bytes).map(Tx::ValidatorUpdate),
+            2 => Attestations::deserialize_compressed(&mut bytes).map(Tx::Attestations),
+            _ => return Err(DecodeError::Malformed),
+        }
+        .map_err(|_| DecodeError::Malformed)?;
+
+        // trailing bytes would let the same transaction be submitted under many encodings
+        if !bytes.is_empty() {
+            return Err(DecodeError::Malformed);
+        }
+
+        Ok(tx)
+    }
+
+    /// The serial numbers of the coins spent by the transaction.
+    pub fn serial_numbers(&self) -> &[CoinID] {
+        match self {
+            Tx::Payment(tx) => &tx.serial_numbers,
+            Tx::ValidatorUpdate(_) | Tx::Attestations(_) => &[],
+        }
+    }
+
+    /// The fee paid by the transaction.
+    pub fn fee(&self) -> Value {
+        match self {
+            Tx::Payment(tx) => tx.fee,
+            Tx::ValidatorUpdate(_) | Tx::Attestations(_) => 0,
+        }
+    }
+}
+
+/// The digest of the transaction data which isn't otherwise a public input of the proof. Proofs
+/// take it as a public input, so that a proof can't be copied into a transaction with a different
+/// fee, memo or notes.
+pub fn digest(fee: Value, memo: &[u8], notes: &[Vec<u8>]) -> BlsFr {
+    let mut hasher = Sha256::new();
+    hasher.update(DIGEST_DOMAIN);
+    hasher.update(fee.to_be_bytes());
+    hasher.update((memo.len() as u64).to_be_bytes());
+    hasher.update(memo);
+    hasher.update((notes.len() as u64).to_be_bytes());
+    for note in notes {
+        hasher.update((note.len() as u64).to_be_bytes());
+        hasher.update(note);
+    }
+
+    BlsFr::from_le_bytes_mod_order(&hasher.finalize())
+}
+
+#[cfg(test)]
+mod test {
+    use super::DecodeError;
+    use super::Tx;
+    use super::TxError;
+    use super::ValidatorUpdate;
+    use super::CODESPACE;
+
+    #[test]
+    /// Check that transactions only decode from their canonical encoding
+    fn encoding() {
+        let tx = Tx::ValidatorUpdate(ValidatorUpdate {
+            pub_key: [1; 32],
+            power: 10,
+            nonce: 3,
+            signature: [2; 64],
+        });
+
+        let mut bytes = tx.encode();
+        assert!(matches!(
+            Tx::decode(&bytes),
+            Ok(Tx::ValidatorUpdate(update)) if update.nonce == 3
+        ));
+
+        bytes.push(0);
+        assert!(matches!(Tx::decode(&bytes), Err(DecodeError::Malformed)));
+
+        bytes.pop();
+        bytes[1] = 7;
+        assert!(matches!(Tx::decode(&bytes), Err(DecodeError::Malformed)));
+
+        bytes[0] = 2;
+        assert!(matches!(
+            Tx::decode(&bytes),
+            Err(DecodeError::UnknownVersion(2))
+        ));
+        assert!(matches!(Tx::decode(&[]), Err(DecodeError::Malformed)));
+    }
+
+    #[test]
+    /// Check that the codes of the errors don't change, and decode back to the same errors
+    fn error_codes() {
+        let codes: Vec<u32> = TxError::ALL.iter().map(TxError::code).collect();
+        assert_eq!(codes, (1..=12).collect::<Vec<u32>>());
+
+        for e in TxError::ALL {
+            assert_eq!(TxError::from_code(CODESPACE, e.code()), Some(e));
+        }
+
+        assert_eq!(TxError::from_code(CODESPACE, 0), None);
+        assert_eq!(TxError::from_code(CODESPACE, 13), None);
+        assert_eq!(TxError::from_code("sdk", 5), None);
+    }
+}
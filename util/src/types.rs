@@ -13,6 +13,9 @@ pub type Key = BlsFr;
 /// A type used to represent the randomness associated with a commitment.
 pub type Rand = BlsFr;
 
+/// The value of a coin.
+pub type Value = u64;
+
 /// A Coin. This is used in the MerkleTree as a `Coin` commitment.
 #[derive(Clone)]
 pub struct Coin {
@@ -24,6 +27,9 @@ pub struct Coin {
 
     /// Noise used when generating the coin commitment
     pub com_rnd: Rand,
+
+    /// How much the coin is worth.
+    pub value: Value,
 }
 
 impl Coin {
@@ -41,10 +47,14 @@ impl Coin {
         // generate some random noise
         let com_rnd = BlsFr::rand(rng);
 
+        // generate a random value
+        let value = rng.gen();
+
         Self {
             pk,
             pre_serial_number,
             com_rnd,
+            value,
         }
     }
 }